@@ -0,0 +1,14 @@
+-- Databases set up before migrations already have this table, so every statement
+-- has to be a no-op when the schema is already there.
+CREATE TABLE IF NOT EXISTS book_metadata (
+    id bigint PRIMARY KEY,
+    title TEXT NOT NULL,
+    author TEXT NOT NULL,
+    birthyear INTEGER,
+    deathyear INTEGER,
+    summary TEXT
+);
+
+ALTER TABLE book_metadata ADD COLUMN IF NOT EXISTS languages TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE book_metadata ADD COLUMN IF NOT EXISTS subjects TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE book_metadata ADD COLUMN IF NOT EXISTS bookshelves TEXT[] NOT NULL DEFAULT '{}';
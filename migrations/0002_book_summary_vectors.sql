@@ -0,0 +1,6 @@
+CREATE EXTENSION IF NOT EXISTS vector;
+
+CREATE TABLE IF NOT EXISTS book_summary_vectors (
+    id bigint PRIMARY KEY,
+    embedding vector(1024)
+);
@@ -0,0 +1,13 @@
+-- Every agent linked to a book, book_metadata.author only keeps a single name.
+-- role is a MARC relator code, "aut" for dcterms:creator.
+CREATE TABLE IF NOT EXISTS book_contributors (
+    book_id bigint NOT NULL REFERENCES book_metadata (id) ON DELETE CASCADE,
+    agent_id bigint NOT NULL,
+    name TEXT NOT NULL,
+    birthyear INTEGER,
+    deathyear INTEGER,
+    role TEXT NOT NULL,
+    PRIMARY KEY (book_id, agent_id, role)
+);
+
+CREATE INDEX IF NOT EXISTS book_contributors_agent_id_idx ON book_contributors (agent_id);
@@ -0,0 +1,21 @@
+-- Subjects and bookshelves move out of the book_metadata arrays so they can be
+-- indexed instead of unnesting every row. scheme is "LCSH" or "bookshelf".
+CREATE TABLE IF NOT EXISTS book_subjects (
+    book_id bigint NOT NULL REFERENCES book_metadata (id) ON DELETE CASCADE,
+    scheme TEXT NOT NULL,
+    heading TEXT NOT NULL,
+    PRIMARY KEY (book_id, scheme, heading)
+);
+
+CREATE INDEX IF NOT EXISTS book_subjects_heading_idx ON book_subjects (scheme, heading);
+
+INSERT INTO book_subjects (book_id, scheme, heading)
+SELECT id, 'LCSH', unnest(subjects) FROM book_metadata
+ON CONFLICT DO NOTHING;
+
+INSERT INTO book_subjects (book_id, scheme, heading)
+SELECT id, 'bookshelf', unnest(bookshelves) FROM book_metadata
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE book_metadata DROP COLUMN IF EXISTS subjects;
+ALTER TABLE book_metadata DROP COLUMN IF EXISTS bookshelves;
@@ -0,0 +1,11 @@
+-- content_hash covers the text that gets embedded, a vector is stale when its
+-- content_hash or model_fingerprint no longer matches.
+ALTER TABLE book_metadata
+    ADD COLUMN IF NOT EXISTS content_hash TEXT GENERATED ALWAYS AS (md5(coalesce(summary, ''))) STORED;
+ALTER TABLE book_metadata ADD COLUMN IF NOT EXISTS updated_at timestamptz NOT NULL DEFAULT now();
+-- set when a book disappears from the catalog, cleared if it comes back
+ALTER TABLE book_metadata ADD COLUMN IF NOT EXISTS deleted_at timestamptz;
+
+ALTER TABLE book_summary_vectors ADD COLUMN IF NOT EXISTS content_hash TEXT;
+ALTER TABLE book_summary_vectors ADD COLUMN IF NOT EXISTS model_fingerprint TEXT;
+ALTER TABLE book_summary_vectors ADD COLUMN IF NOT EXISTS updated_at timestamptz NOT NULL DEFAULT now();
@@ -0,0 +1,23 @@
+-- One row per embedding run. last_processed_id is the checkpoint: books are embedded in
+-- id order and every id up to it has either a vector or a row in indexing_job_failures.
+CREATE TABLE IF NOT EXISTS indexing_jobs (
+    id bigserial PRIMARY KEY,
+    model TEXT NOT NULL,
+    model_fingerprint TEXT NOT NULL,
+    parameters jsonb NOT NULL DEFAULT '{}',
+    status TEXT NOT NULL DEFAULT 'running',
+    last_processed_id bigint,
+    succeeded bigint NOT NULL DEFAULT 0,
+    failed bigint NOT NULL DEFAULT 0,
+    started_at timestamptz NOT NULL DEFAULT now(),
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    finished_at timestamptz
+);
+
+CREATE TABLE IF NOT EXISTS indexing_job_failures (
+    job_id bigint NOT NULL REFERENCES indexing_jobs (id) ON DELETE CASCADE,
+    book_id bigint NOT NULL,
+    error TEXT NOT NULL,
+    failed_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (job_id, book_id)
+);
@@ -0,0 +1,18 @@
+-- Every embedding model gets its own vector tables, since pgvector columns (and their
+-- ANN indexes) have a fixed dimension. model_vector_tables maps (model, vector kind)
+-- to the table holding one row per book.
+CREATE TABLE IF NOT EXISTS embedding_models (
+    id serial PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    dimensions INT NOT NULL,
+    model_path TEXT NOT NULL,
+    tokenizer_path TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS model_vector_tables (
+    model_id INT NOT NULL REFERENCES embedding_models (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    table_name TEXT NOT NULL UNIQUE,
+    PRIMARY KEY (model_id, kind)
+);
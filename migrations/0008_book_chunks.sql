@@ -0,0 +1,14 @@
+-- Passages of a book's full text, character offsets refer to the cleaned text they
+-- were cut from. source_hash identifies that text, so unchanged books are skipped.
+CREATE TABLE IF NOT EXISTS book_chunks (
+    book_id bigint NOT NULL REFERENCES book_metadata (id) ON DELETE CASCADE,
+    chunk_index INT NOT NULL,
+    start_offset INT NOT NULL,
+    end_offset INT NOT NULL,
+    text TEXT NOT NULL,
+    embedding vector(1024),
+    source_hash TEXT,
+    model_fingerprint TEXT,
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (book_id, chunk_index)
+);
@@ -0,0 +1,463 @@
+use clap::ValueEnum;
+use pgvector::Vector;
+use serde::Serialize;
+use sqlx::Row;
+use sqlx::postgres::PgPool;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::output::{Column, OutputRow, column};
+use crate::schema::Schema;
+use crate::search::{SearchRequest, scan_ef_search, scan_settings};
+use crate::vector_index;
+
+// Recall of the ANN index against exact search. Query vectors are a sample of the stored
+// summary vectors, each query's own book is dropped from both result lists. Exact top-k is
+// computed either in Rust over every exported vector, or by Postgres with index scans
+// disabled, which also gives the latency of a sequential scan to compare against.
+//
+// Every query is run once untimed before the sweep, so the first setting does not pay
+// for loading the index into shared buffers. With seqscan disabled the planner still
+// chooses freely between ANN indexes, so the table must have exactly one.
+//
+// Each setting runs with the same scan settings as a search, see `sweep_settings`.
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExactMethod {
+    /// Brute force over vectors exported from the table
+    Rust,
+    /// The same query with the ANN index disabled
+    Sql,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchmarkOptions {
+    /// Stored vectors sampled as queries
+    pub queries: usize,
+    pub k: usize,
+    /// hnsw.ef_search values swept if the table has an HNSW index
+    pub ef_search: Vec<i32>,
+    /// ivfflat.probes values swept if the table has an IVFFlat index
+    pub probes: Vec<i32>,
+    pub exact: ExactMethod,
+    /// The same seed samples the same queries
+    pub seed: u64,
+}
+
+/// One setting of the sweep, or the exact baseline
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SweepRow {
+    /// hnsw, ivfflat or exact
+    pub method: String,
+    /// ef_search, probes, or how exact neighbors were computed
+    pub setting: String,
+    pub value: Option<i32>,
+    pub k: usize,
+    pub queries: usize,
+    /// Mean over queries of the share of the exact top-k that was returned
+    pub recall: f64,
+    pub min_recall: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl OutputRow for SweepRow {
+    const COLUMNS: &'static [Column] = &[
+        column("method", 8, false),
+        column("setting", 10, false),
+        column("value", 6, true),
+        column("k", 4, true),
+        column("queries", 7, true),
+        column("recall", 6, true),
+        column("min_recall", 10, true),
+        column("mean_ms", 8, true),
+        column("p50_ms", 8, true),
+        column("p95_ms", 8, true),
+        column("p99_ms", 8, true),
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.method.clone(),
+            self.setting.clone(),
+            self.value
+                .map(|value| value.to_string())
+                .unwrap_or_default(),
+            self.k.to_string(),
+            self.queries.to_string(),
+            format!("{:.4}", self.recall),
+            format!("{:.4}", self.min_recall),
+            format!("{:.2}", self.mean_ms),
+            format!("{:.2}", self.p50_ms),
+            format!("{:.2}", self.p95_ms),
+            format!("{:.2}", self.p99_ms),
+        ]
+    }
+}
+
+/// Share of `exact` found in `approximate`, 1 when there is nothing to find
+pub fn recall(exact: &[i64], approximate: &[i64]) -> f64 {
+    if exact.is_empty() {
+        return 1.0;
+    }
+    let found: HashSet<i64> = approximate.iter().copied().collect();
+    exact.iter().filter(|id| found.contains(id)).count() as f64 / exact.len() as f64
+}
+
+/// Nearest-rank percentile of sorted latencies in milliseconds, `p` in [0, 100]
+pub fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+}
+
+fn sweep_row(
+    method: &str,
+    setting: &str,
+    value: Option<i32>,
+    k: usize,
+    recalls: &[f64],
+    mut latencies: Vec<Duration>,
+) -> SweepRow {
+    latencies.sort();
+    let count = recalls.len().max(1) as f64;
+    let total: Duration = latencies.iter().sum();
+    SweepRow {
+        method: method.to_string(),
+        setting: setting.to_string(),
+        value,
+        k,
+        queries: recalls.len(),
+        recall: recalls.iter().sum::<f64>() / count,
+        min_recall: recalls.iter().copied().fold(1.0, f64::min),
+        mean_ms: total.as_secs_f64() * 1000.0 / latencies.len().max(1) as f64,
+        p50_ms: percentile(&latencies, 50.0),
+        p95_ms: percentile(&latencies, 95.0),
+        p99_ms: percentile(&latencies, 99.0),
+    }
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Ids of the `k` unit vectors closest to the unit vector `query` by cosine distance,
+/// nearest first, skipping `skip_id`
+pub fn exact_neighbors(
+    vectors: &[(i64, Vec<f32>)],
+    query: &[f32],
+    k: usize,
+    skip_id: i64,
+) -> Vec<i64> {
+    let mut scored: Vec<(f32, i64)> = vectors
+        .iter()
+        .filter(|(id, _)| *id != skip_id)
+        .map(|(id, vector)| {
+            let dot: f32 = vector.iter().zip(query).map(|(x, y)| x * y).sum();
+            (1.0 - dot, *id)
+        })
+        .collect();
+    let by_distance = |a: &(f32, i64), b: &(f32, i64)| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1));
+    if scored.len() > k {
+        scored.select_nth_unstable_by(k, by_distance);
+        scored.truncate(k);
+    }
+    scored.sort_by(by_distance);
+    scored.into_iter().map(|(_, id)| id).collect()
+}
+
+async fn sample_queries(
+    pool: &PgPool,
+    schema: &Schema,
+    count: usize,
+    seed: u64,
+) -> Result<Vec<(i64, Vector)>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT id, embedding FROM {} ORDER BY md5(id::text || $1) LIMIT $2",
+        schema.vectors
+    );
+    let rows = sqlx::query(query_string.as_str())
+        .bind(seed.to_string())
+        .bind(count as i64)
+        .fetch_all(pool)
+        .await?;
+    let queries = rows
+        .iter()
+        .map(|row| Ok((row.try_get("id")?, row.try_get("embedding")?)))
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    Ok(queries)
+}
+
+async fn export_vectors(
+    pool: &PgPool,
+    schema: &Schema,
+) -> Result<Vec<(i64, Vec<f32>)>, Box<dyn std::error::Error>> {
+    let query_string = format!("SELECT id, embedding FROM {}", schema.vectors);
+    let rows = sqlx::query(query_string.as_str()).fetch_all(pool).await?;
+    let mut vectors = Vec::with_capacity(rows.len());
+    for row in rows {
+        let embedding: Vector = row.try_get("embedding")?;
+        let mut embedding = embedding.to_vec();
+        normalize(&mut embedding);
+        vectors.push((row.try_get("id")?, embedding));
+    }
+    Ok(vectors)
+}
+
+/// Top k + 1 ids by cosine distance with the given SET LOCAL statements, and how long the
+/// query took. The query's own book is removed from the ids.
+async fn timed_search(
+    pool: &PgPool,
+    schema: &Schema,
+    settings: &[String],
+    query: &(i64, Vector),
+    k: usize,
+) -> Result<(Vec<i64>, Duration), Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT id FROM {} ORDER BY embedding <=> $1 LIMIT $2",
+        schema.vectors
+    );
+    let mut transaction = pool.begin().await?;
+    for setting in settings {
+        sqlx::query(setting.as_str())
+            .execute(&mut *transaction)
+            .await?;
+    }
+    let start = Instant::now();
+    let rows = sqlx::query(query_string.as_str())
+        .bind(&query.1)
+        .bind(k as i64 + 1)
+        .fetch_all(&mut *transaction)
+        .await?;
+    let elapsed = start.elapsed();
+    transaction.commit().await?;
+
+    let mut ids = rows
+        .iter()
+        .map(|row| row.try_get("id"))
+        .collect::<Result<Vec<i64>, _>>()?;
+    ids.retain(|id| *id != query.0);
+    ids.truncate(k);
+    Ok((ids, elapsed))
+}
+
+/// The method of the only ANN index among the index `definitions` of `table`
+pub fn ann_method(table: &str, definitions: &[String]) -> Result<&'static str, String> {
+    let methods: Vec<&'static str> = definitions
+        .iter()
+        .filter_map(|definition| {
+            let definition = definition.to_lowercase();
+            ["hnsw", "ivfflat"]
+                .into_iter()
+                .find(|method| definition.contains(&format!("using {} ", method)))
+        })
+        .collect();
+    match methods.as_slice() {
+        [method] => Ok(method),
+        [] => Err(format!(
+            "{} has no ANN index, build one with `index build` first",
+            table
+        )),
+        _ => Err(format!(
+            "{} has {} ANN indexes ({}) and the planner picks between them, \
+             drop all but the one to benchmark",
+            table,
+            methods.len(),
+            methods.join(", ")
+        )),
+    }
+}
+
+/// The statements for one setting of the sweep and the value that actually runs. These are
+/// the settings `configure_scan` applies to a search with that ef_search or probes, so
+/// iterative scans are on and an ef_search below the rows fetched is raised to them.
+fn sweep_settings(method: &str, value: i32, k: usize) -> (Vec<String>, i32) {
+    let request = SearchRequest {
+        k: k as i64,
+        ef_search: (method == "hnsw").then_some(value),
+        probes: (method != "hnsw").then_some(value),
+        ..Default::default()
+    };
+    // one more row than k, the query's own book is dropped from the results
+    let rows = k as i64 + 1;
+    let value = match method {
+        "hnsw" => scan_ef_search(&request, rows).unwrap_or(value),
+        _ => value,
+    };
+    // a sequential scan would be exact and make any setting look perfect on small tables
+    let mut settings = vec!["SET LOCAL enable_seqscan = off".to_string()];
+    settings.extend(scan_settings(&request, rows));
+    (settings, value)
+}
+
+/// Recall and latency of the ANN index on the model's vectors over the sweep in `options`,
+/// with the exact search as the first row
+pub async fn run_benchmark(
+    pool: &PgPool,
+    schema: &Schema,
+    options: &BenchmarkOptions,
+) -> Result<Vec<SweepRow>, Box<dyn std::error::Error>> {
+    let definitions: Vec<String> = vector_index::index_report(pool, &schema.vectors)
+        .await?
+        .into_iter()
+        .map(|report| report.definition)
+        .collect();
+    let method = ann_method(&schema.vectors.to_string(), &definitions)?;
+    let (parameter, values) = match method {
+        "hnsw" => ("ef_search", &options.ef_search),
+        _ => ("probes", &options.probes),
+    };
+
+    let queries = sample_queries(pool, schema, options.queries, options.seed).await?;
+    if queries.is_empty() {
+        return Err(format!("{} has no vectors to query", schema.vectors).into());
+    }
+    println!(
+        "Computing exact top {} for {} queries ({:?})",
+        options.k,
+        queries.len(),
+        options.exact
+    );
+    let mut exact = Vec::with_capacity(queries.len());
+    let mut exact_latencies = Vec::with_capacity(queries.len());
+    match options.exact {
+        ExactMethod::Rust => {
+            let vectors = export_vectors(pool, schema).await?;
+            for (id, embedding) in &queries {
+                let mut query = embedding.to_vec();
+                normalize(&mut query);
+                let start = Instant::now();
+                exact.push(exact_neighbors(&vectors, &query, options.k, *id));
+                exact_latencies.push(start.elapsed());
+            }
+        }
+        ExactMethod::Sql => {
+            let settings = [
+                "SET LOCAL enable_indexscan = off".to_string(),
+                "SET LOCAL enable_bitmapscan = off".to_string(),
+            ];
+            for query in &queries {
+                let (ids, elapsed) =
+                    timed_search(pool, schema, &settings, query, options.k).await?;
+                exact.push(ids);
+                exact_latencies.push(elapsed);
+            }
+        }
+    }
+    let setting = match options.exact {
+        ExactMethod::Rust => "rust",
+        ExactMethod::Sql => "sql",
+    };
+    let exact_recalls = vec![1.0; queries.len()];
+    let mut rows = vec![sweep_row(
+        "exact",
+        setting,
+        None,
+        options.k,
+        &exact_recalls,
+        exact_latencies,
+    )];
+
+    let first = values.first().copied();
+    for (index, value) in first.into_iter().chain(values.iter().copied()).enumerate() {
+        let (settings, value) = sweep_settings(method, value, options.k);
+        let mut recalls = Vec::with_capacity(queries.len());
+        let mut latencies = Vec::with_capacity(queries.len());
+        for (query, exact_ids) in queries.iter().zip(&exact) {
+            let (ids, elapsed) = timed_search(pool, schema, &settings, query, options.k).await?;
+            recalls.push(recall(exact_ids, &ids));
+            latencies.push(elapsed);
+        }
+        // the first pass warms the cache
+        if index == 0 {
+            continue;
+        }
+        let row = sweep_row(
+            method,
+            parameter,
+            Some(value),
+            options.k,
+            &recalls,
+            latencies,
+        );
+        println!(
+            "{} {} = {}: recall {:.4}, p95 {:.2} ms",
+            method, parameter, value, row.recall, row.p95_ms
+        );
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_neighbors_and_recall() {
+        let mut vectors = vec![
+            (1, vec![1.0, 0.0]),
+            (2, vec![0.9, 0.1]),
+            (3, vec![0.0, 1.0]),
+            (4, vec![0.7, 0.7]),
+            (5, vec![-1.0, 0.0]),
+        ];
+        vectors.iter_mut().for_each(|(_, vector)| normalize(vector));
+        assert_eq!(exact_neighbors(&vectors, &[1.0, 0.0], 3, 1), vec![2, 4, 3]);
+        assert_eq!(exact_neighbors(&vectors, &[1.0, 0.0], 10, 1).len(), 4);
+
+        assert_eq!(recall(&[2, 4, 3], &[4, 2, 5]), 2.0 / 3.0);
+        assert_eq!(recall(&[], &[4]), 1.0);
+    }
+
+    #[test]
+    fn test_sweep_row_percentiles() {
+        let latencies: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let row = sweep_row("hnsw", "ef_search", Some(40), 10, &[1.0, 0.5], latencies);
+        assert_eq!(row.recall, 0.75);
+        assert_eq!(row.min_recall, 0.5);
+        assert_eq!(row.p50_ms, 50.0);
+        assert_eq!(row.p95_ms, 95.0);
+        assert_eq!(row.p99_ms, 99.0);
+        assert_eq!(row.mean_ms, 50.5);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn test_sweep_settings_match_search() {
+        let (settings, value) = sweep_settings("hnsw", 10, 20);
+        assert_eq!(value, 21);
+        assert!(settings.contains(&"SET LOCAL hnsw.iterative_scan = relaxed_order".to_string()));
+        assert!(settings.contains(&"SET LOCAL hnsw.ef_search = 21".to_string()));
+
+        let (settings, value) = sweep_settings("ivfflat", 5, 20);
+        assert_eq!(value, 5);
+        assert!(settings.contains(&"SET LOCAL ivfflat.probes = 5".to_string()));
+        assert!(
+            !settings
+                .iter()
+                .any(|setting| setting.contains("hnsw.ef_search"))
+        );
+    }
+
+    #[test]
+    fn test_ann_method_needs_exactly_one_index() {
+        let definitions = [
+            "CREATE UNIQUE INDEX v_pkey ON public.v USING btree (id)",
+            "CREATE INDEX v_hnsw ON public.v USING hnsw (embedding vector_cosine_ops)",
+            "CREATE INDEX v_ivf ON public.v USING ivfflat (embedding vector_cosine_ops)",
+        ]
+        .map(String::from);
+        assert_eq!(ann_method("v", &definitions[..2]), Ok("hnsw"));
+        assert!(ann_method("v", &definitions[..1]).is_err());
+        let both = ann_method("v", &definitions).unwrap_err();
+        assert!(both.contains("hnsw, ivfflat"));
+    }
+}
@@ -0,0 +1,443 @@
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::config::EmbeddingConfig;
+use crate::embedding_models::EmbeddingModel;
+use crate::models::{intra_threads, query_model, ready_model, ready_tokenizer};
+use crate::output::{Column, OutputFormat, OutputRow, ResultRow, column, write_rows};
+use crate::schema::Schema;
+use crate::search::{SearchFilters, SearchRequest, search_by_vector, year_range};
+
+// Query files are JSONL, one object per line:
+//
+//   {"id": "q1", "text": "whaling voyage", "k": 20, "filters": {"languages": ["en"]}}
+//
+// or CSV with a header row. CSV has no nesting, so the filters are columns named like the
+// command line flags, with ';' between the entries of lists:
+//
+//   id,text,k,author,born_after,born_before,died_after,died_before,lang,subject,bookshelf,exclude
+//   q1,whaling voyage,20,,,1850,,,en;fr,,,2701
+//
+// Only id and text are required, `filters` takes the fields of `SearchFilters`.
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchQuery {
+    pub id: String,
+    pub text: String,
+    /// Overrides the batch's k for this query
+    pub k: Option<i64>,
+    #[serde(default)]
+    pub filters: SearchFilters,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CsvQuery {
+    id: String,
+    text: String,
+    #[serde(default)]
+    k: Option<i64>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    born_after: Option<i32>,
+    #[serde(default)]
+    born_before: Option<i32>,
+    #[serde(default)]
+    died_after: Option<i32>,
+    #[serde(default)]
+    died_before: Option<i32>,
+    #[serde(default)]
+    lang: Option<String>,
+    #[serde(default)]
+    subject: Option<String>,
+    #[serde(default)]
+    bookshelf: Option<String>,
+    #[serde(default)]
+    exclude: Option<String>,
+}
+
+fn csv_list(value: Option<String>) -> Vec<String> {
+    value
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl CsvQuery {
+    fn into_query(self) -> Result<BatchQuery, String> {
+        let exclude_ids = csv_list(self.exclude)
+            .iter()
+            .map(|id| {
+                id.parse().map_err(|_| {
+                    format!(
+                        "query {}: expected book ids in exclude, got {:?}",
+                        self.id, id
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BatchQuery {
+            id: self.id,
+            text: self.text,
+            k: self.k,
+            filters: SearchFilters {
+                author: self.author.filter(|author| !author.trim().is_empty()),
+                birthyear_range: year_range(self.born_after, self.born_before),
+                deathyear_range: year_range(self.died_after, self.died_before),
+                languages: csv_list(self.lang),
+                subjects: csv_list(self.subject),
+                bookshelves: csv_list(self.bookshelf),
+                exclude_ids,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+fn parse_jsonl(text: &str) -> Result<Vec<BatchQuery>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+fn parse_csv(text: &str) -> Result<Vec<BatchQuery>, String> {
+    csv::Reader::from_reader(text.as_bytes())
+        .deserialize::<CsvQuery>()
+        .map(|record| record.map_err(|e| e.to_string())?.into_query())
+        .collect()
+}
+
+fn check_queries(queries: &[BatchQuery]) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    for query in queries {
+        if query.text.trim().is_empty() {
+            return Err(format!("query {} has no text", query.id));
+        }
+        if !ids.insert(query.id.as_str()) {
+            return Err(format!("query id {} appears more than once", query.id));
+        }
+        if query.k.is_some_and(|k| k < 1) {
+            return Err(format!("query {} has k < 1", query.id));
+        }
+    }
+    Ok(())
+}
+
+/// Reads a .jsonl or .csv query file, see the top of this file for the layout
+pub fn read_queries(path: &Path) -> Result<Vec<BatchQuery>, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read query file {}: {}", path.display(), e))?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let queries = match extension {
+        Some("jsonl") => parse_jsonl(&text),
+        Some("csv") => parse_csv(&text),
+        _ => Err("expected a .jsonl or .csv file".to_string()),
+    }
+    .map_err(|e| format!("{}: {}", path.display(), e))?;
+    check_queries(&queries).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(queries)
+}
+
+/// The result set of one query. A query whose search failed has `error` set and no results.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryResults {
+    pub query_id: String,
+    pub text: String,
+    pub results: Vec<ResultRow>,
+    pub error: Option<String>,
+}
+
+/// Embeds the queries `embedding.query_batch_size` at a time with a single model session
+/// and searches for each one concurrently, with at most as many searches in flight as the
+/// pool has connections. Results are in query order.
+pub async fn run_queries(
+    pool: &PgPool,
+    schema: &Schema,
+    model: &EmbeddingModel,
+    queries: &[BatchQuery],
+    defaults: &SearchRequest,
+    embedding: &EmbeddingConfig,
+) -> Result<Vec<QueryResults>, Box<dyn std::error::Error>> {
+    let batch_size = embedding.query_batch_size.max(1);
+    let intra_threads = intra_threads(embedding.intra_threads, 1);
+    let mut session = Some(ready_model(&model.model_path, intra_threads)?);
+    let tokenizer = Arc::new(ready_tokenizer(&model.tokenizer_path)?);
+    let mut searches = JoinSet::new();
+    let permits = Arc::new(Semaphore::new(pool.options().get_max_connections() as usize));
+
+    for (batch_index, batch) in queries.chunks(batch_size).enumerate() {
+        let texts: Vec<String> = batch.iter().map(|query| query.text.clone()).collect();
+        let mut batch_session = session
+            .take()
+            .expect("the session is returned after each batch");
+        let batch_tokenizer = Arc::clone(&tokenizer);
+        // inference runs off the runtime thread so the previous batch's searches keep going
+        let (returned, embeddings) = tokio::task::spawn_blocking(move || {
+            let inputs = texts.iter().map(String::as_str).collect();
+            let embeddings = query_model(&mut batch_session, &batch_tokenizer, inputs)
+                .map_err(|e| e.to_string());
+            (batch_session, embeddings)
+        })
+        .await?;
+        session = Some(returned);
+        let embeddings = embeddings?;
+        if embeddings.len() != batch.len() {
+            return Err(format!(
+                "model returned {} embeddings for {} queries",
+                embeddings.len(),
+                batch.len()
+            )
+            .into());
+        }
+
+        for (offset, (query, (_, embedding))) in batch.iter().zip(embeddings).enumerate() {
+            let index = batch_index * batch_size + offset;
+            let pool = pool.clone();
+            let schema = schema.clone();
+            let request = SearchRequest {
+                k: query.k.unwrap_or(defaults.k),
+                filters: query.filters.clone(),
+                ef_search: defaults.ef_search,
+                probes: defaults.probes,
+            };
+            // waiting here also holds back the next batch until the searches catch up
+            let permit = Arc::clone(&permits).acquire_owned().await?;
+            searches.spawn(async move {
+                let _permit = permit;
+                let outcome = search_by_vector(&pool, &schema, &Vector::from(embedding), &request)
+                    .await
+                    .map_err(|e| e.to_string());
+                (index, outcome)
+            });
+        }
+    }
+
+    let mut outcomes: Vec<_> = (0..queries.len()).map(|_| None).collect();
+    while let Some(joined) = searches.join_next().await {
+        let (index, outcome) = joined?;
+        outcomes[index] = Some(outcome);
+    }
+    Ok(queries
+        .iter()
+        .zip(outcomes)
+        .map(|(query, outcome)| {
+            let (results, error) = match outcome.expect("every query was searched") {
+                Ok(results) => (ResultRow::from_results(&results), None),
+                Err(e) => (Vec::new(), Some(e)),
+            };
+            QueryResults {
+                query_id: query.id.clone(),
+                text: query.text.clone(),
+                results,
+                error,
+            }
+        })
+        .collect())
+}
+
+/// A `ResultRow` with its query, for the flat output formats
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchRow {
+    pub query_id: String,
+    #[serde(flatten)]
+    pub result: ResultRow,
+}
+
+impl OutputRow for BatchRow {
+    const COLUMNS: &'static [Column] = &[
+        column("query_id", 12, false),
+        column("rank", 4, true),
+        column("id", 6, true),
+        column("title", 50, false),
+        column("author", 30, false),
+        column("score", 6, true),
+        column("summary", 60, false),
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        let mut cells = vec![self.query_id.clone()];
+        cells.extend(self.result.cells());
+        cells
+    }
+}
+
+/// json and jsonl keep one `QueryResults` per query, the other formats have a row per
+/// result with its query id. Failed queries are only in json and jsonl.
+pub fn write_results(
+    out: &mut impl Write,
+    results: &[QueryResults],
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(results)?)?,
+        OutputFormat::Jsonl => {
+            for query_results in results {
+                writeln!(out, "{}", serde_json::to_string(query_results)?)?;
+            }
+        }
+        OutputFormat::Table | OutputFormat::Csv | OutputFormat::Markdown => {
+            let rows: Vec<BatchRow> = results
+                .iter()
+                .flat_map(|query_results| {
+                    query_results.results.iter().map(|result| BatchRow {
+                        query_id: query_results.query_id.clone(),
+                        result: result.clone(),
+                    })
+                })
+                .collect();
+            write_rows(out, &rows, format)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs every query in `input` and writes the result sets to `output`
+#[allow(clippy::too_many_arguments)]
+pub async fn run_batch(
+    pool: &PgPool,
+    schema: &Schema,
+    model: &EmbeddingModel,
+    input: &Path,
+    output: &Path,
+    format: OutputFormat,
+    defaults: &SearchRequest,
+    embedding: &EmbeddingConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let queries = read_queries(input)?;
+    let started = Instant::now();
+    let results = run_queries(pool, schema, model, &queries, defaults, embedding).await?;
+
+    let mut out = BufWriter::new(File::create(output)?);
+    write_results(&mut out, &results, format)?;
+    out.flush()?;
+
+    let failed: Vec<&QueryResults> = results.iter().filter(|r| r.error.is_some()).collect();
+    for query_results in &failed {
+        println!(
+            "Query {} failed: {}",
+            query_results.query_id,
+            query_results.error.as_deref().unwrap_or_default()
+        );
+    }
+    println!(
+        "Ran {} queries ({} failed) in {:.1?}, results written to {}",
+        results.len(),
+        failed.len(),
+        started.elapsed(),
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query_files() {
+        let jsonl = r#"{"id": "q1", "text": "whaling voyage", "k": 20}
+
+{"id": "q2", "text": "a governess in a haunted house", "filters": {"languages": ["en"]}}
+"#;
+        let queries = parse_jsonl(jsonl).unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].k, Some(20));
+        assert_eq!(queries[1].filters.languages, vec!["en"]);
+        assert!(
+            parse_jsonl(r#"{"id": "q1", "txt": "whaling"}"#)
+                .unwrap_err()
+                .starts_with("line 1:")
+        );
+
+        let csv = "id,text,k,born_before,lang,exclude\n\
+                   q1,whaling voyage,20,1850,en;fr,2701\n\
+                   q2,\"ghosts, governesses\",,,,\n";
+        let queries = parse_csv(csv).unwrap();
+        assert_eq!(queries[0].k, Some(20));
+        assert_eq!(queries[0].filters.languages, vec!["en", "fr"]);
+        assert_eq!(queries[0].filters.exclude_ids, vec![2701]);
+        assert_eq!(queries[0].filters.birthyear_range, Some((i32::MIN, 1850)));
+        assert_eq!(queries[1].filters.birthyear_range, None);
+        assert_eq!(queries[1].text, "ghosts, governesses");
+        assert_eq!(queries[1].k, None);
+        assert!(
+            queries[1].filters.languages.is_empty() && queries[1].filters.exclude_ids.is_empty()
+        );
+        assert!(parse_csv("id,text,exclude\nq1,whaling,moby\n").is_err());
+        assert!(parse_csv("id,text,language\nq1,whaling,en\n").is_err());
+    }
+
+    #[test]
+    fn test_check_queries() {
+        let query = |id: &str, text: &str| BatchQuery {
+            id: id.to_string(),
+            text: text.to_string(),
+            k: None,
+            filters: SearchFilters::default(),
+        };
+        assert!(check_queries(&[query("q1", "whales"), query("q2", "ghosts")]).is_ok());
+        assert!(check_queries(&[query("q1", "whales"), query("q1", "ghosts")]).is_err());
+        assert!(check_queries(&[query("q1", "  ")]).is_err());
+    }
+
+    #[test]
+    fn test_write_results() {
+        let results = vec![
+            QueryResults {
+                query_id: "q1".to_string(),
+                text: "whaling voyage".to_string(),
+                results: vec![ResultRow {
+                    rank: 1,
+                    id: 2701,
+                    title: "Moby Dick; Or, The Whale".to_string(),
+                    author: "Melville, Herman".to_string(),
+                    score: 0.8,
+                    summary: None,
+                }],
+                error: None,
+            },
+            QueryResults {
+                query_id: "q2".to_string(),
+                text: "ghosts".to_string(),
+                results: Vec::new(),
+                error: Some("connection reset".to_string()),
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_results(&mut out, &results, OutputFormat::Jsonl).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["results"][0]["id"], 2701);
+        assert_eq!(lines[1]["error"], "connection reset");
+
+        let mut out = Vec::new();
+        write_results(&mut out, &results, OutputFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "query_id,rank,id,title,author,score,summary\r\n\
+             q1,1,2701,\"Moby Dick; Or, The Whale\",\"Melville, Herman\",0.8000,\r\n"
+        );
+    }
+}
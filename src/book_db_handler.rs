@@ -1,12 +1,20 @@
-use pgvector::Vector;
-use rayon::prelude::*;
+use serde::Serialize;
 use sqlx::Row;
-use sqlx::postgres::PgPool;
-use std::cell::RefCell;
-use std::sync::mpsc;
+use sqlx::postgres::{PgPool, PgRow};
+use std::path::Path;
 
 use crate::book_metadata::RdfFileIterator;
-use crate::models::{query_model, ready_model, ready_tokenizer};
+use crate::bulk::MetadataWriter;
+use crate::chunks::{ChunkOptions, index_book_text};
+use crate::ebook_text::{chapters_text, load_ebook_chapters};
+use crate::embedding_models::{
+    EmbeddingModel, VectorKind, ensure_vector_table, load_model, schema_for_model,
+};
+use crate::gutenberg_text::load_plain_text;
+use crate::jobs::{self, JobParameters, JobStatus};
+use crate::models::{self, model_fingerprint, ready_model, ready_tokenizer};
+use crate::pipeline::{BookSource, run_indexing_job};
+use crate::schema::Schema;
 
 // psql -U postgres
 // sudo -i -u postgres
@@ -27,288 +35,457 @@ use crate::models::{query_model, ready_model, ready_tokenizer};
 // grant all on sequence table_name_id_seq to role_name;
 // grant all on all sequences in schema public to role_name;
 
+/// Loads every RDF file in `catalog_dir` into the metadata table along with its contributors
+/// and subjects, `batch_size` books per transaction, keeping YAML copies in `metadata_dir`
+/// if given. The tables are created by `migrations::run_migrations`. Re-running it updates changed books in place, and a full
+/// scan (no `id_range`) also tombstones books no longer in the catalog. Books the database
+/// rejects are reported and skipped, see `MetadataWriter::flush`.
 pub async fn set_up_metadata_table(
     pool: &PgPool,
-    table_name: &str,
+    schema: &Schema,
+    catalog_dir: &str,
+    id_range: Option<(u32, u32)>,
+    metadata_dir: Option<&Path>,
+    batch_size: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let table_creation_string = format!(
-        "
-        CREATE TABLE IF NOT EXISTS {} (
-        id bigint PRIMARY KEY,
-        title TEXT NOT NULL,
-        author TEXT NOT NULL,
-        birthyear INTEGER,
-        deathyear INTEGER,
-        summary TEXT
-        )",
-        table_name
-    );
-
-    sqlx::query(table_creation_string.as_str())
-        .execute(pool)
-        .await?;
-
-    // let all_metadata = process_all_rdf_files("data/cache/epub", Some((1, 10)), Some(false))?;
-    let metadata_iterator = RdfFileIterator::new("data/cache/epub", None, Some(false))?;
-
-    for metadata in metadata_iterator {
-        let metadata = metadata?;
-        let insert_string = format!(
-            "
-            INSERT INTO {} (id, title, author, birthyear, deathyear, summary) VALUES ($1, $2, $3, $4, $5, $6)
-            ",
-            table_name
-        );
-        let birthyear = match metadata.birthyear.parse::<i32>() {
-            Ok(year) => Some(year),
-            Err(_) => None,
-        };
-        let deathyear = match metadata.deathyear.parse::<i32>() {
-            Ok(year) => Some(year),
-            Err(_) => None,
-        };
-        match sqlx::query(insert_string.as_str())
-            .bind(metadata.id)
-            .bind(metadata.title)
-            .bind(metadata.author)
-            .bind(birthyear)
-            .bind(deathyear)
-            .bind(metadata.summary)
-            .execute(pool)
-            .await
-        {
-            Ok(_) => (),
-            Err(e) => {
-                println!("Error inserting metadata for book {}: {}", metadata.id, e);
-                continue;
-            }
-        };
+    let metadata_dir = metadata_dir.map(Path::to_path_buf);
+    let mut metadata_iterator = RdfFileIterator::new(catalog_dir, id_range, metadata_dir)?;
+    let mut writer = MetadataWriter::new(pool, schema, batch_size);
+    // a book is still in the catalog if its directory is, even if its RDF did not parse
+    let seen_ids: Vec<i64> = metadata_iterator
+        .book_ids()
+        .iter()
+        .map(|&id| id as i64)
+        .collect();
+
+    for metadata in metadata_iterator.by_ref() {
+        writer.push(metadata?).await?;
     }
 
+    writer.finish().await?;
+    // a partial scan has not seen the books outside its range
+    if id_range.is_none() {
+        if metadata_iterator.failures() > 0 {
+            println!(
+                "Not tombstoning missing books, {} RDF files failed to parse",
+                metadata_iterator.failures()
+            );
+        } else if seen_ids.is_empty() {
+            println!(
+                "Not tombstoning missing books, {} has no book directories",
+                catalog_dir
+            );
+        } else {
+            let tombstoned = tombstone_missing_books(pool, schema, &seen_ids).await?;
+            println!("Tombstoned {} books no longer in the catalog", tombstoned);
+        }
+    }
     Ok(())
 }
 
-pub async fn set_up_vector_table(
+/// Marks books that were not seen in a full catalog scan as deleted. Their rows are kept
+/// so ids in reading histories and evaluation sets still resolve, but search skips them.
+pub async fn tombstone_missing_books(
     pool: &PgPool,
-    table_name: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    sqlx::query("CREATE EXTENSION IF NOT EXISTS vector")
-        .execute(pool)
-        .await?;
-    println!("added pgvector extension");
-
-    let table_creation_string = format!(
-        "
-        CREATE TABLE IF NOT EXISTS {} (
-        id bigserial PRIMARY KEY,
-        embedding vector(1024)
-    )",
-        table_name
+    schema: &Schema,
+    seen_ids: &[i64],
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let update_string = format!(
+        "UPDATE {} SET deleted_at = now() WHERE deleted_at IS NULL AND id <> ALL($1)",
+        schema.metadata
     );
-
-    sqlx::query(table_creation_string.as_str())
-        .bind(table_name)
+    let result = sqlx::query(update_string.as_str())
+        .bind(seen_ids)
         .execute(pool)
         .await?;
+    Ok(result.rows_affected())
+}
 
-    let model_path = "/home/sand/coding/qwen3-test/model.onnx";
-    let tokenizer_path = "/home/sand/coding/qwen3-test/tokenizer.json";
-    let mut session = ready_model(model_path)?;
-    let tokenizer = ready_tokenizer(tokenizer_path);
-
-    let metadata_iterator = RdfFileIterator::new("data/cache/epub", None, Some(false))?;
-
-    for metadata in metadata_iterator {
-        let metadata = metadata?;
-
-        let id = metadata.id;
-        let result = sqlx::query("select exists(select 1 from book_summary_vectors where id = $1)")
-            .bind(id)
-            .fetch_all(pool)
-            .await?;
-        let val: bool = result.get(0).unwrap().get(0);
-        if val {
-            println!("already did id {}", id);
-            continue;
-        }
-
-        let summary = vec![metadata.summary.as_str()];
-        let summary_vector = Vector::from(
-            query_model(&mut session, &tokenizer, summary)?
-                .first()
-                .unwrap()
-                .1
-                .clone(),
-        );
-        let insert_string = format!(
-            "
-            INSERT INTO {} (id, embedding) VALUES ($1, $2)
-            ",
-            table_name
-        );
+/// A book whose summary has no embedding yet, or whose embedding was made
+/// from an older summary or a different model.
+pub struct PendingBook {
+    pub id: i64,
+    pub summary: String,
+    pub content_hash: String,
+}
 
-        match sqlx::query(insert_string.as_str())
-            .bind(metadata.id)
-            .bind(summary_vector)
-            .execute(pool)
-            .await
-        {
-            Ok(_) => (),
-            Err(e) => {
-                println!("Error inserting metadata for book {}: {}", metadata.id, e);
-                continue;
-            }
-        };
+fn pending_books_from_rows(
+    rows: Vec<PgRow>,
+) -> Result<Vec<PendingBook>, Box<dyn std::error::Error>> {
+    let mut pending = Vec::with_capacity(rows.len());
+    for row in rows {
+        pending.push(PendingBook {
+            id: row.try_get("id")?,
+            summary: row.try_get("summary")?,
+            content_hash: row.try_get("content_hash")?,
+        });
     }
-
-    Ok(())
+    Ok(pending)
 }
 
-pub async fn set_up_vector_table_par(
+/// Pending books in id order, optionally only those after a job's checkpoint.
+/// `limit` pages through the catalog without holding every summary in memory.
+pub async fn pending_embeddings(
     pool: &PgPool,
-    table_name: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    sqlx::query("CREATE EXTENSION IF NOT EXISTS vector")
-        .execute(pool)
+    schema: &Schema,
+    model_fingerprint: &str,
+    after_id: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Vec<PendingBook>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "
+        SELECT m.id, coalesce(m.summary, '') AS summary, m.content_hash
+        FROM {} m
+        LEFT JOIN {} v ON v.id = m.id
+        WHERE m.deleted_at IS NULL
+        AND m.id > coalesce($2, -1)
+        AND (v.id IS NULL
+            OR v.content_hash IS DISTINCT FROM m.content_hash
+            OR v.model_fingerprint IS DISTINCT FROM $1)
+        ORDER BY m.id
+        LIMIT $3
+        ",
+        schema.metadata, schema.vectors
+    );
+    let rows = sqlx::query(query_string.as_str())
+        .bind(model_fingerprint)
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(pool)
         .await?;
-    println!("added pgvector extension");
+    pending_books_from_rows(rows)
+}
 
-    let table_creation_string = format!(
+pub async fn books_by_id(
+    pool: &PgPool,
+    schema: &Schema,
+    ids: &[i64],
+) -> Result<Vec<PendingBook>, Box<dyn std::error::Error>> {
+    let query_string = format!(
         "
-        CREATE TABLE IF NOT EXISTS {} (
-        id bigserial PRIMARY KEY,
-        embedding vector(1024)
-    )",
-        table_name
+        SELECT id, coalesce(summary, '') AS summary, content_hash
+        FROM {} WHERE id = ANY($1) AND deleted_at IS NULL
+        ORDER BY id
+        ",
+        schema.metadata
     );
-
-    sqlx::query(table_creation_string.as_str())
-        .bind(table_name)
-        .execute(pool)
+    let rows = sqlx::query(query_string.as_str())
+        .bind(ids)
+        .fetch_all(pool)
         .await?;
+    pending_books_from_rows(rows)
+}
 
-    let model_path = "/home/sand/coding/qwen3-test/model.onnx";
-    let tokenizer_path = "/home/sand/coding/qwen3-test/tokenizer.json";
-    let tokenizer = ready_tokenizer(tokenizer_path);
+fn job_parameters(
+    model: &EmbeddingModel,
+    batch_size: usize,
+    workers: usize,
+    intra_threads: Option<usize>,
+) -> JobParameters {
+    JobParameters {
+        model_name: model.name.clone(),
+        model_path: model.model_path.clone(),
+        tokenizer_path: model.tokenizer_path.clone(),
+        batch_size,
+        workers,
+        intra_threads: models::intra_threads(intra_threads, workers),
+    }
+}
 
-    let metadata_iterator = RdfFileIterator::new("data/cache/epub", None, Some(false))?;
+/// Embeds every pending book with `model_name` (see `embedding_models::register_model`)
+/// using `workers` embedding threads, as a new indexing job. `intra_threads` is the
+/// ONNX Runtime threads per worker, None splits the cores between the workers.
+/// Returns the job id for `resume_indexing_job` and `retry_failed_embeddings`.
+pub async fn set_up_vector_table(
+    pool: &PgPool,
+    schema: &Schema,
+    model_name: &str,
+    batch_size: usize,
+    workers: usize,
+    intra_threads: Option<usize>,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let model = load_model(pool, schema, model_name).await?;
+    let parameters = job_parameters(&model, batch_size, workers, intra_threads);
+    let fingerprint = model_fingerprint(&parameters.model_path)?;
+    let job = jobs::create_job(pool, schema, &parameters, &fingerprint).await?;
+    let source = BookSource::Pending { after_id: None };
+    run_indexing_job(pool, schema, &job, source).await?;
+    Ok(job.id)
+}
 
-    // Collect metadata that needs processing
-    let mut metadata_to_process = Vec::new();
-    for metadata in metadata_iterator {
-        let metadata = metadata?;
-        let id = metadata.id;
+/// Same as `set_up_vector_table`, with one embedding worker (and model session)
+/// per available core.
+pub async fn set_up_vector_table_par(
+    pool: &PgPool,
+    schema: &Schema,
+    model_name: &str,
+    batch_size: usize,
+    intra_threads: Option<usize>,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    set_up_vector_table(pool, schema, model_name, batch_size, workers, intra_threads).await
+}
 
-        let result = sqlx::query("select exists(select 1 from book_summary_vectors where id = $1)")
-            .bind(id)
-            .fetch_all(pool)
-            .await?;
-        let val: bool = result.get(0).unwrap().get(0);
+/// Continues an interrupted job from its checkpoint with the job's original parameters.
+pub async fn resume_indexing_job(
+    pool: &PgPool,
+    schema: &Schema,
+    job_id: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let job = jobs::load_job(pool, schema, job_id).await?;
+    if job.status == JobStatus::Completed {
+        println!("Job {} already completed", job.id);
+        return Ok(());
+    }
+    if model_fingerprint(&job.parameters.model_path)? != job.model_fingerprint {
+        return Err(format!(
+            "model at {} changed since job {} started, start a new job instead",
+            job.parameters.model_path, job.id
+        )
+        .into());
+    }
 
-        if val {
-            println!("already did id {}", id);
-            continue;
-        }
+    jobs::set_job_status(pool, schema, job.id, JobStatus::Running).await?;
+    let source = BookSource::Pending {
+        after_id: job.last_processed_id,
+    };
+    run_indexing_job(pool, schema, &job, source).await
+}
 
-        metadata_to_process.push(metadata);
-    }
+/// Re-embeds only the books that failed in a job. The checkpoint is left where it is.
+pub async fn retry_failed_embeddings(
+    pool: &PgPool,
+    schema: &Schema,
+    job_id: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let job = jobs::load_job(pool, schema, job_id).await?;
+    let failures = jobs::job_failures(pool, schema, job_id).await?;
+    let ids: Vec<i64> = failures.iter().map(|failure| failure.book_id).collect();
+    println!("Retrying {} failed books from job {}", ids.len(), job.id);
 
-    // Process in batches: compute vectors in parallel, then insert, repeat
-    let batch_size = 100;
-    thread_local! {
-        static MODEL_SESSION: RefCell<Option<ort::session::Session>> = RefCell::new(None);
-    }
+    jobs::set_job_status(pool, schema, job.id, JobStatus::Running).await?;
+    run_indexing_job(pool, schema, &job, BookSource::Ids(ids)).await
+}
 
-    for chunk in metadata_to_process.chunks(batch_size) {
-        // Parallel processing of model inference for this batch
-        let results: Vec<_> = chunk
-            .par_iter()
-            .map(|metadata| {
-                MODEL_SESSION.with(|session_cell| {
-                    let mut session_opt = session_cell.borrow_mut();
-                    if session_opt.is_none() {
-                        *session_opt = ready_model(model_path).ok();
-                    }
-
-                    let session = session_opt.as_mut()?;
-                    let summary = vec![metadata.summary.as_str()];
-                    let summary_vector = query_model(session, &tokenizer, summary)
-                        .ok()?
-                        .first()
-                        .map(|(_, vec)| Vector::from(vec.clone()))?;
-
-                    Some((metadata.id, summary_vector))
-                })
-            })
-            .collect();
-
-        // Insert this batch into the database
-        for result in results {
-            if let Some((id, summary_vector)) = result {
-                let insert_string = format!(
-                    "
-                    INSERT INTO {} (id, embedding) VALUES ($1, $2)
-                    ",
-                    table_name
-                );
-
-                match sqlx::query(insert_string.as_str())
-                    .bind(id)
-                    .bind(summary_vector)
-                    .execute(pool)
-                    .await
-                {
-                    Ok(_) => (),
-                    Err(e) => {
-                        println!("Error inserting metadata for book {}: {}", id, e);
-                        continue;
-                    }
-                };
+/// Chunks and embeds the full text of every book in `catalog_dir` with `model_name`.
+/// `mirror_dir` holds the book files laid out like the catalog, see
+/// `gutenberg_text::mirror_path`. Books without a mirrored text, or not yet in the
+/// metadata table, are skipped.
+#[allow(clippy::too_many_arguments)]
+pub async fn index_full_texts(
+    pool: &PgPool,
+    schema: &Schema,
+    model_name: &str,
+    catalog_dir: &str,
+    mirror_dir: &Path,
+    id_range: Option<(u32, u32)>,
+    options: &ChunkOptions,
+    intra_threads: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (schema, model) = schema_for_model(pool, schema, model_name).await?;
+    ensure_vector_table(pool, &schema, &model, VectorKind::Chunk).await?;
+    let mut session = ready_model(&model.model_path, models::intra_threads(intra_threads, 1))?;
+    let tokenizer = ready_tokenizer(&model.tokenizer_path)?;
+    let fingerprint = model_fingerprint(&model.model_path)?;
+    let (mut indexed, mut skipped, mut chunks) = (0, 0, 0);
+
+    for metadata in RdfFileIterator::new(catalog_dir, id_range, None)? {
+        let metadata = metadata?;
+        let book_id = metadata.id as i64;
+        let mut text = load_plain_text(mirror_dir, &metadata)?;
+        if text.is_none() {
+            // books without a plain-text rendition
+            match load_ebook_chapters(mirror_dir, &metadata) {
+                Ok(chapters) => text = chapters.map(|chapters| chapters_text(&chapters)),
+                Err(e) => println!("Failed to extract the ebook of book {}: {}", book_id, e),
+            }
+        }
+        let Some(text) = text else {
+            skipped += 1;
+            continue;
+        };
+        match index_book_text(
+            pool,
+            &schema,
+            &mut session,
+            &tokenizer,
+            &fingerprint,
+            book_id,
+            &text,
+            options,
+        )
+        .await
+        {
+            Ok(written) => {
+                indexed += 1;
+                chunks += written;
+            }
+            Err(e) => {
+                println!("Failed to index the text of book {}: {}", book_id, e);
+                skipped += 1;
             }
         }
-
-        println!("Completed batch of {} items", chunk.len());
     }
 
+    println!(
+        "Indexed {} full texts ({} passages written), skipped {}",
+        indexed, chunks, skipped
+    );
     Ok(())
 }
 
-pub async fn query_sample_text(
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogStats {
+    pub books: i64,
+    pub deleted_books: i64,
+    pub books_with_summary: i64,
+    /// Live books with a row in the model's summary vector table
+    pub embedded_books: i64,
+    /// Passages of live books in the model's chunk table, 0 if it has not been created
+    pub passages: i64,
+    pub books_with_passages: i64,
+}
+
+/// Row counts for the catalog and the vectors in `schema`, see `schema_for_model`
+pub async fn catalog_stats(
     pool: &PgPool,
-    text: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let model_path = "/home/sand/coding/qwen3-test/model.onnx";
-    let tokenizer_path = "/home/sand/coding/qwen3-test/tokenizer.json";
-    let mut session = ready_model(model_path)?;
-    let tokenizer = ready_tokenizer(tokenizer_path);
-    let text_embedding = Vector::from(
-        query_model(&mut session, &tokenizer, vec![text])?
-            .first()
-            .unwrap()
-            .1
-            .clone(),
+    schema: &Schema,
+) -> Result<CatalogStats, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "
+        SELECT count(*) FILTER (WHERE deleted_at IS NULL) AS books,
+        count(*) FILTER (WHERE deleted_at IS NOT NULL) AS deleted_books,
+        count(*) FILTER (
+            WHERE deleted_at IS NULL AND coalesce(summary, '') <> ''
+        ) AS books_with_summary,
+        (
+            SELECT count(*) FROM {} v
+            JOIN {} m ON m.id = v.id
+            WHERE m.deleted_at IS NULL
+        ) AS embedded_books
+        FROM {}
+        ",
+        schema.vectors, schema.metadata, schema.metadata
     );
+    let row = sqlx::query(query_string.as_str()).fetch_one(pool).await?;
+
+    let chunks_exist: bool = sqlx::query("SELECT to_regclass($1) IS NOT NULL AS found")
+        .bind(schema.chunks.to_string())
+        .fetch_one(pool)
+        .await?
+        .try_get("found")?;
+    let (passages, books_with_passages) = if chunks_exist {
+        let query_string = format!(
+            "
+            SELECT count(*) AS passages, count(DISTINCT c.book_id) AS books
+            FROM {} c
+            JOIN {} m ON m.id = c.book_id
+            WHERE m.deleted_at IS NULL
+            ",
+            schema.chunks, schema.metadata
+        );
+        let row = sqlx::query(query_string.as_str()).fetch_one(pool).await?;
+        (row.try_get("passages")?, row.try_get("books")?)
+    } else {
+        (0, 0)
+    };
+
+    Ok(CatalogStats {
+        books: row.try_get("books")?,
+        deleted_books: row.try_get("deleted_books")?,
+        books_with_summary: row.try_get("books_with_summary")?,
+        embedded_books: row.try_get("embedded_books")?,
+        passages,
+        books_with_passages,
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Contributor {
+    pub name: String,
+    /// MARC relator code, "aut" for the author
+    pub role: String,
+    pub birthyear: Option<i32>,
+    pub deathyear: Option<i32>,
+}
 
+#[derive(Debug, Clone, Serialize)]
+pub struct BookDetail {
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    pub birthyear: Option<i32>,
+    pub deathyear: Option<i32>,
+    pub summary: Option<String>,
+    pub languages: Vec<String>,
+    pub subjects: Vec<String>,
+    pub bookshelves: Vec<String>,
+    pub contributors: Vec<Contributor>,
+    /// Whether the book has a vector in `schema.vectors`, i.e. can seed similar and recommend
+    pub embedded: bool,
+}
+
+/// Everything stored about a book that is still in the catalog, None if there is no such book
+pub async fn book_detail(
+    pool: &PgPool,
+    schema: &Schema,
+    id: i64,
+) -> Result<Option<BookDetail>, Box<dyn std::error::Error>> {
     let query_string = format!(
         "
-        with top3 as (
-            SELECT *, embedding <=> $1 as distance
-            FROM book_summary_vectors ORDER BY embedding <=> $1 LIMIT 3
-        )
-        select *
-        from book_metadata m
-        join top3 t
-        on m.id = t.id
-        "
+        SELECT m.id, m.title, m.author, m.birthyear, m.deathyear, m.summary, m.languages,
+        ARRAY(SELECT heading FROM {subjects} s WHERE s.book_id = m.id AND s.scheme = 'LCSH'
+            ORDER BY heading) AS subjects,
+        ARRAY(SELECT heading FROM {subjects} s WHERE s.book_id = m.id AND s.scheme = 'bookshelf'
+            ORDER BY heading) AS bookshelves,
+        EXISTS (SELECT 1 FROM {vectors} v WHERE v.id = m.id) AS embedded
+        FROM {metadata} m
+        WHERE m.id = $1 AND m.deleted_at IS NULL
+        ",
+        subjects = schema.subjects,
+        vectors = schema.vectors,
+        metadata = schema.metadata
     );
+    let Some(row) = sqlx::query(query_string.as_str())
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+    else {
+        return Ok(None);
+    };
 
-    let result = sqlx::query(query_string.as_str())
-        .bind(text_embedding)
+    let query_string = format!(
+        "SELECT name, role, birthyear, deathyear FROM {} WHERE book_id = $1 ORDER BY role, name",
+        schema.contributors
+    );
+    let contributors = sqlx::query(query_string.as_str())
+        .bind(id)
         .fetch_all(pool)
-        .await?;
-
-    println!("Query result: {:?}", result);
-    Ok(())
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(Contributor {
+                name: row.try_get("name")?,
+                role: row.try_get("role")?,
+                birthyear: row.try_get("birthyear")?,
+                deathyear: row.try_get("deathyear")?,
+            })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+
+    Ok(Some(BookDetail {
+        id: row.try_get("id")?,
+        title: row.try_get("title")?,
+        author: row.try_get("author")?,
+        birthyear: row.try_get("birthyear")?,
+        deathyear: row.try_get("deathyear")?,
+        summary: row.try_get("summary")?,
+        languages: row.try_get("languages")?,
+        subjects: row.try_get("subjects")?,
+        bookshelves: row.try_get("bookshelves")?,
+        contributors,
+        embedded: row.try_get("embedded")?,
+    }))
 }
 
 #[cfg(test)]
@@ -319,19 +496,41 @@ mod tests {
     use sqlx::postgres::PgPoolOptions;
 
     use super::*;
+    use crate::output::{OutputFormat, ResultRow, print_rows};
+    use crate::search::{SearchRequest, search_text};
+
+    async fn query_sample_text(
+        pool: &PgPool,
+        schema: &Schema,
+        model_name: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (schema, model) = schema_for_model(pool, schema, model_name).await?;
+        let mut session = ready_model(&model.model_path, models::intra_threads(None, 1))?;
+        let tokenizer = ready_tokenizer(&model.tokenizer_path)?;
+        let request = SearchRequest {
+            k: 3,
+            ..Default::default()
+        };
+
+        let results = search_text(pool, &schema, &mut session, &tokenizer, text, &request).await?;
+
+        print_rows(&ResultRow::from_results(&results), OutputFormat::Table)
+    }
 
     #[tokio::test]
     async fn test_set_up_metadata_table() -> Result<(), Box<dyn std::error::Error>> {
         dotenv().ok();
-        let DATABASE_URL = env::var("DATABASE_URL")?;
+        let database_url = env::var("DATABASE_URL")?;
         let pool = PgPoolOptions::new()
             .max_connections(5)
-            .connect(&DATABASE_URL)
+            .connect(&database_url)
             .await?;
-        let table_name = "book_metadata";
-        match set_up_metadata_table(&pool, table_name).await {
+        crate::migrations::run_migrations(&pool).await?;
+        let schema = Schema::default();
+        match set_up_metadata_table(&pool, &schema, "data/cache/epub", None, None, 1000).await {
             Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
@@ -341,7 +540,7 @@ mod tests {
             .max_connections(5)
             .connect(&env::var("DATABASE_URL")?)
             .await?;
-        let table_name = "book_summary_vectors";
+        let schema = Schema::default();
         // let text = "A woman and a man fall in love. The man's friend pursues the woman's younger sister, who does not like him at first.";
         // let text = "Lockwood, the new tenant of Thrushcross Grange, situated on the bleak Yorkshire moors, is forced to seek shelter one night at Wuthering Heights, the home of his landlord. There he discovers the history of the tempestuous events that took place years before; of the intense relationship between the gypsy foundling Heathcliff and Catherine Earnshaw; and how Catherine, forced to choose between passionate, tortured Heathcliff and gentle, well-bred Edgar Linton, surrendered to the expectations of her class. As Heathcliff's bitterness and vengeance at his betrayal is visited upon the next generation, their innocent heirs must struggle to escape the legacy of the past.";
         // let text = "A man finds himself stranded in the wilderness. He must use his survival skills to hold out until a rescue party arrives. In the process, he learns about himself.";
@@ -350,9 +549,10 @@ mod tests {
         // let text =
         // "Sailors attempt to cross a treacherous sea but must contend with weather and pirates.";
         let text = "Sailors adventure through the seas, finding treasure while escaping bad guys.";
-        match query_sample_text(&pool, text).await {
+        match query_sample_text(&pool, &schema, crate::embedding_models::DEFAULT_MODEL, text).await
+        {
             Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 }
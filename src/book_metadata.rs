@@ -1,9 +1,8 @@
 use oxrdfio::{RdfFormat, RdfParser};
 use serde::{Deserialize, Serialize};
-use serde_yaml; // Add this line for YAML serialization
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -14,24 +13,101 @@ pub struct BookMetadata {
     pub birthyear: String,
     pub deathyear: String,
     pub summary: String,
+    #[serde(default)]
+    pub languages: Vec<String>,
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    #[serde(default)]
+    pub bookshelves: Vec<String>,
+    #[serde(default)]
+    pub contributors: Vec<Contributor>,
+    #[serde(default)]
+    pub formats: Vec<BookFormat>,
+}
+
+/// A Gutenberg agent linked to the book, either as dcterms:creator or with a MARC relator
+/// role such as "ill" (illustrator), "trl" (translator) or "edt" (editor).
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Contributor {
+    pub agent_id: i64,
+    pub name: String,
+    pub birthyear: Option<i32>,
+    pub deathyear: Option<i32>,
+    pub role: String,
+}
+
+/// A downloadable rendition listed in the RDF with dcterms:hasFormat
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookFormat {
+    pub url: String,
+    /// e.g. "text/plain; charset=utf-8", zipped files also list "application/zip"
+    pub media_types: Vec<String>,
+}
+
+impl BookFormat {
+    pub fn has_media_type(&self, prefix: &str) -> bool {
+        self.media_types
+            .iter()
+            .any(|media_type| media_type.starts_with(prefix))
+    }
+}
+
+const RDF_VALUE: &str = "<http://www.w3.org/1999/02/22-rdf-syntax-ns#value>";
+const DCAM_MEMBER_OF: &str = "<http://purl.org/dc/dcam/memberOf>";
+const LCSH: &str = "<http://purl.org/dc/terms/LCSH>";
+const MARC_RELATOR_PREFIX: &str = "<http://id.loc.gov/vocabulary/relators/";
+
+// Language, subject and bookshelf entries are blank nodes in the RDF, e.g.
+// dcterms:subject -> _:n1, _:n1 rdf:value "Science fiction", _:n1 dcam:memberOf dcterms:LCSH,
+// so they can only be resolved once every quad has been seen.
+#[derive(Default)]
+struct NodeValues {
+    values: HashMap<String, String>,
+    member_of: HashMap<String, String>,
+}
+
+impl NodeValues {
+    fn resolve(&self, nodes: &[String]) -> Vec<String> {
+        let mut resolved: Vec<String> = Vec::new();
+        for node in nodes {
+            if let Some(value) = self.values.get(node)
+                && !resolved.contains(value)
+            {
+                resolved.push(value.clone());
+            }
+        }
+        resolved
+    }
+}
+
+fn literal_value(object: &str) -> String {
+    object.split("^^").next().unwrap_or("").replace("\"", "")
+}
+
+// Agent IRIs look like <http://www.gutenberg.org/2009/agents/68>
+fn extract_agent_id(agent: &str) -> Option<i64> {
+    agent
+        .trim_end_matches('>')
+        .rsplit('/')
+        .next()
+        .and_then(|id| id.parse::<i64>().ok())
 }
 
 // Extracting the ID from the filename
 fn extract_id_from_filename(file_path: &str) -> Result<i32, std::num::ParseIntError> {
-    let id = Path::new(file_path)
+    Path::new(file_path)
         .file_stem()
         .and_then(|s| s.to_str())
         .unwrap_or("")
         .strip_prefix("pg")
         .unwrap_or("")
-        .parse::<i32>();
-
-    id
+        .parse::<i32>()
 }
 
+/// Parses one RDF file, also writing it as YAML into `metadata_dir` if given
 pub fn process_rdf(
     file_path: &str,
-    write_flag: Option<bool>,
+    metadata_dir: Option<&Path>,
 ) -> Result<BookMetadata, Box<dyn std::error::Error>> {
     println!("Processing file: {:?}", file_path);
     let mut file = File::open(file_path)?;
@@ -43,37 +119,80 @@ pub fn process_rdf(
         .collect::<Result<Vec<_>, _>>()?;
 
     let book_id = extract_id_from_filename(file_path)?; // Extracts ID from filename
-    let mut book_metadata = BookMetadata::default();
-    book_metadata.id = book_id;
+    let mut book_metadata = BookMetadata {
+        id: book_id,
+        ..Default::default()
+    };
+
+    let mut node_values = NodeValues::default();
+    let mut language_nodes: Vec<String> = Vec::new();
+    let mut subject_nodes: Vec<String> = Vec::new();
+    let mut bookshelf_nodes: Vec<String> = Vec::new();
+    let mut agents: HashMap<String, Contributor> = HashMap::new();
+    let mut agent_roles: Vec<(String, String)> = Vec::new();
+    let mut format_urls: Vec<String> = Vec::new();
+    let mut format_nodes: HashMap<String, Vec<String>> = HashMap::new();
 
     for quad in quads {
-        match quad.predicate.to_string().as_str() {
+        let predicate = quad.predicate.to_string();
+        if predicate == "<http://purl.org/dc/terms/creator>" {
+            agent_roles.push((quad.object.to_string(), "aut".to_string()));
+        } else if let Some(role) = predicate.strip_prefix(MARC_RELATOR_PREFIX) {
+            agent_roles.push((
+                quad.object.to_string(),
+                role.trim_end_matches('>').to_string(),
+            ));
+        }
+
+        match predicate.as_str() {
+            RDF_VALUE => {
+                node_values.values.insert(
+                    quad.subject.to_string(),
+                    literal_value(&quad.object.to_string()),
+                );
+            }
+            DCAM_MEMBER_OF => {
+                node_values
+                    .member_of
+                    .insert(quad.subject.to_string(), quad.object.to_string());
+            }
+            "<http://purl.org/dc/terms/language>" => {
+                language_nodes.push(quad.object.to_string());
+            }
+            "<http://purl.org/dc/terms/subject>" => {
+                subject_nodes.push(quad.object.to_string());
+            }
+            "<http://www.gutenberg.org/2009/pgterms/bookshelf>" => {
+                bookshelf_nodes.push(quad.object.to_string());
+            }
+            "<http://purl.org/dc/terms/hasFormat>" => {
+                format_urls.push(quad.object.to_string());
+            }
+            "<http://purl.org/dc/terms/format>" => {
+                format_nodes
+                    .entry(quad.subject.to_string())
+                    .or_default()
+                    .push(quad.object.to_string());
+            }
             "<http://www.gutenberg.org/2009/pgterms/name>" => {
-                book_metadata.author = quad.object.to_string().replace("\"", "");
+                agents.entry(quad.subject.to_string()).or_default().name =
+                    quad.object.to_string().replace("\"", "");
             }
             "<http://purl.org/dc/terms/title>" => {
                 book_metadata.title = quad.object.to_string().replace("\"", "");
             }
             "<http://www.gutenberg.org/2009/pgterms/birthdate>" => {
-                book_metadata.birthyear = quad
-                    .object
-                    .to_string()
-                    .split("^^")
-                    .next()
-                    .unwrap_or("")
-                    .to_string()
-                    .replace("\"", "");
+                agents
+                    .entry(quad.subject.to_string())
+                    .or_default()
+                    .birthyear = literal_value(&quad.object.to_string()).parse::<i32>().ok();
             }
 
             "<http://www.gutenberg.org/2009/pgterms/deathdate>" => {
-                book_metadata.deathyear = quad
-                    .object
-                    .to_string()
-                    .split("^^")
-                    .next()
-                    .unwrap_or("")
-                    .to_string()
-                    .replace("\"", "");
+                agents
+                    .entry(quad.subject.to_string())
+                    .or_default()
+                    .deathyear = literal_value(&quad.object.to_string()).parse::<i32>().ok();
             }
             "<http://www.gutenberg.org/2009/pgterms/marc520>" => {
                 book_metadata.summary = quad
@@ -94,37 +213,89 @@ pub fn process_rdf(
         // println!("{}", quad.predicate.to_string());
         // println!("{}", quad.object.to_string());
     }
+    // Only keep LCSH headings, LCC entries are classification codes like "PS"
+    subject_nodes.retain(|node| node_values.member_of.get(node).map(|m| m.as_str()) == Some(LCSH));
+    book_metadata.languages = node_values.resolve(&language_nodes);
+    book_metadata.subjects = node_values.resolve(&subject_nodes);
+    book_metadata.bookshelves = node_values.resolve(&bookshelf_nodes);
+    for url in format_urls {
+        let nodes = format_nodes
+            .get(&url)
+            .map(|nodes| nodes.as_slice())
+            .unwrap_or(&[]);
+        book_metadata.formats.push(BookFormat {
+            media_types: node_values.resolve(nodes),
+            url: url
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string(),
+        });
+    }
+    for (agent, role) in agent_roles {
+        let (Some(agent_id), Some(details)) = (extract_agent_id(&agent), agents.get(&agent)) else {
+            continue;
+        };
+        book_metadata.contributors.push(Contributor {
+            agent_id,
+            role,
+            ..details.clone()
+        });
+    }
+    // Illustrators, translators and editors are agents too, the book's author and years
+    // come from its first creator
+    if let Some(author) = book_metadata
+        .contributors
+        .iter()
+        .find(|contributor| contributor.role == "aut")
+    {
+        book_metadata.author = author.name.clone();
+        book_metadata.birthyear = author
+            .birthyear
+            .map(|year| year.to_string())
+            .unwrap_or_default();
+        book_metadata.deathyear = author
+            .deathyear
+            .map(|year| year.to_string())
+            .unwrap_or_default();
+    }
     // println!("{:?}", book_metadata);
     // println!("{}", book_metadata.summary);
 
     // Write book_metadata to file
-    if let Some(true) = write_flag {
-        write_metadata_to_file(&book_metadata)?;
+    if let Some(metadata_dir) = metadata_dir {
+        write_metadata_to_file(&book_metadata, metadata_dir)?;
     }
     Ok(book_metadata)
 }
 
-fn write_metadata_to_file(metadata: &BookMetadata) -> Result<(), Box<dyn std::error::Error>> {
-    let file_path = format!("data/metadata/book_metadata_{}.yaml", metadata.id);
+fn write_metadata_to_file(
+    metadata: &BookMetadata,
+    metadata_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_path = metadata_dir.join(format!("book_metadata_{}.yaml", metadata.id));
     {
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&file_path)?;
 
         serde_yaml::to_writer(file, &metadata)?;
     }
-    println!("Metadata written to {}", file_path);
+    println!("Metadata written to {}", file_path.display());
 
     Ok(())
 }
 
 /// An iterator that processes RDF files one at a time
 pub struct RdfFileIterator {
+    /// Every book directory that will be visited, whether or not its RDF parses
+    book_ids: Vec<u32>,
+    /// RDF files that could not be parsed and were skipped
+    failures: usize,
     dir_queue: VecDeque<PathBuf>,
     current_rdf_files: std::fs::ReadDir,
-    id_range: Option<(u32, u32)>,
-    write_flag: Option<bool>,
+    metadata_dir: Option<PathBuf>,
     finished: bool,
 }
 
@@ -133,30 +304,32 @@ impl RdfFileIterator {
     pub fn new(
         epub_dir: &str,
         id_range: Option<(u32, u32)>,
-        write_flag: Option<bool>,
+        metadata_dir: Option<PathBuf>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let entries = fs::read_dir(epub_dir)?;
         let mut dir_queue = VecDeque::new();
+        let mut book_ids = Vec::new();
 
         for entry in entries {
             let entry = entry?;
             let path = entry.path();
 
-            if path.is_dir() {
-                if let Some(dir_name) = path.file_name().and_then(|s| s.to_str()) {
-                    let book_id = match dir_name.parse::<u32>() {
-                        Ok(id) => id,
-                        Err(_) => continue,
-                    };
-
-                    if let Some((start, end)) = id_range {
-                        if book_id < start || book_id > end {
-                            continue;
-                        }
-                    }
-
-                    dir_queue.push_back(path);
+            if path.is_dir()
+                && let Some(dir_name) = path.file_name().and_then(|s| s.to_str())
+            {
+                let book_id = match dir_name.parse::<u32>() {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+
+                if let Some((start, end)) = id_range
+                    && (book_id < start || book_id > end)
+                {
+                    continue;
                 }
+
+                book_ids.push(book_id);
+                dir_queue.push_back(path);
             }
         }
 
@@ -169,13 +342,22 @@ impl RdfFileIterator {
         };
 
         Ok(RdfFileIterator {
+            book_ids,
+            failures: 0,
             dir_queue,
             current_rdf_files,
-            id_range,
-            write_flag,
+            metadata_dir,
             finished: is_empty,
         })
     }
+
+    pub fn book_ids(&self) -> &[u32] {
+        &self.book_ids
+    }
+
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
 }
 
 impl Iterator for RdfFileIterator {
@@ -198,13 +380,14 @@ impl Iterator for RdfFileIterator {
                             None => continue,
                         };
 
-                        let metadata = match process_rdf(&path_str, self.write_flag) {
+                        let metadata = match process_rdf(&path_str, self.metadata_dir.as_deref()) {
                             Ok(metadata) => metadata,
                             Err(e) => {
                                 println!(
                                     "Failed to process RDF file: {} because of {}",
                                     path_str, e
                                 );
+                                self.failures += 1;
                                 continue;
                             }
                         };
@@ -238,14 +421,14 @@ impl Iterator for RdfFileIterator {
     }
 }
 
-/// Process all RDF files in the epub directory using an iterator
-/// This is a convenience function that collects all results. For streaming processing,
-/// use `RdfFileIterator` directly.
-///
-/// # Arguments
-/// * `epub_dir` - The path to the epub directory
-/// * `id_range` - Optional range of book IDs to process. If None, processes all books.
-///                Format: (start_id, end_id) inclusive
+// Process all RDF files in the epub directory using an iterator
+// This is a convenience function that collects all results. For streaming processing,
+// use `RdfFileIterator` directly.
+//
+// # Arguments
+// * `epub_dir` - The path to the epub directory
+// * `id_range` - Optional range of book IDs to process. If None, processes all books.
+//                Format: (start_id, end_id) inclusive
 // pub fn process_all_rdf_files(
 //     epub_dir: &str,
 //     id_range: Option<(u32, u32)>,
@@ -274,6 +457,15 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_extract_agent_id() {
+        assert_eq!(
+            extract_agent_id("<http://www.gutenberg.org/2009/agents/68>"),
+            Some(68)
+        );
+        assert_eq!(extract_agent_id("_:n1"), None);
+    }
+
     // #[test]
     // fn test_process_rdf_pg1() {
     //     let test_path = "data/cache/epub/1/pg1.rdf";
@@ -304,4 +496,63 @@ mod tests {
 
     //     Ok(())
     // }
+
+    #[test]
+    fn test_rdf_iterator_counts_unparsable_books() {
+        let catalog = std::env::temp_dir().join(format!("rdf_catalog_{}", std::process::id()));
+        fs::create_dir_all(catalog.join("12")).unwrap();
+        fs::create_dir_all(catalog.join("notes")).unwrap();
+        fs::write(catalog.join("12").join("pg12.rdf"), "not rdf").unwrap();
+
+        let mut iterator = RdfFileIterator::new(catalog.to_str().unwrap(), None, None).unwrap();
+        assert_eq!(iterator.book_ids(), &[12]);
+        assert!(iterator.by_ref().all(|metadata| metadata.is_err()));
+        assert_eq!(iterator.failures(), 1);
+        fs::remove_dir_all(&catalog).unwrap();
+    }
+
+    #[test]
+    fn test_process_rdf_takes_author_from_creator() {
+        let rdf = r#"<?xml version="1.0" encoding="utf-8"?>
+<rdf:RDF xml:base="http://www.gutenberg.org/"
+         xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+         xmlns:dcterms="http://purl.org/dc/terms/"
+         xmlns:pgterms="http://www.gutenberg.org/2009/pgterms/"
+         xmlns:marcrel="http://id.loc.gov/vocabulary/relators/">
+  <pgterms:ebook rdf:about="ebooks/1342">
+    <dcterms:title>Pride and Prejudice</dcterms:title>
+    <dcterms:creator>
+      <pgterms:agent rdf:about="2009/agents/68">
+        <pgterms:name>Austen, Jane</pgterms:name>
+        <pgterms:birthdate rdf:datatype="http://www.w3.org/2001/XMLSchema#integer">1775</pgterms:birthdate>
+        <pgterms:deathdate rdf:datatype="http://www.w3.org/2001/XMLSchema#integer">1817</pgterms:deathdate>
+      </pgterms:agent>
+    </dcterms:creator>
+    <marcrel:ill>
+      <pgterms:agent rdf:about="2009/agents/2806">
+        <pgterms:name>Brock, C. E. (Charles Edmund)</pgterms:name>
+        <pgterms:birthdate rdf:datatype="http://www.w3.org/2001/XMLSchema#integer">1870</pgterms:birthdate>
+        <pgterms:deathdate rdf:datatype="http://www.w3.org/2001/XMLSchema#integer">1938</pgterms:deathdate>
+      </pgterms:agent>
+    </marcrel:ill>
+  </pgterms:ebook>
+</rdf:RDF>"#;
+        let dir = std::env::temp_dir().join(format!("rdf_author_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pg1342.rdf");
+        fs::write(&path, rdf).unwrap();
+
+        let metadata = process_rdf(path.to_str().unwrap(), None).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(metadata.author, "Austen, Jane");
+        assert_eq!(metadata.birthyear, "1775");
+        assert_eq!(metadata.deathyear, "1817");
+        assert_eq!(metadata.contributors.len(), 2);
+        assert!(
+            metadata
+                .contributors
+                .iter()
+                .any(|contributor| contributor.role == "ill" && contributor.birthyear == Some(1870))
+        );
+    }
 }
@@ -0,0 +1,435 @@
+use pgvector::Vector;
+use sqlx::postgres::PgPool;
+use std::time::{Duration, Instant};
+
+use crate::book_metadata::BookMetadata;
+use crate::schema::Schema;
+
+// Rows are sent as one array per column and expanded with UNNEST, so a batch of any size
+// is a single statement with a fixed number of parameters. COPY would be slightly faster
+// but cannot express ON CONFLICT, which the loaders rely on to be re-runnable.
+
+#[derive(Debug, Default, Clone)]
+pub struct BulkStats {
+    pub rows: usize,
+    /// Rows that were inserted or actually updated, unchanged rows are skipped by the upsert
+    pub changed: u64,
+    pub batches: usize,
+    pub elapsed: Duration,
+    /// Ids of rows the database refused, written neither alone nor in their batch
+    pub rejected: Vec<i64>,
+}
+
+impl BulkStats {
+    pub fn rows_per_second(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.rows as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn report(&self, what: &str) {
+        println!(
+            "Wrote {} {} ({} new or changed) in {} batches, {:.1}s ({:.0} rows/s)",
+            self.rows,
+            what,
+            self.changed,
+            self.batches,
+            self.elapsed.as_secs_f64(),
+            self.rows_per_second()
+        );
+        if !self.rejected.is_empty() {
+            println!(
+                "Rejected {} {}: {:?}",
+                self.rejected.len(),
+                what,
+                self.rejected
+            );
+        }
+    }
+}
+
+/// Whether a failed write is down to the rows, which a retry of the same rows cannot fix,
+/// rather than the connection
+fn is_row_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::Database(_) | sqlx::Error::Encode(_))
+    )
+}
+
+fn metadata_insert_sql(schema: &Schema) -> String {
+    format!(
+        "
+        INSERT INTO {} AS m (id, title, author, birthyear, deathyear, summary, languages)
+        SELECT id, title, author, birthyear, deathyear, summary,
+        ARRAY(SELECT jsonb_array_elements_text(languages::jsonb))
+        FROM UNNEST($1::bigint[], $2::text[], $3::text[], $4::int[], $5::int[], $6::text[], $7::text[])
+        AS t(id, title, author, birthyear, deathyear, summary, languages)
+        ON CONFLICT (id) DO UPDATE SET
+            title = excluded.title,
+            author = excluded.author,
+            birthyear = excluded.birthyear,
+            deathyear = excluded.deathyear,
+            summary = excluded.summary,
+            languages = excluded.languages,
+            updated_at = now(),
+            deleted_at = NULL
+        WHERE (m.title, m.author, m.birthyear, m.deathyear, m.summary, m.languages)
+            IS DISTINCT FROM
+            (excluded.title, excluded.author, excluded.birthyear, excluded.deathyear,
+            excluded.summary, excluded.languages)
+            OR m.deleted_at IS NOT NULL
+        ",
+        schema.metadata
+    )
+}
+
+// Contributors and subjects of every book in the batch are replaced wholesale, which is
+// simpler than diffing them and cheap next to the metadata upsert.
+fn contributors_delete_sql(schema: &Schema) -> String {
+    format!(
+        "DELETE FROM {} WHERE book_id = ANY($1)",
+        schema.contributors
+    )
+}
+
+fn subjects_delete_sql(schema: &Schema) -> String {
+    format!("DELETE FROM {} WHERE book_id = ANY($1)", schema.subjects)
+}
+
+fn contributors_insert_sql(schema: &Schema) -> String {
+    format!(
+        "
+        INSERT INTO {} (book_id, agent_id, name, birthyear, deathyear, role)
+        SELECT * FROM UNNEST($1::bigint[], $2::bigint[], $3::text[], $4::int[], $5::int[], $6::text[])
+        ON CONFLICT DO NOTHING
+        ",
+        schema.contributors
+    )
+}
+
+fn subjects_insert_sql(schema: &Schema) -> String {
+    format!(
+        "
+        INSERT INTO {} (book_id, scheme, heading)
+        SELECT * FROM UNNEST($1::bigint[], $2::text[], $3::text[])
+        ON CONFLICT DO NOTHING
+        ",
+        schema.subjects
+    )
+}
+
+fn vectors_insert_sql(schema: &Schema) -> String {
+    format!(
+        "
+        INSERT INTO {} (id, embedding, content_hash, model_fingerprint)
+        SELECT * FROM UNNEST($1::bigint[], $2::vector[], $3::text[], $4::text[])
+        ON CONFLICT (id) DO UPDATE SET
+            embedding = excluded.embedding,
+            content_hash = excluded.content_hash,
+            model_fingerprint = excluded.model_fingerprint,
+            updated_at = now()
+        ",
+        schema.vectors
+    )
+}
+
+/// Buffers parsed books and writes them, with their contributors and subjects,
+/// one transaction per batch.
+pub struct MetadataWriter<'a> {
+    pool: &'a PgPool,
+    schema: &'a Schema,
+    batch_size: usize,
+    buffer: Vec<BookMetadata>,
+    stats: BulkStats,
+}
+
+impl<'a> MetadataWriter<'a> {
+    pub fn new(pool: &'a PgPool, schema: &'a Schema, batch_size: usize) -> Self {
+        MetadataWriter {
+            pool,
+            schema,
+            batch_size: batch_size.max(1),
+            buffer: Vec::with_capacity(batch_size),
+            stats: BulkStats::default(),
+        }
+    }
+
+    pub async fn push(&mut self, metadata: BookMetadata) -> Result<(), Box<dyn std::error::Error>> {
+        self.buffer.push(metadata);
+        if self.buffer.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered books in one transaction. If the batch fails on a database error,
+    /// e.g. one row breaking a constraint, its books are retried one at a time and the ones
+    /// that still fail are logged and counted in `BulkStats::rejected` instead of aborting.
+    pub async fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let batch = std::mem::take(&mut self.buffer);
+
+        match self.write_batch(&batch).await {
+            Ok(changed) => {
+                self.stats.rows += batch.len();
+                self.stats.changed += changed;
+            }
+            Err(e) if is_row_error(e.as_ref()) => {
+                println!(
+                    "Batch of {} books failed ({}), retrying them one by one",
+                    batch.len(),
+                    e
+                );
+                for metadata in &batch {
+                    match self.write_batch(std::slice::from_ref(metadata)).await {
+                        Ok(changed) => {
+                            self.stats.rows += 1;
+                            self.stats.changed += changed;
+                        }
+                        Err(e) if is_row_error(e.as_ref()) => {
+                            println!("Rejected book {}: {}", metadata.id, e);
+                            self.stats.rejected.push(metadata.id as i64);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        self.stats.batches += 1;
+        self.stats.elapsed += start.elapsed();
+        Ok(())
+    }
+
+    /// Upserts `batch` with its contributors and subjects, returning the changed row count
+    async fn write_batch(&self, batch: &[BookMetadata]) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut titles = Vec::with_capacity(batch.len());
+        let mut authors = Vec::with_capacity(batch.len());
+        let mut birthyears = Vec::with_capacity(batch.len());
+        let mut deathyears = Vec::with_capacity(batch.len());
+        let mut summaries = Vec::with_capacity(batch.len());
+        // UNNEST cannot expand a jagged text[][], so each row's languages travel as JSON
+        let mut languages = Vec::with_capacity(batch.len());
+
+        let mut contributor_book_ids = Vec::new();
+        let mut agent_ids = Vec::new();
+        let mut names = Vec::new();
+        let mut contributor_birthyears = Vec::new();
+        let mut contributor_deathyears = Vec::new();
+        let mut roles = Vec::new();
+
+        let mut subject_book_ids = Vec::new();
+        let mut schemes = Vec::new();
+        let mut headings = Vec::new();
+
+        for metadata in batch {
+            let book_id = metadata.id as i64;
+            ids.push(book_id);
+            titles.push(metadata.title.as_str());
+            authors.push(metadata.author.as_str());
+            birthyears.push(metadata.birthyear.parse::<i32>().ok());
+            deathyears.push(metadata.deathyear.parse::<i32>().ok());
+            summaries.push(metadata.summary.as_str());
+            languages.push(serde_json::to_string(&metadata.languages)?);
+
+            for contributor in &metadata.contributors {
+                contributor_book_ids.push(book_id);
+                agent_ids.push(contributor.agent_id);
+                names.push(contributor.name.as_str());
+                contributor_birthyears.push(contributor.birthyear);
+                contributor_deathyears.push(contributor.deathyear);
+                roles.push(contributor.role.as_str());
+            }
+
+            let subjects = metadata.subjects.iter().map(|heading| ("LCSH", heading));
+            let bookshelves = metadata
+                .bookshelves
+                .iter()
+                .map(|heading| ("bookshelf", heading));
+            for (scheme, heading) in subjects.chain(bookshelves) {
+                subject_book_ids.push(book_id);
+                schemes.push(scheme);
+                headings.push(heading.as_str());
+            }
+        }
+
+        let mut transaction = self.pool.begin().await?;
+        let result = sqlx::query(metadata_insert_sql(self.schema).as_str())
+            .bind(&ids)
+            .bind(&titles)
+            .bind(&authors)
+            .bind(&birthyears)
+            .bind(&deathyears)
+            .bind(&summaries)
+            .bind(&languages)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(contributors_delete_sql(self.schema).as_str())
+            .bind(&ids)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(subjects_delete_sql(self.schema).as_str())
+            .bind(&ids)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(contributors_insert_sql(self.schema).as_str())
+            .bind(&contributor_book_ids)
+            .bind(&agent_ids)
+            .bind(&names)
+            .bind(&contributor_birthyears)
+            .bind(&contributor_deathyears)
+            .bind(&roles)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(subjects_insert_sql(self.schema).as_str())
+            .bind(&subject_book_ids)
+            .bind(&schemes)
+            .bind(&headings)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Writes whatever is still buffered and reports throughput
+    pub async fn finish(mut self) -> Result<BulkStats, Box<dyn std::error::Error>> {
+        self.flush().await?;
+        self.stats.report("books");
+        Ok(self.stats)
+    }
+}
+
+/// Buffers (book id, embedding) pairs and upserts them one transaction per batch,
+/// recording which text and which model produced each vector.
+pub struct VectorWriter<'a> {
+    pool: &'a PgPool,
+    schema: &'a Schema,
+    batch_size: usize,
+    model_fingerprint: String,
+    ids: Vec<i64>,
+    embeddings: Vec<Vector>,
+    content_hashes: Vec<String>,
+    stats: BulkStats,
+}
+
+impl<'a> VectorWriter<'a> {
+    pub fn new(
+        pool: &'a PgPool,
+        schema: &'a Schema,
+        batch_size: usize,
+        model_fingerprint: &str,
+    ) -> Self {
+        VectorWriter {
+            pool,
+            schema,
+            batch_size: batch_size.max(1),
+            model_fingerprint: model_fingerprint.to_string(),
+            ids: Vec::with_capacity(batch_size),
+            embeddings: Vec::with_capacity(batch_size),
+            content_hashes: Vec::with_capacity(batch_size),
+            stats: BulkStats::default(),
+        }
+    }
+
+    /// `content_hash` is the metadata row's content_hash at the time the text was read
+    pub async fn push(
+        &mut self,
+        id: i64,
+        embedding: Vector,
+        content_hash: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.ids.push(id);
+        self.embeddings.push(embedding);
+        self.content_hashes.push(content_hash);
+        if self.ids.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.ids.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let ids = std::mem::take(&mut self.ids);
+        let embeddings = std::mem::take(&mut self.embeddings);
+        let content_hashes = std::mem::take(&mut self.content_hashes);
+        let fingerprints = vec![self.model_fingerprint.as_str(); ids.len()];
+
+        let mut transaction = self.pool.begin().await?;
+        let result = sqlx::query(vectors_insert_sql(self.schema).as_str())
+            .bind(&ids)
+            .bind(&embeddings)
+            .bind(&content_hashes)
+            .bind(&fingerprints)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        self.stats.rows += ids.len();
+        self.stats.changed += result.rows_affected();
+        self.stats.batches += 1;
+        self.stats.elapsed += start.elapsed();
+        Ok(())
+    }
+
+    /// Writes whatever is still buffered and reports throughput
+    pub async fn finish(mut self) -> Result<BulkStats, Box<dyn std::error::Error>> {
+        self.flush().await?;
+        self.stats.report("embeddings");
+        Ok(self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_sql_uses_schema_tables() {
+        let schema = Schema::in_namespace("test_run").unwrap();
+        assert!(
+            metadata_insert_sql(&schema).contains("INSERT INTO \"test_run\".\"book_metadata\"")
+        );
+        assert!(metadata_insert_sql(&schema).contains("ON CONFLICT (id) DO UPDATE"));
+        assert!(
+            vectors_insert_sql(&schema)
+                .contains("UNNEST($1::bigint[], $2::vector[], $3::text[], $4::text[])")
+        );
+        assert_eq!(
+            subjects_delete_sql(&schema),
+            "DELETE FROM \"test_run\".\"book_subjects\" WHERE book_id = ANY($1)"
+        );
+        assert!(subjects_insert_sql(&schema).contains("\"test_run\".\"book_subjects\""));
+        assert!(contributors_insert_sql(&schema).contains("\"test_run\".\"book_contributors\""));
+    }
+
+    #[test]
+    fn test_rows_per_second() {
+        let stats = BulkStats {
+            rows: 500,
+            changed: 20,
+            batches: 5,
+            elapsed: Duration::from_secs(2),
+            rejected: Vec::new(),
+        };
+        assert_eq!(stats.rows_per_second(), 250.0);
+        assert_eq!(BulkStats::default().rows_per_second(), 0.0);
+    }
+
+    #[test]
+    fn test_is_row_error() {
+        let encode: Box<dyn std::error::Error> = Box::new(sqlx::Error::Encode("bad value".into()));
+        assert!(is_row_error(encode.as_ref()));
+        let timeout: Box<dyn std::error::Error> = Box::new(sqlx::Error::PoolTimedOut);
+        assert!(!is_row_error(timeout.as_ref()));
+        assert!(!is_row_error(&std::io::Error::other("disk full")));
+    }
+}
@@ -0,0 +1,458 @@
+use ort::session::Session;
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+use sqlx::{QueryBuilder, Row};
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+use crate::models::query_model;
+use crate::schema::Schema;
+use crate::search::{SearchRequest, SearchResult, configure_scan, push_filters};
+
+// Full texts are split into overlapping passages that are embedded one row each in
+// `schema.chunks`. Searching ranks passages, then folds them back into one hit per book.
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkOptions {
+    /// Upper bound on passage length in characters, ~4 characters per token for English
+    pub max_chars: usize,
+    /// Characters repeated from the end of the previous passage, so a scene split at a
+    /// boundary still appears whole in one of them. Capped at half of `max_chars`.
+    pub overlap_chars: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        ChunkOptions {
+            max_chars: 1200,
+            overlap_chars: 200,
+        }
+    }
+}
+
+/// A passage of a text. Offsets count characters, not bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub index: i32,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+// Latest position in start..end to cut at, preferring a paragraph break, then the end
+// of a sentence, then any whitespace. The cut falls after the matched character.
+fn break_point(chars: &[char], start: usize, end: usize) -> Option<usize> {
+    let paragraph = (start + 1..end)
+        .rev()
+        .find(|&i| chars[i] == '\n' && chars[i - 1] == '\n');
+    let sentence = || {
+        (start..end)
+            .rev()
+            .find(|&i| matches!(chars[i], '.' | '!' | '?') && chars[i + 1].is_whitespace())
+    };
+    let word = || (start..end).rev().find(|&i| chars[i].is_whitespace());
+    paragraph.or_else(sentence).or_else(word).map(|i| i + 1)
+}
+
+fn skip_whitespace(chars: &[char], mut position: usize) -> usize {
+    while position < chars.len() && chars[position].is_whitespace() {
+        position += 1;
+    }
+    position
+}
+
+/// Splits `text` into passages of at most `max_chars` characters, cut at natural
+/// boundaries where possible. Leading and trailing whitespace is left out of each passage.
+pub fn chunk_text(text: &str, options: &ChunkOptions) -> Vec<TextChunk> {
+    let chars: Vec<char> = text.chars().collect();
+    let max_chars = options.max_chars.max(1);
+    let overlap = options.overlap_chars.min(max_chars / 2);
+    let mut chunks = Vec::new();
+    let mut start = skip_whitespace(&chars, 0);
+
+    while start < chars.len() {
+        let mut end = (start + max_chars).min(chars.len());
+        if end < chars.len() {
+            // only cut early if that keeps at least half a passage
+            end = break_point(&chars, start + max_chars / 2, end).unwrap_or(end);
+        }
+        let mut text_end = end;
+        while text_end > start && chars[text_end - 1].is_whitespace() {
+            text_end -= 1;
+        }
+        chunks.push(TextChunk {
+            index: chunks.len() as i32,
+            start,
+            end: text_end,
+            text: chars[start..text_end].iter().collect(),
+        });
+        if end >= chars.len() {
+            break;
+        }
+
+        // step back for the overlap, then forward to the start of a word
+        let mut next = end.saturating_sub(overlap).max(start + 1);
+        while next < end && !chars[next - 1].is_whitespace() {
+            next += 1;
+        }
+        start = skip_whitespace(&chars, next);
+    }
+    chunks
+}
+
+fn source_hash(text: &str) -> String {
+    format!("sha256:{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Chunks and embeds a book's full text and replaces its stored passages.
+/// Returns the number of passages written, 0 if the text and model are unchanged.
+#[allow(clippy::too_many_arguments)]
+pub async fn index_book_text(
+    pool: &PgPool,
+    schema: &Schema,
+    session: &mut Session,
+    tokenizer: &Tokenizer,
+    model_fingerprint: &str,
+    book_id: i64,
+    text: &str,
+    options: &ChunkOptions,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let hash = source_hash(text);
+    let existing_string = format!(
+        "SELECT 1 FROM {} WHERE book_id = $1 AND source_hash = $2 AND model_fingerprint = $3 LIMIT 1",
+        schema.chunks
+    );
+    let existing = sqlx::query(existing_string.as_str())
+        .bind(book_id)
+        .bind(&hash)
+        .bind(model_fingerprint)
+        .fetch_optional(pool)
+        .await?;
+    if existing.is_some() {
+        return Ok(0);
+    }
+
+    let chunks = chunk_text(text, options);
+    let mut embeddings = Vec::with_capacity(chunks.len());
+    // a few passages per inference call, padding to the longest keeps batches small
+    for batch in chunks.chunks(8) {
+        let texts = batch.iter().map(|chunk| chunk.text.as_str()).collect();
+        for (_, embedding) in query_model(session, tokenizer, texts)? {
+            embeddings.push(Vector::from(embedding));
+        }
+    }
+    if embeddings.len() != chunks.len() {
+        return Err(format!(
+            "model returned {} embeddings for {} passages of book {}",
+            embeddings.len(),
+            chunks.len(),
+            book_id
+        )
+        .into());
+    }
+
+    let indexes: Vec<i32> = chunks.iter().map(|chunk| chunk.index).collect();
+    let starts: Vec<i32> = chunks.iter().map(|chunk| chunk.start as i32).collect();
+    let ends: Vec<i32> = chunks.iter().map(|chunk| chunk.end as i32).collect();
+    let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+
+    let mut transaction = pool.begin().await?;
+    let delete_string = format!("DELETE FROM {} WHERE book_id = $1", schema.chunks);
+    sqlx::query(delete_string.as_str())
+        .bind(book_id)
+        .execute(&mut *transaction)
+        .await?;
+    let insert_string = format!(
+        "
+        INSERT INTO {} (book_id, chunk_index, start_offset, end_offset, text, embedding,
+            source_hash, model_fingerprint)
+        SELECT $1, *, $7, $8
+        FROM UNNEST($2::int[], $3::int[], $4::int[], $5::text[], $6::vector[])
+        ",
+        schema.chunks
+    );
+    sqlx::query(insert_string.as_str())
+        .bind(book_id)
+        .bind(&indexes)
+        .bind(&starts)
+        .bind(&ends)
+        .bind(&texts)
+        .bind(&embeddings)
+        .bind(&hash)
+        .bind(model_fingerprint)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(chunks.len())
+}
+
+/// How a book's matching passages combine into one score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkAggregation {
+    /// The single best passage decides, good for "the scene where ..." queries
+    Max,
+    /// Mean distance of the best n passages, rewards books that match throughout.
+    /// Books with fewer matching passages are averaged over the ones they have.
+    TopNMean(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkSearchOptions {
+    pub aggregation: ChunkAggregation,
+    /// Passages retrieved per requested book before grouping
+    pub candidates_per_book: i64,
+}
+
+impl Default for ChunkSearchOptions {
+    fn default() -> Self {
+        ChunkSearchOptions {
+            aggregation: ChunkAggregation::Max,
+            candidates_per_book: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkHit {
+    pub book_id: i64,
+    pub chunk_index: i32,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub text: String,
+    pub distance: f64,
+}
+
+#[derive(Debug, Clone)]
+struct BookHits {
+    book_id: i64,
+    distance: f64,
+    best: ChunkHit,
+    matched_chunks: usize,
+}
+
+fn aggregate_hits(hits: Vec<ChunkHit>, aggregation: ChunkAggregation) -> Vec<BookHits> {
+    let mut by_book: HashMap<i64, Vec<ChunkHit>> = HashMap::new();
+    for hit in hits {
+        by_book.entry(hit.book_id).or_default().push(hit);
+    }
+
+    let mut books: Vec<BookHits> = by_book
+        .into_iter()
+        .map(|(book_id, mut hits)| {
+            hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+            let distance = match aggregation {
+                ChunkAggregation::Max => hits[0].distance,
+                ChunkAggregation::TopNMean(n) => {
+                    let top = &hits[..n.clamp(1, hits.len())];
+                    top.iter().map(|hit| hit.distance).sum::<f64>() / top.len() as f64
+                }
+            };
+            BookHits {
+                book_id,
+                distance,
+                matched_chunks: hits.len(),
+                best: hits.swap_remove(0),
+            }
+        })
+        .collect();
+    books.sort_by(|a, b| {
+        a.distance
+            .total_cmp(&b.distance)
+            .then(a.book_id.cmp(&b.book_id))
+    });
+    books
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkSearchResult {
+    /// `distance` is the aggregated passage distance
+    #[serde(flatten)]
+    pub book: SearchResult,
+    /// The best-matching passage
+    pub snippet: String,
+    pub chunk_index: i32,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    /// Retrieved passages of this book, a rough measure of how much of it matches
+    pub matched_chunks: usize,
+}
+
+/// Searches passages and returns the `request.k` best books, each with its best passage.
+/// Metadata filters apply to the passages' books before ranking.
+pub async fn search_chunks(
+    pool: &PgPool,
+    schema: &Schema,
+    embedding: &Vector,
+    request: &SearchRequest,
+    options: &ChunkSearchOptions,
+) -> Result<Vec<ChunkSearchResult>, Box<dyn std::error::Error>> {
+    let mut builder = QueryBuilder::new(
+        "
+        WITH hits AS MATERIALIZED (
+            SELECT c.book_id, c.chunk_index, c.start_offset, c.end_offset, c.text,
+            c.embedding <=> ",
+    );
+    builder.push_bind(embedding.clone());
+    builder.push(format!(
+        " AS distance
+            FROM {} c
+            JOIN {} m ON m.id = c.book_id",
+        schema.chunks, schema.metadata
+    ));
+    push_filters(&mut builder, schema, &request.filters);
+    // passages of the same book crowd each other out, so fetch several per book wanted
+    let limit = request.k * options.candidates_per_book.max(1);
+    builder.push(" ORDER BY c.embedding <=> $1 LIMIT ");
+    builder.push_bind(limit);
+    builder.push(
+        "
+        )
+        SELECT * FROM hits ORDER BY distance",
+    );
+
+    let mut transaction = pool.begin().await?;
+    configure_scan(&mut transaction, request, limit).await?;
+    let rows = builder.build().fetch_all(&mut *transaction).await?;
+    transaction.commit().await?;
+
+    let mut hits = Vec::with_capacity(rows.len());
+    for row in rows {
+        hits.push(ChunkHit {
+            book_id: row.try_get("book_id")?,
+            chunk_index: row.try_get("chunk_index")?,
+            start_offset: row.try_get("start_offset")?,
+            end_offset: row.try_get("end_offset")?,
+            text: row.try_get("text")?,
+            distance: row.try_get("distance")?,
+        });
+    }
+    let mut books = aggregate_hits(hits, options.aggregation);
+    books.truncate(request.k.max(0) as usize);
+
+    let ids: Vec<i64> = books.iter().map(|book| book.book_id).collect();
+    let metadata_string = format!(
+        "
+        SELECT id, title, author, birthyear, deathyear, summary, 0::float8 AS distance
+        FROM {} WHERE id = ANY($1)
+        ",
+        schema.metadata
+    );
+    let rows = sqlx::query(metadata_string.as_str())
+        .bind(&ids)
+        .fetch_all(pool)
+        .await?;
+    let mut metadata = HashMap::new();
+    for row in rows {
+        let result = SearchResult::from_row(&row)?;
+        metadata.insert(result.id, result);
+    }
+
+    Ok(books
+        .into_iter()
+        .filter_map(|book| {
+            let mut result = metadata.remove(&book.book_id)?;
+            result.distance = book.distance;
+            Some(ChunkSearchResult {
+                book: result,
+                snippet: book.best.text,
+                chunk_index: book.best.chunk_index,
+                start_offset: book.best.start_offset,
+                end_offset: book.best.end_offset,
+                matched_chunks: book.matched_chunks,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice_chars(text: &str, start: usize, end: usize) -> String {
+        text.chars().skip(start).take(end - start).collect()
+    }
+
+    #[test]
+    fn test_chunk_text_short_text_is_one_chunk() {
+        let chunks = chunk_text("  Call me Ishmael.  ", &ChunkOptions::default());
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "Call me Ishmael.");
+        assert_eq!((chunks[0].start, chunks[0].end), (2, 18));
+        assert!(chunk_text(" \n ", &ChunkOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_text_cuts_at_boundaries_with_overlap() {
+        let text = "The whale surfaced. The crew watched in silence.\n\nAhab spoke first. \
+                    Nobody answered him. The sea was calm that morning, and the wind had gone.";
+        let options = ChunkOptions {
+            max_chars: 60,
+            overlap_chars: 20,
+        };
+        let chunks = chunk_text(text, &options);
+
+        assert!(chunks.len() > 1);
+        assert_eq!(
+            chunks[0].text,
+            "The whale surfaced. The crew watched in silence."
+        );
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.index, i as i32);
+            assert!(chunk.text.chars().count() <= 60);
+            assert_eq!(slice_chars(text, chunk.start, chunk.end), chunk.text);
+        }
+        for pair in chunks.windows(2) {
+            assert!(pair[1].start < pair[0].end, "chunks should overlap");
+            assert!(pair[1].start > pair[0].start);
+        }
+        assert_eq!(chunks.last().unwrap().end, text.chars().count());
+    }
+
+    #[test]
+    fn test_chunk_text_offsets_count_characters() {
+        let text = "Éléonore rêvait. ".repeat(10);
+        let options = ChunkOptions {
+            max_chars: 40,
+            overlap_chars: 0,
+        };
+        for chunk in chunk_text(&text, &options) {
+            assert_eq!(slice_chars(&text, chunk.start, chunk.end), chunk.text);
+        }
+    }
+
+    fn hit(book_id: i64, chunk_index: i32, distance: f64) -> ChunkHit {
+        ChunkHit {
+            book_id,
+            chunk_index,
+            start_offset: 0,
+            end_offset: 0,
+            text: format!("book {} passage {}", book_id, chunk_index),
+            distance,
+        }
+    }
+
+    #[test]
+    fn test_aggregate_hits() {
+        let hits = vec![
+            hit(1, 0, 0.10),
+            hit(2, 0, 0.15),
+            hit(2, 1, 0.16),
+            hit(2, 2, 0.17),
+            hit(1, 5, 0.50),
+        ];
+
+        let by_max = aggregate_hits(hits.clone(), ChunkAggregation::Max);
+        assert_eq!(by_max[0].book_id, 1);
+        assert_eq!(by_max[0].best.chunk_index, 0);
+        assert_eq!(by_max[0].matched_chunks, 2);
+
+        let by_mean = aggregate_hits(hits, ChunkAggregation::TopNMean(2));
+        assert_eq!(by_mean[0].book_id, 2);
+        assert!((by_mean[0].distance - 0.155).abs() < 1e-9);
+        assert_eq!(by_mean[0].best.chunk_index, 0);
+        assert!((by_mean[1].distance - 0.30).abs() < 1e-9);
+    }
+}
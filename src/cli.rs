@@ -0,0 +1,1214 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pgvector::Vector;
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::ann_benchmark::{self, BenchmarkOptions, ExactMethod};
+use crate::batch;
+use crate::book_db_handler;
+use crate::chunks::{ChunkAggregation, ChunkSearchOptions, ChunkSearchResult, search_chunks};
+use crate::config::Config;
+use crate::diversify::{MmrOptions, search_diverse};
+use crate::embedding_models::{self, schema_for_model};
+use crate::eval::{self, Comparison, EvalReport, ReportFormat};
+use crate::jobs;
+use crate::migrations;
+use crate::models::{
+    get_model_info, intra_threads, model_fingerprint, query_model, ready_model, ready_tokenizer,
+};
+use crate::output::{OutputFormat, PassageRow, ResultRow, print_rows};
+use crate::recommend::{
+    ProfileStrategy, RatedBook, ReadingHistory, recommend_from_history, recommend_in_store,
+};
+use crate::repl;
+use crate::schema::Schema;
+use crate::search::{
+    SearchFilters, SearchRequest, SearchResult, search_text, similar_in_store, similar_to,
+    year_range,
+};
+use crate::server;
+use crate::synthetic_judgments::{self, SyntheticOptions};
+use crate::vector_index::{self, IndexKind};
+use crate::vector_store::{self, MemoryStore, VectorStore};
+
+/// Semantic search and recommendations over the Project Gutenberg catalog
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// TOML settings file, see book-recommender.example.toml. Defaults to
+    /// $BOOK_RECOMMENDER_CONFIG, then ./book-recommender.toml if it exists
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Registered embedding model whose vectors are read and written
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+
+    /// Postgres schema holding the tables, migrated on startup. Defaults to the search path
+    #[arg(long, global = true)]
+    pub namespace: Option<String>,
+
+    /// Run search, similar and recommend against this `store export` file, without Postgres
+    #[arg(long, global = true)]
+    pub store_file: Option<PathBuf>,
+
+    /// ONNX Runtime threads per model session. Defaults to the cores divided by the sessions
+    #[arg(long, global = true)]
+    pub intra_threads: Option<usize>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Load the RDF catalog into the metadata tables
+    IngestMetadata {
+        #[command(flatten)]
+        catalog: CatalogArgs,
+        /// Books per transaction
+        #[arg(long)]
+        batch_size: Option<usize>,
+        /// Also write each book's metadata as YAML into this directory
+        #[arg(long)]
+        metadata_dir: Option<PathBuf>,
+    },
+    /// Embed every book whose summary has no up-to-date vector, as a new indexing job
+    Embed {
+        #[arg(long)]
+        batch_size: Option<usize>,
+        /// Embedding threads, each with its own model session. Defaults to one per core
+        #[arg(long)]
+        workers: Option<usize>,
+    },
+    /// Continue an interrupted or failed indexing job from its checkpoint
+    Resume { job_id: i64 },
+    /// Re-embed the books that failed in an indexing job
+    Retry { job_id: i64 },
+    /// Recent indexing jobs
+    Jobs {
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Books whose summary matches a description
+    Search {
+        text: String,
+        #[command(flatten)]
+        query: QueryArgs,
+        /// Re-rank a larger candidate set so results are not all by one author or series
+        #[arg(long, conflicts_with = "passages")]
+        diverse: bool,
+        /// With --diverse, 1.0 ranks purely by similarity to the text, 0.0 purely by novelty.
+        /// Defaults to 0.7
+        #[arg(long, requires = "diverse")]
+        lambda: Option<f64>,
+        /// With --diverse, at most this many books per author
+        #[arg(long, requires = "diverse")]
+        max_per_author: Option<usize>,
+        /// Search full-text passages instead of summaries, see `full-text`
+        #[arg(long)]
+        passages: bool,
+        /// Rank passage results by the mean of each book's best n passages
+        /// instead of its single best one
+        #[arg(long, requires = "passages")]
+        top_passages: Option<usize>,
+    },
+    /// Books similar to one that is already embedded
+    Similar {
+        id: i64,
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+    /// Unread books for a reading history
+    Recommend {
+        /// Comma separated ids of books the reader liked
+        #[arg(long, value_delimiter = ',', required = true)]
+        liked: Vec<i64>,
+        /// Comma separated ids of books the reader disliked
+        #[arg(long, value_delimiter = ',')]
+        disliked: Vec<i64>,
+        /// Search with the max similarity to each liked book instead of one averaged profile
+        #[arg(long)]
+        per_book: bool,
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+    /// Show the model's registry entry, fingerprint and ONNX inputs and outputs
+    ModelInfo,
+    /// Manage the embedding model registry
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Manage the ANN index on the model's summary vectors
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+    /// Row counts for the catalog and the model's vectors
+    Stats {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Applied and pending schema migrations
+    Migrations,
+    /// Chunk and embed the full text of every mirrored book
+    FullText {
+        #[command(flatten)]
+        catalog: CatalogArgs,
+        /// Local copy of the book files, laid out like the catalog
+        #[arg(long)]
+        mirror_dir: Option<PathBuf>,
+        /// Longest passage in characters
+        #[arg(long)]
+        max_chars: Option<usize>,
+        /// Characters repeated from the end of the previous passage
+        #[arg(long)]
+        overlap_chars: Option<usize>,
+    },
+    /// Run every query in a JSONL or CSV file and write each one's results to a file
+    Batch {
+        /// Objects or rows with id, text and optionally k and filters, see src/batch.rs
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Jsonl)]
+        format: OutputFormat,
+        /// Results per query, unless the query sets its own
+        #[arg(short, long)]
+        k: Option<i64>,
+        /// Queries embedded per model call
+        #[arg(long)]
+        batch_size: Option<usize>,
+    },
+    /// Measure retrieval quality against relevance judgments
+    Eval {
+        #[command(subcommand)]
+        command: EvalCommand,
+    },
+    /// Interactive searching with the model loaded once, see :help inside
+    Repl {
+        /// Number of results to start with
+        #[arg(short, long)]
+        k: Option<i64>,
+        #[command(flatten)]
+        filters: FilterArgs,
+        /// File keeping entered lines across sessions. Defaults to ~/.book_recommender_history
+        #[arg(long)]
+        history: Option<PathBuf>,
+    },
+    /// Serve the JSON API for search and recommendations over HTTP
+    Serve {
+        /// ip:port to listen on
+        #[arg(long)]
+        address: Option<String>,
+        /// Model sessions, also the most inferences running at once
+        #[arg(long)]
+        sessions: Option<usize>,
+        /// Seconds before a request is answered with 504
+        #[arg(long)]
+        timeout_secs: Option<u64>,
+    },
+    /// Vector store files for running without the database
+    Store {
+        #[command(subcommand)]
+        command: StoreCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StoreCommand {
+    /// Write the model's vectors with the metadata their filters need to a file
+    Export {
+        /// Defaults to the configured store file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Model and book count of the configured store file
+    Info,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ModelsCommand {
+    /// Registered models
+    List,
+    /// Register a model and create its vector table
+    Register {
+        name: String,
+        #[arg(long)]
+        dimensions: i32,
+        #[arg(long)]
+        model_path: String,
+        #[arg(long)]
+        tokenizer_path: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EvalCommand {
+    /// Run every judged query and score its results, see src/eval.rs for the file layout
+    Run {
+        judgments: PathBuf,
+        /// Ranks scored per query
+        #[arg(short, long)]
+        k: Option<i64>,
+        /// Name of the run in reports. Defaults to the model name
+        #[arg(long)]
+        label: Option<String>,
+        /// Save the report here, as JSON for `eval compare`, instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Defaults to JSON with --output and markdown without
+        #[arg(long, value_enum)]
+        format: Option<ReportFormat>,
+        /// Queries embedded per model call
+        #[arg(long)]
+        batch_size: Option<usize>,
+    },
+    /// Metrics of two saved runs side by side, with the queries that changed
+    Compare {
+        baseline: PathBuf,
+        candidate: PathBuf,
+        /// Write the comparison here instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Markdown)]
+        format: ReportFormat,
+    },
+    /// Judgments derived from shared authors, series, subjects and bookshelves
+    Generate {
+        /// JSONL judgments file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Seed books to sample, stratified by subject
+        #[arg(long, default_value_t = 1000)]
+        queries: usize,
+        /// Skip seeds with fewer relevant books
+        #[arg(long, default_value_t = 3)]
+        min_relevant: usize,
+        /// Ignore subjects and bookshelves with more books than this
+        #[arg(long, default_value_t = 500)]
+        max_group_size: usize,
+        /// Sample a different set of seed books
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum IndexMethod {
+    Hnsw,
+    Ivfflat,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IndexCommand {
+    /// Create the index, or drop and recreate it with --rebuild
+    Build {
+        #[arg(long, value_enum, default_value_t = IndexMethod::Hnsw)]
+        method: IndexMethod,
+        /// HNSW graph degree
+        #[arg(long, default_value_t = 16)]
+        m: i32,
+        /// HNSW candidate list size while building
+        #[arg(long, default_value_t = 64)]
+        ef_construction: i32,
+        /// IVFFlat clusters, about rows / 1000
+        #[arg(long, default_value_t = 100)]
+        lists: i32,
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// Every index on the model's summary vectors with its size
+    Report,
+    /// Recall and latency of the only ANN index against exact search, over a sweep of
+    /// ef_search for HNSW and probes for IVFFlat
+    Benchmark {
+        /// Ranks compared per query
+        #[arg(short, long)]
+        k: Option<i64>,
+        /// Stored vectors sampled as queries
+        #[arg(long, default_value_t = 200)]
+        queries: usize,
+        #[arg(long, value_delimiter = ',', default_values_t = [10, 20, 40, 80, 160, 320])]
+        ef_search: Vec<i32>,
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 2, 4, 8, 16, 32])]
+        probes: Vec<i32>,
+        /// How the true nearest neighbors are found
+        #[arg(long, value_enum, default_value_t = ExactMethod::Rust)]
+        exact: ExactMethod,
+        /// Sample a different set of queries
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct CatalogArgs {
+    /// Directory with one sub-directory per book id, as in the Gutenberg RDF export
+    #[arg(long)]
+    pub catalog_dir: Option<PathBuf>,
+    /// Only books with ids in this inclusive range, e.g. 1-1000
+    #[arg(long, value_parser = parse_id_range)]
+    pub id_range: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    /// Number of results
+    #[arg(short, long)]
+    pub k: Option<i64>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+    #[command(flatten)]
+    pub filters: FilterArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Case-insensitive substring of the author name
+    #[arg(long)]
+    pub author: Option<String>,
+    /// Only authors born in or after this year
+    #[arg(long)]
+    pub born_after: Option<i32>,
+    /// Only authors born in or before this year
+    #[arg(long)]
+    pub born_before: Option<i32>,
+    /// Only authors who died in or after this year
+    #[arg(long)]
+    pub died_after: Option<i32>,
+    /// Only authors who died in or before this year
+    #[arg(long)]
+    pub died_before: Option<i32>,
+    /// Language code, e.g. en. Repeat for any of several
+    #[arg(long = "lang")]
+    pub languages: Vec<String>,
+    /// Substring of an LCSH subject heading. Repeat for any of several
+    #[arg(long = "subject")]
+    pub subjects: Vec<String>,
+    /// Substring of a Gutenberg bookshelf. Repeat for any of several
+    #[arg(long = "bookshelf")]
+    pub bookshelves: Vec<String>,
+    /// Book ids to leave out, comma separated
+    #[arg(long = "exclude", value_delimiter = ',')]
+    pub exclude_ids: Vec<i64>,
+}
+
+impl FilterArgs {
+    pub fn to_filters(&self) -> SearchFilters {
+        SearchFilters {
+            author: self.author.clone(),
+            birthyear_range: year_range(self.born_after, self.born_before),
+            deathyear_range: year_range(self.died_after, self.died_before),
+            languages: self.languages.clone(),
+            subjects: self.subjects.clone(),
+            bookshelves: self.bookshelves.clone(),
+            exclude_ids: self.exclude_ids.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parses "start-end" into an inclusive id range
+pub fn parse_id_range(s: &str) -> Result<(u32, u32), String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected an id range like 1-1000, got {:?}", s))?;
+    let start: u32 = start.trim().parse().map_err(|e| format!("{}", e))?;
+    let end: u32 = end.trim().parse().map_err(|e| format!("{}", e))?;
+    if start > end {
+        return Err(format!("id range {} is empty", s));
+    }
+    Ok((start, end))
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn print_results(
+    results: &[SearchResult],
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    print_rows(&ResultRow::from_results(results), format)
+}
+
+fn print_passage_results(
+    results: &[ChunkSearchResult],
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    print_rows(&PassageRow::from_results(results), format)
+}
+
+fn search_request(config: &Config, filters: SearchFilters) -> SearchRequest {
+    SearchRequest {
+        k: config.search.k,
+        filters,
+        ef_search: config.search.ef_search,
+        probes: config.search.probes,
+    }
+}
+
+async fn search(
+    pool: &PgPool,
+    schema: &Schema,
+    config: &Config,
+    text: &str,
+    query: &QueryArgs,
+    diverse: Option<MmrOptions>,
+    passages: Option<ChunkAggregation>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (schema, model) = schema_for_model(pool, schema, &config.embedding.model).await?;
+    let mut session = ready_model(
+        &model.model_path,
+        intra_threads(config.embedding.intra_threads, 1),
+    )?;
+    let tokenizer = ready_tokenizer(&model.tokenizer_path)?;
+    let request = search_request(config, query.filters.to_filters());
+    if diverse.is_none() && passages.is_none() {
+        let results = search_text(pool, &schema, &mut session, &tokenizer, text, &request).await?;
+        return print_results(&results, query.format);
+    }
+
+    let embedding = Vector::from(
+        query_model(&mut session, &tokenizer, vec![text])?
+            .into_iter()
+            .next()
+            .ok_or("model returned no embedding")?
+            .1,
+    );
+    if let Some(aggregation) = passages {
+        let options = ChunkSearchOptions {
+            aggregation,
+            ..Default::default()
+        };
+        let results = search_chunks(pool, &schema, &embedding, &request, &options).await?;
+        return print_passage_results(&results, query.format);
+    }
+    let options = diverse.unwrap_or_default();
+    let results = search_diverse(pool, &schema, &embedding, &request, &options).await?;
+    print_results(&results, query.format)
+}
+
+async fn build_index(
+    pool: &PgPool,
+    schema: &Schema,
+    kind: IndexKind,
+    rebuild: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let report = if rebuild {
+        vector_index::rebuild_index(pool, &schema.vectors, &kind).await?
+    } else {
+        vector_index::create_index(pool, &schema.vectors, &kind).await?
+    };
+    println!(
+        "{} {} bytes: {}",
+        report.name, report.size_bytes, report.definition
+    );
+    Ok(())
+}
+
+/// Search, similar and recommend against the configured store file, see `Cli::runs_in_memory`
+pub async fn run_in_memory(cli: Cli, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let path = config
+        .store
+        .file
+        .as_deref()
+        .ok_or("no store file, pass --store-file or set store.file")?;
+    let start = Instant::now();
+    let store = MemoryStore::load(path)?.with_hnsw(config.store.hnsw);
+    let model = store
+        .model()
+        .ok_or_else(|| format!("{} does not name its model", path.display()))?;
+    if model.name != config.embedding.model {
+        return Err(format!(
+            "{} holds {} vectors, not {}, run with --model {}",
+            path.display(),
+            model.name,
+            config.embedding.model,
+            model.name
+        )
+        .into());
+    }
+    match cli.command {
+        Command::Search {
+            text,
+            query,
+            diverse,
+            passages,
+            ..
+        } => {
+            if diverse || passages {
+                return Err("--diverse and --passages need the database, unset store.file".into());
+            }
+            let mut session = ready_model(
+                &model.model_path,
+                intra_threads(config.embedding.intra_threads, 1),
+            )?;
+            let tokenizer = ready_tokenizer(&model.tokenizer_path)?;
+            let embedding = query_model(&mut session, &tokenizer, vec![text.as_str()])?
+                .into_iter()
+                .next()
+                .ok_or("model returned no embedding")?
+                .1;
+            let request = search_request(config, query.filters.to_filters());
+            let results = store.search(&embedding, &request).await?;
+            print_results(&results, query.format)?;
+        }
+        Command::Similar { id, query } => {
+            let request = search_request(config, query.filters.to_filters());
+            let results = similar_in_store(&store, id, &request).await?;
+            print_results(&results, query.format)?;
+        }
+        Command::Recommend {
+            liked,
+            disliked,
+            per_book,
+            query,
+        } => {
+            let history = ReadingHistory {
+                liked: liked.into_iter().map(RatedBook::new).collect(),
+                disliked: disliked.into_iter().map(RatedBook::new).collect(),
+            };
+            let strategy = if per_book {
+                ProfileStrategy::MaxSimilarity
+            } else {
+                ProfileStrategy::default()
+            };
+            let request = search_request(config, query.filters.to_filters());
+            let results = recommend_in_store(&store, &history, &request, strategy).await?;
+            print_results(&results, query.format)?;
+        }
+        Command::Store {
+            command: StoreCommand::Info,
+        } => {
+            println!(
+                "{}: {} books, {} ({} dimensions), loaded in {:.1}s",
+                path.display(),
+                store.len().await?,
+                model.name,
+                store.dimensions(),
+                start.elapsed().as_secs_f64()
+            );
+        }
+        _ => return Err("this command needs the database, unset store.file".into()),
+    }
+    Ok(())
+}
+
+impl Cli {
+    /// Config file, then environment, then these flags, validated
+    pub fn resolve_config(&self) -> Result<Config, Box<dyn std::error::Error>> {
+        let path = self
+            .config
+            .clone()
+            .or_else(|| env::var_os("BOOK_RECOMMENDER_CONFIG").map(PathBuf::from));
+        let mut config = Config::load(path.as_deref())?;
+        config.apply_env(|name| env::var(name).ok())?;
+        self.apply_flags(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Whether the command reads the store file instead of the database
+    pub fn runs_in_memory(&self, config: &Config) -> bool {
+        match &self.command {
+            Command::Store {
+                command: StoreCommand::Info,
+            } => true,
+            Command::Search { .. } | Command::Similar { .. } | Command::Recommend { .. } => {
+                config.store.file.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn apply_flags(&self, config: &mut Config) {
+        if let Some(model) = &self.model {
+            config.embedding.model = model.clone();
+        }
+        if let Some(namespace) = &self.namespace {
+            config.database.namespace = Some(namespace.clone());
+        }
+        if self.store_file.is_some() {
+            config.store.file = self.store_file.clone();
+        }
+        if self.intra_threads.is_some() {
+            config.embedding.intra_threads = self.intra_threads;
+        }
+
+        let catalog = match &self.command {
+            Command::IngestMetadata {
+                catalog,
+                batch_size,
+                metadata_dir,
+            } => {
+                set_if_some(&mut config.catalog.batch_size, *batch_size);
+                if metadata_dir.is_some() {
+                    config.catalog.metadata_dir = metadata_dir.clone();
+                }
+                Some(catalog)
+            }
+            Command::Embed {
+                batch_size,
+                workers,
+            } => {
+                set_if_some(&mut config.embedding.batch_size, *batch_size);
+                if workers.is_some() {
+                    config.embedding.workers = *workers;
+                }
+                None
+            }
+            Command::Search { query, .. }
+            | Command::Similar { query, .. }
+            | Command::Recommend { query, .. } => {
+                set_if_some(&mut config.search.k, query.k);
+                None
+            }
+            Command::Batch { k, batch_size, .. }
+            | Command::Eval {
+                command: EvalCommand::Run { k, batch_size, .. },
+            } => {
+                set_if_some(&mut config.search.k, *k);
+                set_if_some(&mut config.embedding.query_batch_size, *batch_size);
+                None
+            }
+            Command::Repl { k, .. }
+            | Command::Index {
+                command: IndexCommand::Benchmark { k, .. },
+            } => {
+                set_if_some(&mut config.search.k, *k);
+                None
+            }
+            Command::FullText {
+                catalog,
+                mirror_dir,
+                max_chars,
+                overlap_chars,
+            } => {
+                if mirror_dir.is_some() {
+                    config.catalog.mirror_dir = mirror_dir.clone();
+                }
+                set_if_some(&mut config.chunks.max_chars, *max_chars);
+                set_if_some(&mut config.chunks.overlap_chars, *overlap_chars);
+                Some(catalog)
+            }
+            Command::Serve {
+                address,
+                sessions,
+                timeout_secs,
+            } => {
+                if let Some(address) = address {
+                    config.server.address = address.clone();
+                }
+                set_if_some(&mut config.server.sessions, *sessions);
+                set_if_some(&mut config.server.request_timeout_secs, *timeout_secs);
+                None
+            }
+            _ => None,
+        };
+        if let Some(catalog_dir) = catalog.and_then(|catalog| catalog.catalog_dir.clone()) {
+            config.catalog.catalog_dir = catalog_dir;
+        }
+    }
+}
+
+fn set_if_some<T>(setting: &mut T, flag: Option<T>) {
+    if let Some(value) = flag {
+        *setting = value;
+    }
+}
+
+fn path_str(path: &Path) -> Result<&str, String> {
+    path.to_str()
+        .ok_or_else(|| format!("path {} is not valid UTF-8", path.display()))
+}
+
+/// Migrates the configured tables and runs the command against them
+pub async fn run(
+    cli: Cli,
+    config: &Config,
+    pool: &PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match &config.database.namespace {
+        Some(namespace) => migrations::run_migrations_in_namespace(pool, namespace).await?,
+        None => migrations::run_migrations(pool).await?,
+    }
+    let schema = &config.schema()?;
+    let model_name = config.embedding.model.as_str();
+    let batch_size = config.embedding.batch_size;
+    let k = config.search.k;
+    match cli.command {
+        Command::IngestMetadata { catalog, .. } => {
+            book_db_handler::set_up_metadata_table(
+                pool,
+                schema,
+                path_str(&config.catalog.catalog_dir)?,
+                catalog.id_range,
+                config.catalog.metadata_dir.as_deref(),
+                config.catalog.batch_size,
+            )
+            .await?;
+        }
+        Command::Embed { .. } => {
+            let intra_threads = config.embedding.intra_threads;
+            match config.embedding.workers {
+                Some(workers) => {
+                    book_db_handler::set_up_vector_table(
+                        pool,
+                        schema,
+                        model_name,
+                        batch_size,
+                        workers,
+                        intra_threads,
+                    )
+                    .await?;
+                }
+                None => {
+                    book_db_handler::set_up_vector_table_par(
+                        pool,
+                        schema,
+                        model_name,
+                        batch_size,
+                        intra_threads,
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::Resume { job_id } => {
+            book_db_handler::resume_indexing_job(pool, schema, job_id).await?;
+        }
+        Command::Retry { job_id } => {
+            book_db_handler::retry_failed_embeddings(pool, schema, job_id).await?;
+        }
+        Command::Jobs { limit } => {
+            for job in jobs::list_jobs(pool, schema, limit).await? {
+                jobs::print_job(&job);
+            }
+        }
+        Command::Search {
+            text,
+            query,
+            diverse,
+            lambda,
+            max_per_author,
+            passages,
+            top_passages,
+        } => {
+            let aggregation = match top_passages {
+                Some(n) => ChunkAggregation::TopNMean(n),
+                None => ChunkAggregation::Max,
+            };
+            let passages = passages.then_some(aggregation);
+            let diverse = match diverse {
+                true => Some(MmrOptions::with_overrides(lambda, max_per_author)?),
+                false => None,
+            };
+            search(pool, schema, config, &text, &query, diverse, passages).await?;
+        }
+        Command::Similar { id, query } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            let request = search_request(config, query.filters.to_filters());
+            let results = similar_to(pool, &schema, id, &request).await?;
+            print_results(&results, query.format)?;
+        }
+        Command::Recommend {
+            liked,
+            disliked,
+            per_book,
+            query,
+        } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            let history = ReadingHistory {
+                liked: liked.into_iter().map(RatedBook::new).collect(),
+                disliked: disliked.into_iter().map(RatedBook::new).collect(),
+            };
+            let strategy = if per_book {
+                ProfileStrategy::MaxSimilarity
+            } else {
+                ProfileStrategy::default()
+            };
+            let request = search_request(config, query.filters.to_filters());
+            let results =
+                recommend_from_history(pool, &schema, &history, &request, strategy).await?;
+            print_results(&results, query.format)?;
+        }
+        Command::ModelInfo => {
+            let model = embedding_models::load_model(pool, schema, model_name).await?;
+            println!(
+                "{} ({} dimensions)\n  model {}\n  tokenizer {}\n  fingerprint {}",
+                model.name,
+                model.dimensions,
+                model.model_path,
+                model.tokenizer_path,
+                model_fingerprint(&model.model_path)?
+            );
+            get_model_info(&model.model_path)?;
+        }
+        Command::Models {
+            command: ModelsCommand::List,
+        } => {
+            for model in embedding_models::list_models(pool, schema).await? {
+                println!(
+                    "{:<16} {:>5} dims  {}",
+                    model.name, model.dimensions, model.model_path
+                );
+            }
+        }
+        Command::Models {
+            command:
+                ModelsCommand::Register {
+                    name,
+                    dimensions,
+                    model_path,
+                    tokenizer_path,
+                },
+        } => {
+            let model = embedding_models::register_model(
+                pool,
+                schema,
+                &name,
+                dimensions,
+                &model_path,
+                &tokenizer_path,
+            )
+            .await?;
+            println!("Registered model {} with id {}", model.name, model.id);
+        }
+        Command::Index {
+            command:
+                IndexCommand::Build {
+                    method,
+                    m,
+                    ef_construction,
+                    lists,
+                    rebuild,
+                },
+        } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            let kind = match method {
+                IndexMethod::Hnsw => IndexKind::Hnsw { m, ef_construction },
+                IndexMethod::Ivfflat => IndexKind::IvfFlat { lists },
+            };
+            build_index(pool, &schema, kind, rebuild).await?;
+        }
+        Command::Index {
+            command: IndexCommand::Report,
+        } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            for report in vector_index::index_report(pool, &schema.vectors).await? {
+                println!(
+                    "{} {} bytes: {}",
+                    report.name, report.size_bytes, report.definition
+                );
+            }
+        }
+        Command::Stats { format } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            let stats = book_db_handler::catalog_stats(pool, &schema).await?;
+            match format {
+                OutputFormat::Json => print_json(&stats)?,
+                OutputFormat::Jsonl | OutputFormat::Csv | OutputFormat::Markdown => {
+                    print_rows(&[stats], format)?
+                }
+                OutputFormat::Table => {
+                    println!("books               {}", stats.books);
+                    println!("deleted books       {}", stats.deleted_books);
+                    println!("books with summary  {}", stats.books_with_summary);
+                    println!("embedded books      {}", stats.embedded_books);
+                    println!(
+                        "passages            {} ({} books)",
+                        stats.passages, stats.books_with_passages
+                    );
+                }
+            }
+        }
+        Command::Migrations => migrations::print_migration_status(pool).await?,
+        Command::FullText { catalog, .. } => {
+            book_db_handler::index_full_texts(
+                pool,
+                schema,
+                model_name,
+                path_str(&config.catalog.catalog_dir)?,
+                config.catalog.mirror_dir(),
+                catalog.id_range,
+                &config.chunks,
+                config.embedding.intra_threads,
+            )
+            .await?;
+        }
+        Command::Index {
+            command:
+                IndexCommand::Benchmark {
+                    queries,
+                    ef_search,
+                    probes,
+                    exact,
+                    seed,
+                    format,
+                    ..
+                },
+        } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            let options = BenchmarkOptions {
+                queries,
+                k: k as usize,
+                ef_search,
+                probes,
+                exact,
+                seed,
+            };
+            let rows = ann_benchmark::run_benchmark(pool, &schema, &options).await?;
+            print_rows(&rows, format)?;
+        }
+        Command::Batch {
+            input,
+            output,
+            format,
+            ..
+        } => {
+            let (schema, model) = schema_for_model(pool, schema, model_name).await?;
+            let defaults = search_request(config, SearchFilters::default());
+            batch::run_batch(
+                pool,
+                &schema,
+                &model,
+                &input,
+                &output,
+                format,
+                &defaults,
+                &config.embedding,
+            )
+            .await?;
+        }
+        Command::Eval {
+            command:
+                EvalCommand::Run {
+                    judgments,
+                    label,
+                    output,
+                    format,
+                    ..
+                },
+        } => {
+            let (schema, model) = schema_for_model(pool, schema, model_name).await?;
+            let (judged, judgments_sha256) = eval::read_judgments(&judgments)?;
+            let request = search_request(config, SearchFilters::default());
+            let retrieved =
+                eval::run_evaluation(pool, &schema, &model, &judged, &request, &config.embedding)
+                    .await?;
+            let label = label.unwrap_or_else(|| model.name.clone());
+            let report = EvalReport::new(
+                &label,
+                &model.name,
+                k as usize,
+                &judgments,
+                &judgments_sha256,
+                &judged,
+                retrieved,
+            );
+            match output {
+                Some(output) => {
+                    let format = format.unwrap_or(ReportFormat::Json);
+                    let rendered = eval::render(&report, EvalReport::to_markdown, format)?;
+                    fs::write(&output, rendered)?;
+                    print!("{}", report.to_markdown());
+                    println!("Report written to {}", output.display());
+                }
+                None => {
+                    let format = format.unwrap_or(ReportFormat::Markdown);
+                    let rendered = eval::render(&report, EvalReport::to_markdown, format)?;
+                    print!("{}", rendered);
+                }
+            }
+        }
+        Command::Eval {
+            command:
+                EvalCommand::Compare {
+                    baseline,
+                    candidate,
+                    output,
+                    format,
+                },
+        } => {
+            let comparison = Comparison::new(
+                &EvalReport::load(&baseline)?,
+                &EvalReport::load(&candidate)?,
+            )?;
+            let rendered = eval::render(&comparison, Comparison::to_markdown, format)?;
+            match output {
+                Some(output) => fs::write(output, rendered)?,
+                None => print!("{}", rendered),
+            }
+        }
+        Command::Eval {
+            command:
+                EvalCommand::Generate {
+                    output,
+                    queries,
+                    min_relevant,
+                    max_group_size,
+                    seed,
+                },
+        } => {
+            let (schema, _) = schema_for_model(pool, schema, model_name).await?;
+            let books = synthetic_judgments::load_catalog(pool, &schema).await?;
+            let options = SyntheticOptions {
+                queries,
+                min_relevant,
+                max_group_size,
+                seed,
+            };
+            let judgments = synthetic_judgments::generate_judgments(&books, &options);
+            synthetic_judgments::write_judgments(&output, &judgments)?;
+            let pairs: usize = judgments.iter().map(|j| j.relevant.len()).sum();
+            println!(
+                "Wrote {} judgments with {} relevant books from {} embedded books to {}",
+                judgments.len(),
+                pairs,
+                books.len(),
+                output.display()
+            );
+        }
+        Command::Repl {
+            filters, history, ..
+        } => {
+            let history = history.or_else(|| {
+                env::var_os("HOME").map(|home| Path::new(&home).join(".book_recommender_history"))
+            });
+            repl::run_repl(
+                pool,
+                schema,
+                config,
+                filters.to_filters(),
+                history.as_deref(),
+            )
+            .await?;
+        }
+        Command::Serve { .. } => server::serve(pool, schema, config).await?,
+        Command::Store {
+            command: StoreCommand::Export { output },
+        } => {
+            let output = output
+                .or_else(|| config.store.file.clone())
+                .ok_or("no output file, pass --output or set store.file")?;
+            let (schema, model) = schema_for_model(pool, schema, model_name).await?;
+            let store = vector_store::export_store(pool, &schema, model).await?;
+            store.save(&output)?;
+            println!(
+                "Wrote {} books with {} dimensions to {}",
+                store.len().await?,
+                store.dimensions(),
+                output.display()
+            );
+        }
+        Command::Store {
+            command: StoreCommand::Info,
+        } => return Err("store info reads the store file, set --store-file".into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from([
+            "book-recommender",
+            "search",
+            "sailors and pirates",
+            "-k",
+            "5",
+            "--lang",
+            "en",
+            "--format",
+            "json",
+        ]);
+        assert_eq!(cli.model, None);
+        let Command::Search { text, query, .. } = cli.command else {
+            panic!("expected the search command");
+        };
+        assert_eq!(text, "sailors and pirates");
+        assert_eq!(query.k, Some(5));
+        assert_eq!(query.format, OutputFormat::Json);
+        assert_eq!(query.filters.to_filters().languages, vec!["en"]);
+
+        let cli = Cli::parse_from(["book-recommender", "recommend", "--liked", "1342,158"]);
+        let Command::Recommend { liked, .. } = cli.command else {
+            panic!("expected the recommend command");
+        };
+        assert_eq!(liked, vec![1342, 158]);
+    }
+
+    #[test]
+    fn test_flags_override_config() {
+        let mut config = Config::default();
+        config.embedding.workers = Some(2);
+        let cli = Cli::parse_from([
+            "book-recommender",
+            "--model",
+            "minilm_l6",
+            "embed",
+            "--batch-size",
+            "10",
+        ]);
+        cli.apply_flags(&mut config);
+        assert_eq!(config.embedding.model, "minilm_l6");
+        assert_eq!(config.embedding.batch_size, 10);
+        // not given on the command line, so the configured value stays
+        assert_eq!(config.embedding.workers, Some(2));
+
+        let cli = Cli::parse_from([
+            "book-recommender",
+            "full-text",
+            "--catalog-dir",
+            "/srv/rdf",
+            "--max-chars",
+            "600",
+        ]);
+        cli.apply_flags(&mut config);
+        assert_eq!(config.catalog.catalog_dir, PathBuf::from("/srv/rdf"));
+        assert_eq!(config.catalog.mirror_dir(), Path::new("/srv/rdf"));
+        assert_eq!(config.chunks.max_chars, 600);
+
+        let cli = Cli::parse_from(["book-recommender", "similar", "1342"]);
+        assert!(!cli.runs_in_memory(&config));
+        let cli = Cli::parse_from([
+            "book-recommender",
+            "similar",
+            "1342",
+            "--store-file",
+            "b.store",
+            "--intra-threads",
+            "2",
+        ]);
+        cli.apply_flags(&mut config);
+        assert_eq!(config.store.file, Some(PathBuf::from("b.store")));
+        assert_eq!(config.embedding.intra_threads, Some(2));
+        assert!(cli.runs_in_memory(&config));
+        let cli = Cli::parse_from(["book-recommender", "store", "export"]);
+        assert!(!cli.runs_in_memory(&config));
+    }
+
+    #[test]
+    fn test_parse_id_range() {
+        assert_eq!(parse_id_range("1-1000"), Ok((1, 1000)));
+        assert!(parse_id_range("1000-1").is_err());
+        assert!(parse_id_range("1000").is_err());
+        assert!(parse_id_range("a-b").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::chunks::ChunkOptions;
+use crate::embedding_models::DEFAULT_MODEL;
+use crate::schema::Schema;
+
+// Settings are resolved in layers, each overriding the one before:
+//
+//   built-in defaults -> TOML file -> environment variables -> command line flags
+//
+// then validated once at startup. See book-recommender.example.toml for every key.
+
+/// Used when neither --config nor BOOK_RECOMMENDER_CONFIG is given and the file exists
+pub const DEFAULT_CONFIG_FILE: &str = "book-recommender.toml";
+
+const ENV_PREFIX: &str = "BOOK_RECOMMENDER_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Usually left out of the file and taken from DATABASE_URL
+    pub url: Option<String>,
+    pub max_connections: u32,
+    /// Postgres schema holding the tables, migrated on startup. None uses the search path
+    pub namespace: Option<String>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            max_connections: 5,
+            namespace: None,
+        }
+    }
+}
+
+/// Table names, see `Schema`. Migrations create the default names, so other names must
+/// refer to tables created some other way. Model vector tables come from the registry.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TablesConfig {
+    pub metadata: String,
+    pub vectors: String,
+    pub contributors: String,
+    pub subjects: String,
+    pub chunks: String,
+    pub jobs: String,
+    pub job_failures: String,
+    pub models: String,
+    pub model_tables: String,
+}
+
+impl Default for TablesConfig {
+    fn default() -> Self {
+        let schema = Schema::default();
+        TablesConfig {
+            metadata: schema.metadata.name().to_string(),
+            vectors: schema.vectors.name().to_string(),
+            contributors: schema.contributors.name().to_string(),
+            subjects: schema.subjects.name().to_string(),
+            chunks: schema.chunks.name().to_string(),
+            jobs: schema.jobs.name().to_string(),
+            job_failures: schema.job_failures.name().to_string(),
+            models: schema.models.name().to_string(),
+            model_tables: schema.model_tables.name().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CatalogConfig {
+    /// The RDF export, one directory per book id
+    pub catalog_dir: PathBuf,
+    /// Local copy of the book files for full-text indexing, laid out like the catalog.
+    /// Defaults to `catalog_dir`.
+    pub mirror_dir: Option<PathBuf>,
+    /// Where ingest writes a YAML copy of each book's metadata. None writes nothing.
+    pub metadata_dir: Option<PathBuf>,
+    /// Books per metadata transaction
+    pub batch_size: usize,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        CatalogConfig {
+            catalog_dir: PathBuf::from("data/cache/epub"),
+            mirror_dir: None,
+            metadata_dir: None,
+            batch_size: 1000,
+        }
+    }
+}
+
+impl CatalogConfig {
+    pub fn mirror_dir(&self) -> &Path {
+        self.mirror_dir.as_deref().unwrap_or(&self.catalog_dir)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    /// Registered model name, model and tokenizer paths are kept in the registry
+    pub model: String,
+    /// Vectors per write transaction
+    pub batch_size: usize,
+    /// Embedding threads. None uses one per core
+    pub workers: Option<usize>,
+    /// ONNX Runtime threads per model session. None splits the cores between the sessions
+    pub intra_threads: Option<usize>,
+    /// Queries embedded per model call by `batch` and `eval run`
+    pub query_batch_size: usize,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        EmbeddingConfig {
+            model: DEFAULT_MODEL.to_string(),
+            batch_size: 100,
+            workers: None,
+            intra_threads: None,
+            query_batch_size: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    pub k: i64,
+    /// hnsw.ef_search, pgvector's default when None
+    pub ef_search: Option<i32>,
+    /// ivfflat.probes, pgvector's default when None
+    pub probes: Option<i32>,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            k: 10,
+            ef_search: None,
+            probes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the HTTP API listens on
+    pub address: String,
+    /// Model sessions shared by all requests, which is also the most inferences run at once
+    pub sessions: usize,
+    /// Requests still running after this long, including waiting for a session, get a 504
+    pub request_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "127.0.0.1:8080".to_string(),
+            sessions: 2,
+            request_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// Vector store file written by `store export`. When set, search, similar and recommend
+    /// read it instead of the database and no database url is needed for them
+    pub file: Option<PathBuf>,
+    /// Search the file through an HNSW graph built on load instead of scanning every vector
+    pub hnsw: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub tables: TablesConfig,
+    pub catalog: CatalogConfig,
+    pub embedding: EmbeddingConfig,
+    pub search: SearchConfig,
+    pub chunks: ChunkOptions,
+    pub server: ServerConfig,
+    pub store: StoreConfig,
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("{}{}={:?}: {}", ENV_PREFIX, name, value, e))
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Reads `path`, or DEFAULT_CONFIG_FILE if it exists, or starts from the defaults
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Path::new(DEFAULT_CONFIG_FILE),
+            None => return Ok(Config::default()),
+        };
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+        Ok(Config::from_toml(&text)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?)
+    }
+
+    /// Applies DATABASE_URL and the BOOK_RECOMMENDER_* variables. `var` looks a variable
+    /// up, e.g. `|name| std::env::var(name).ok()`.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        if let Some(url) = var("DATABASE_URL") {
+            self.database.url = Some(url);
+        }
+        let prefixed = |name: &str| var(&format!("{}{}", ENV_PREFIX, name));
+
+        if let Some(value) = prefixed("MAX_CONNECTIONS") {
+            self.database.max_connections = parse_env("MAX_CONNECTIONS", &value)?;
+        }
+        if let Some(value) = prefixed("NAMESPACE") {
+            self.database.namespace = Some(value);
+        }
+        if let Some(value) = prefixed("CATALOG_DIR") {
+            self.catalog.catalog_dir = PathBuf::from(value);
+        }
+        if let Some(value) = prefixed("MIRROR_DIR") {
+            self.catalog.mirror_dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = prefixed("METADATA_DIR") {
+            self.catalog.metadata_dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = prefixed("MODEL") {
+            self.embedding.model = value;
+        }
+        if let Some(value) = prefixed("BATCH_SIZE") {
+            self.embedding.batch_size = parse_env("BATCH_SIZE", &value)?;
+        }
+        if let Some(value) = prefixed("WORKERS") {
+            self.embedding.workers = Some(parse_env("WORKERS", &value)?);
+        }
+        if let Some(value) = prefixed("INTRA_THREADS") {
+            self.embedding.intra_threads = Some(parse_env("INTRA_THREADS", &value)?);
+        }
+        if let Some(value) = prefixed("QUERY_BATCH_SIZE") {
+            self.embedding.query_batch_size = parse_env("QUERY_BATCH_SIZE", &value)?;
+        }
+        if let Some(value) = prefixed("SERVER_ADDRESS") {
+            self.server.address = value;
+        }
+        if let Some(value) = prefixed("STORE_FILE") {
+            self.store.file = Some(PathBuf::from(value));
+        }
+        Ok(())
+    }
+
+    /// The configured tables, inside `database.namespace` if set
+    pub fn schema(&self) -> Result<Schema, String> {
+        let defaults = match &self.database.namespace {
+            Some(namespace) => Schema::in_namespace(namespace)?,
+            None => Schema::default(),
+        };
+        let tables = &self.tables;
+        Ok(Schema {
+            metadata: defaults.sibling(&tables.metadata)?,
+            vectors: defaults.sibling(&tables.vectors)?,
+            contributors: defaults.sibling(&tables.contributors)?,
+            subjects: defaults.sibling(&tables.subjects)?,
+            chunks: defaults.sibling(&tables.chunks)?,
+            jobs: defaults.sibling(&tables.jobs)?,
+            job_failures: defaults.sibling(&tables.job_failures)?,
+            models: defaults.sibling(&tables.models)?,
+            model_tables: defaults.sibling(&tables.model_tables)?,
+        })
+    }
+
+    /// Checks every setting and reports all problems at once
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.database.url.as_deref().is_none_or(str::is_empty) && self.store.file.is_none() {
+            problems.push("no database url, set DATABASE_URL".to_string());
+        }
+        if self.database.max_connections == 0 {
+            problems.push("database.max_connections must be at least 1".to_string());
+        }
+        if let Err(e) = self.schema() {
+            problems.push(format!("tables: {}", e));
+        }
+        if self.catalog.batch_size == 0 {
+            problems.push("catalog.batch_size must be at least 1".to_string());
+        }
+        if self.embedding.model.is_empty() {
+            problems.push("embedding.model is empty".to_string());
+        }
+        if self.embedding.batch_size == 0 {
+            problems.push("embedding.batch_size must be at least 1".to_string());
+        }
+        if self.embedding.workers == Some(0) {
+            problems.push("embedding.workers must be at least 1".to_string());
+        }
+        if self.embedding.intra_threads == Some(0) {
+            problems.push("embedding.intra_threads must be at least 1".to_string());
+        }
+        if self.embedding.query_batch_size == 0 {
+            problems.push("embedding.query_batch_size must be at least 1".to_string());
+        }
+        if self.search.k < 1 {
+            problems.push("search.k must be at least 1".to_string());
+        }
+        if self.search.ef_search.is_some_and(|ef_search| ef_search < 1) {
+            problems.push("search.ef_search must be at least 1".to_string());
+        }
+        if self.search.probes.is_some_and(|probes| probes < 1) {
+            problems.push("search.probes must be at least 1".to_string());
+        }
+        if self.chunks.max_chars == 0 {
+            problems.push("chunks.max_chars must be at least 1".to_string());
+        }
+        if self.server.address.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "server.address {:?} is not an ip:port address",
+                self.server.address
+            ));
+        }
+        if self.server.sessions == 0 {
+            problems.push("server.sessions must be at least 1".to_string());
+        }
+        if self.server.request_timeout_secs == 0 {
+            problems.push("server.request_timeout_secs must be at least 1".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("invalid configuration: {}", problems.join("; ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_config_layers() {
+        let mut config = Config::from_toml(
+            r#"
+            [database]
+            max_connections = 8
+            namespace = "staging"
+
+            [embedding]
+            model = "minilm_l6"
+            batch_size = 50
+
+            [chunks]
+            max_chars = 800
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.database.max_connections, 8);
+        assert_eq!(config.embedding.batch_size, 50);
+        // keys missing from the file keep their defaults
+        assert_eq!(config.chunks.overlap_chars, 200);
+        assert_eq!(config.catalog.catalog_dir, PathBuf::from("data/cache/epub"));
+        assert_eq!(config.catalog.mirror_dir(), Path::new("data/cache/epub"));
+
+        let env = HashMap::from([
+            ("DATABASE_URL", "postgres://localhost/books"),
+            ("BOOK_RECOMMENDER_BATCH_SIZE", "25"),
+            ("BOOK_RECOMMENDER_QUERY_BATCH_SIZE", "8"),
+            ("BOOK_RECOMMENDER_MIRROR_DIR", "/srv/gutenberg"),
+        ]);
+        config
+            .apply_env(|name| env.get(name).map(|value| value.to_string()))
+            .unwrap();
+        assert_eq!(config.embedding.batch_size, 25);
+        assert_eq!(config.embedding.query_batch_size, 8);
+        assert_eq!(config.embedding.model, "minilm_l6");
+        assert_eq!(config.catalog.mirror_dir(), Path::new("/srv/gutenberg"));
+        config.validate().unwrap();
+
+        let schema = config.schema().unwrap();
+        assert_eq!(schema.metadata.to_string(), "\"staging\".\"book_metadata\"");
+
+        let bad = HashMap::from([("BOOK_RECOMMENDER_WORKERS", "many")]);
+        assert!(
+            config
+                .apply_env(|name| bad.get(name).map(|value| value.to_string()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_example_config() {
+        let text = fs::read_to_string("book-recommender.example.toml").unwrap();
+        assert_eq!(Config::from_toml(&text).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert!(Config::from_toml("[embedding]\nmodle = \"qwen3\"").is_err());
+
+        let mut config = Config::default();
+        config.database.url = Some("postgres://localhost/books".to_string());
+        config.validate().unwrap();
+
+        config.embedding.batch_size = 0;
+        config.search.k = 0;
+        config.tables.metadata = "book metadata".to_string();
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("embedding.batch_size"));
+        assert!(error.contains("search.k"));
+        assert!(error.contains("tables:"));
+
+        // a store file is enough for the commands that can run without the database
+        let mut config = Config::default();
+        assert!(config.validate().unwrap_err().contains("DATABASE_URL"));
+        config.store.file = Some(PathBuf::from("books.store"));
+        config.validate().unwrap();
+    }
+}
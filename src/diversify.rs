@@ -0,0 +1,207 @@
+use pgvector::Vector;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+
+use crate::schema::Schema;
+use crate::search::{SearchRequest, SearchResult, fetch_embeddings, search_by_vector};
+
+#[derive(Debug, Clone)]
+pub struct MmrOptions {
+    /// 1.0 ranks purely by query similarity, 0.0 purely by novelty
+    pub lambda: f64,
+    /// Number of nearest neighbours retrieved before re-ranking
+    pub candidates: i64,
+    /// At most this many results per author, e.g. 1 for one book per author
+    pub max_per_author: Option<usize>,
+}
+
+impl Default for MmrOptions {
+    fn default() -> Self {
+        MmrOptions {
+            lambda: 0.7,
+            candidates: 50,
+            max_per_author: None,
+        }
+    }
+}
+
+impl MmrOptions {
+    /// The defaults with the given lambda and per-author cap
+    pub fn with_overrides(
+        lambda: Option<f64>,
+        max_per_author: Option<usize>,
+    ) -> Result<MmrOptions, String> {
+        let defaults = MmrOptions::default();
+        let lambda = lambda.unwrap_or(defaults.lambda);
+        if !(0.0..=1.0).contains(&lambda) {
+            return Err(format!("lambda must be between 0 and 1, got {}", lambda));
+        }
+        if max_per_author == Some(0) {
+            return Err("max_per_author must be at least 1".to_string());
+        }
+        Ok(MmrOptions {
+            lambda,
+            max_per_author,
+            ..defaults
+        })
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    (dot / (norm_a * norm_b)) as f64
+}
+
+/// Maximal marginal relevance: greedily picks the candidate maximizing
+/// lambda * sim(query, c) - (1 - lambda) * max sim(c, selected).
+/// Query similarity is taken from the candidate's search distance.
+pub fn mmr_rerank(
+    candidates: Vec<(SearchResult, Vec<f32>)>,
+    k: usize,
+    options: &MmrOptions,
+) -> Vec<SearchResult> {
+    let mut remaining = candidates;
+    let mut selected: Vec<(SearchResult, Vec<f32>)> = Vec::new();
+    let mut author_counts: HashMap<String, usize> = HashMap::new();
+
+    while selected.len() < k {
+        if let Some(max_per_author) = options.max_per_author {
+            remaining.retain(|(result, _)| {
+                author_counts.get(&result.author).copied().unwrap_or(0) < max_per_author
+            });
+        }
+
+        let best = remaining
+            .iter()
+            .enumerate()
+            .map(|(i, (result, embedding))| {
+                let relevance = 1.0 - result.distance;
+                let redundancy = selected
+                    .iter()
+                    .map(|(_, chosen)| cosine_similarity(embedding, chosen))
+                    .fold(0.0, f64::max);
+                let score = options.lambda * relevance - (1.0 - options.lambda) * redundancy;
+                (i, score)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        match best {
+            Some((i, _)) => {
+                let chosen = remaining.swap_remove(i);
+                *author_counts.entry(chosen.0.author.clone()).or_insert(0) += 1;
+                selected.push(chosen);
+            }
+            None => break,
+        }
+    }
+
+    selected.into_iter().map(|(result, _)| result).collect()
+}
+
+/// Retrieves `options.candidates` neighbours and re-ranks them down to `request.k`
+/// so series volumes and prolific authors do not crowd out everything else.
+pub async fn search_diverse(
+    pool: &PgPool,
+    schema: &Schema,
+    embedding: &Vector,
+    request: &SearchRequest,
+    options: &MmrOptions,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let candidate_request = SearchRequest {
+        k: options.candidates.max(request.k),
+        ..request.clone()
+    };
+    let results = search_by_vector(pool, schema, embedding, &candidate_request).await?;
+
+    let ids: Vec<i64> = results.iter().map(|result| result.id).collect();
+    let mut embeddings = fetch_embeddings(pool, schema, &ids).await?;
+    let candidates = results
+        .into_iter()
+        .filter_map(|result| {
+            embeddings
+                .remove(&result.id)
+                .map(|embedding| (result, embedding))
+        })
+        .collect();
+
+    Ok(mmr_rerank(candidates, request.k as usize, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(
+        id: i64,
+        author: &str,
+        distance: f64,
+        embedding: Vec<f32>,
+    ) -> (SearchResult, Vec<f32>) {
+        let result = SearchResult {
+            id,
+            title: format!("Book {}", id),
+            author: author.to_string(),
+            birthyear: None,
+            deathyear: None,
+            summary: None,
+            distance,
+        };
+        (result, embedding)
+    }
+
+    fn ids(results: &[SearchResult]) -> Vec<i64> {
+        results.iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn test_mmr_rerank_prefers_novel_candidates() {
+        let candidates = vec![
+            candidate(1, "A", 0.10, vec![1.0, 0.0]),
+            candidate(2, "A", 0.11, vec![1.0, 0.01]),
+            candidate(3, "B", 0.20, vec![0.0, 1.0]),
+        ];
+
+        let relevance_only = MmrOptions {
+            lambda: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&mmr_rerank(candidates.clone(), 2, &relevance_only)),
+            vec![1, 2]
+        );
+
+        let balanced = MmrOptions {
+            lambda: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(ids(&mmr_rerank(candidates, 2, &balanced)), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_mmr_rerank_caps_results_per_author() {
+        let candidates = vec![
+            candidate(1, "A", 0.10, vec![1.0, 0.0]),
+            candidate(2, "A", 0.11, vec![0.0, 1.0]),
+            candidate(3, "A", 0.12, vec![1.0, 1.0]),
+            candidate(4, "B", 0.30, vec![1.0, 0.0]),
+        ];
+        let options = MmrOptions::with_overrides(Some(1.0), Some(2)).unwrap();
+        assert_eq!(options.candidates, MmrOptions::default().candidates);
+
+        assert_eq!(ids(&mmr_rerank(candidates, 4, &options)), vec![1, 2, 4]);
+        assert!(MmrOptions::with_overrides(Some(1.5), None).is_err());
+        assert!(MmrOptions::with_overrides(None, Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-9);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-9);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
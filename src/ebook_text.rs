@@ -0,0 +1,649 @@
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::book_metadata::{BookFormat, BookMetadata};
+use crate::gutenberg_text::{
+    decode_text, is_contents_heading, is_end_marker, is_start_marker, mirror_path,
+    normalize_paragraph,
+};
+
+// Some books are only mirrored as EPUB or HTML. An EPUB is a zip whose
+// META-INF/container.xml points at an OPF package; the package's spine lists the XHTML
+// documents in reading order. Both end up in the same markup stripper, which splits the
+// text into chapters at h1-h3 headings.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// None for text before the first heading
+    pub title: Option<String>,
+    /// Paragraphs separated by blank lines, like `clean_gutenberg_text` output
+    pub text: String,
+}
+
+// Content of these elements is never book text
+const SKIPPED_ELEMENTS: [&str; 5] = ["head", "script", "style", "title", "svg"];
+
+// Elements that never have a closing tag in HTML
+const VOID_ELEMENTS: [&str; 8] = ["br", "hr", "img", "meta", "link", "input", "col", "wbr"];
+
+const BLOCK_ELEMENTS: [&str; 20] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "body",
+    "blockquote",
+    "pre",
+    "li",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "table",
+    "tr",
+    "hr",
+    "h4",
+    "h5",
+    "h6",
+    "figure",
+];
+
+fn heading_level(name: &str) -> Option<u8> {
+    match name {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        _ => None,
+    }
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(number) = entity.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    let decoded = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "hellip" => '…',
+        "laquo" => '«',
+        "raquo" => '»',
+        "shy" => '\u{ad}',
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+/// Replaces character references; unknown ones are left as written
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match entity {
+            Some((c, length)) => {
+                // soft hyphens only mark where a word may break
+                if c != '\u{ad}' {
+                    decoded.push(c);
+                }
+                rest = &rest[length..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Gutenberg HTML and EPUB files wrap their license header and footer in sections with
+// these ids, or the pg-boilerplate class
+fn is_boilerplate_element(tag: &str) -> bool {
+    tag.contains("pg-boilerplate")
+        || tag.contains("\"pg-header\"")
+        || tag.contains("\"pg-footer\"")
+        || tag.contains("'pg-header'")
+        || tag.contains("'pg-footer'")
+}
+
+/// Collects chapters from one or more documents fed in reading order, so a chapter split
+/// across EPUB files continues until the next heading.
+#[derive(Debug, Default)]
+struct ChapterBuilder {
+    chapters: Vec<Chapter>,
+    title: Option<String>,
+    title_level: u8,
+    paragraphs: Vec<String>,
+    paragraph: String,
+    heading: Option<(u8, String)>,
+    /// Set at the license footer of older files without pg-footer sections
+    ended: bool,
+}
+
+impl ChapterBuilder {
+    fn text(&mut self, text: &str) {
+        match &mut self.heading {
+            Some((_, heading)) => heading.push_str(text),
+            None => self.paragraph.push_str(text),
+        }
+    }
+
+    fn end_paragraph(&mut self) {
+        let raw = std::mem::take(&mut self.paragraph);
+        // blank lines only separate paragraphs inside <pre>, where older files keep
+        // their license header
+        for paragraph in raw.split("\n\n").map(normalize_paragraph) {
+            if paragraph.is_empty() || self.ended {
+                continue;
+            }
+            if is_start_marker(&paragraph) {
+                // everything so far was the license header
+                self.chapters.clear();
+                self.paragraphs.clear();
+                self.title = None;
+            } else if is_end_marker(&paragraph) {
+                self.ended = true;
+            } else {
+                self.paragraphs.push(paragraph);
+            }
+        }
+    }
+
+    fn end_chapter(&mut self) {
+        if !self.paragraphs.is_empty() {
+            self.chapters.push(Chapter {
+                title: self.title.take(),
+                text: self.paragraphs.join("\n\n"),
+            });
+            self.paragraphs.clear();
+        }
+    }
+
+    fn start_heading(&mut self, level: u8) {
+        self.end_paragraph();
+        self.heading = Some((level, String::new()));
+    }
+
+    fn end_heading(&mut self) {
+        let Some((level, heading)) = self.heading.take() else {
+            return;
+        };
+        let heading = normalize_paragraph(&heading);
+        if heading.is_empty() {
+            return;
+        }
+        if self.paragraphs.is_empty() && level > self.title_level {
+            // a subtitle right under its chapter heading
+            if let Some(title) = &mut self.title {
+                title.push_str(": ");
+                title.push_str(&heading);
+                return;
+            }
+        }
+        self.end_chapter();
+        self.title = Some(heading);
+        self.title_level = level;
+    }
+
+    fn tag(&mut self, name: &str, closing: bool) {
+        if let Some(level) = heading_level(name) {
+            if closing {
+                self.end_heading();
+            } else {
+                self.start_heading(level);
+            }
+        } else if name == "br" {
+            self.text(" ");
+        } else if BLOCK_ELEMENTS.contains(&name) {
+            self.end_paragraph();
+        }
+    }
+
+    fn feed(&mut self, html: &str) {
+        // element being skipped and how deeply it is nested in itself
+        let mut skipping: Option<(String, usize)> = None;
+        let mut rest = html;
+
+        while !rest.is_empty() && !self.ended {
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                rest = comment
+                    .split_once("-->")
+                    .map(|(_, after)| after)
+                    .unwrap_or("");
+                continue;
+            }
+            if !rest.starts_with('<') {
+                let end = rest.find('<').unwrap_or(rest.len());
+                if skipping.is_none() {
+                    self.text(&decode_entities(&rest[..end]));
+                }
+                rest = &rest[end..];
+                continue;
+            }
+
+            let Some(end) = rest.find('>') else {
+                break;
+            };
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with(['!', '?']) {
+                continue;
+            }
+            let (closing, tag) = match tag.strip_prefix('/') {
+                Some(tag) => (true, tag),
+                None => (false, tag),
+            };
+            let name = tag
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or("")
+                .to_lowercase();
+            let opens = !closing && !tag.ends_with('/') && !VOID_ELEMENTS.contains(&name.as_str());
+
+            if let Some((skipped, depth)) = &mut skipping {
+                if *skipped == name {
+                    if closing {
+                        *depth -= 1;
+                    } else if opens {
+                        *depth += 1;
+                    }
+                    if *depth == 0 {
+                        skipping = None;
+                    }
+                }
+                continue;
+            }
+            if opens && (SKIPPED_ELEMENTS.contains(&name.as_str()) || is_boilerplate_element(tag)) {
+                skipping = Some((name, 1));
+                continue;
+            }
+            self.tag(&name, closing);
+        }
+        self.end_heading();
+        self.end_paragraph();
+    }
+
+    fn finish(mut self) -> Vec<Chapter> {
+        self.end_chapter();
+        self.chapters
+            .into_iter()
+            .filter(|chapter| !chapter.title.as_deref().is_some_and(is_contents_heading))
+            .collect()
+    }
+}
+
+/// Strips the markup of an HTML or XHTML document and splits its text into chapters
+pub fn html_to_chapters(html: &str) -> Vec<Chapter> {
+    let mut builder = ChapterBuilder::default();
+    builder.feed(html);
+    builder.finish()
+}
+
+/// Joins chapters back into one text for chunking, each title as its own paragraph
+pub fn chapters_text(chapters: &[Chapter]) -> String {
+    let mut parts = Vec::new();
+    for chapter in chapters {
+        if let Some(title) = &chapter.title {
+            parts.push(title.as_str());
+        }
+        parts.push(chapter.text.as_str());
+    }
+    parts.join("\n\n")
+}
+
+fn attribute(
+    element: &BytesStart,
+    name: &[u8],
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        if attribute.key.local_name().as_ref() == name {
+            return Ok(Some(attribute.unescape_value()?.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Path of the OPF package inside the archive, from META-INF/container.xml
+fn package_path(container: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut reader = Reader::from_str(container);
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"rootfile" =>
+            {
+                if let Some(path) = attribute(&element, b"full-path")? {
+                    return Ok(path);
+                }
+            }
+            Event::Eof => return Err("container.xml has no rootfile".into()),
+            _ => (),
+        }
+    }
+}
+
+/// Hrefs of the package's spine documents in reading order, relative to the package file.
+/// Non-linear items (footnotes, covers) and anything that is not XHTML are left out.
+fn spine_documents(package: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut manifest = HashMap::new();
+    let mut spine = Vec::new();
+    let mut reader = Reader::from_str(package);
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element) => match element.local_name().as_ref() {
+                b"item" => {
+                    let (Some(id), Some(href)) =
+                        (attribute(&element, b"id")?, attribute(&element, b"href")?)
+                    else {
+                        continue;
+                    };
+                    let media_type = attribute(&element, b"media-type")?.unwrap_or_default();
+                    manifest.insert(id, (href, media_type));
+                }
+                b"itemref" => {
+                    if attribute(&element, b"linear")?.as_deref() != Some("no")
+                        && let Some(idref) = attribute(&element, b"idref")?
+                    {
+                        spine.push(idref);
+                    }
+                }
+                _ => (),
+            },
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    Ok(spine
+        .iter()
+        .filter_map(|idref| manifest.get(idref))
+        .filter(|(_, media_type)| {
+            media_type == "application/xhtml+xml" || media_type == "text/html"
+        })
+        .map(|(href, _)| href.clone())
+        .collect())
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = text.get(i + 1..i + 3);
+        match (
+            bytes[i],
+            hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+        ) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Archive path of an href relative to the package file
+fn resolve_href(package_path: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or(href);
+    let mut parts: Vec<&str> = package_path.split('/').collect();
+    parts.pop();
+    let href = percent_decode(href);
+    for part in href.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut bytes = Vec::new();
+    archive
+        .by_name(name)
+        .map_err(|e| format!("{} in EPUB: {}", name, e))?
+        .read_to_end(&mut bytes)?;
+    Ok(decode_text(&bytes))
+}
+
+fn read_epub_archive<R: Read + Seek>(
+    reader: R,
+) -> Result<Vec<Chapter>, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(reader)?;
+    let package_path = package_path(&read_entry(&mut archive, "META-INF/container.xml")?)?;
+    let package = read_entry(&mut archive, &package_path)?;
+
+    let mut builder = ChapterBuilder::default();
+    for href in spine_documents(&package)? {
+        let document = read_entry(&mut archive, &resolve_href(&package_path, &href))?;
+        builder.feed(&document);
+    }
+    Ok(builder.finish())
+}
+
+pub fn read_epub(path: &Path) -> Result<Vec<Chapter>, Box<dyn std::error::Error>> {
+    read_epub_archive(fs::File::open(path)?)
+}
+
+pub fn read_html(path: &Path) -> Result<Vec<Chapter>, Box<dyn std::error::Error>> {
+    Ok(html_to_chapters(&decode_text(&fs::read(path)?)))
+}
+
+/// EPUB renditions first, then HTML, skipping zipped copies
+pub fn ebook_formats(metadata: &BookMetadata) -> Vec<&BookFormat> {
+    let mut formats: Vec<&BookFormat> = metadata
+        .formats
+        .iter()
+        .filter(|format| {
+            (format.has_media_type("application/epub+zip") || format.has_media_type("text/html"))
+                && !format.has_media_type("application/zip")
+        })
+        .collect();
+    formats.sort_by_key(|format| !format.has_media_type("application/epub+zip"));
+    formats
+}
+
+/// Extracts the chapters of the first EPUB or HTML rendition found in the mirror.
+/// Returns None if none is mirrored, or if the one found has no text.
+pub fn load_ebook_chapters(
+    mirror_dir: &Path,
+    metadata: &BookMetadata,
+) -> Result<Option<Vec<Chapter>>, Box<dyn std::error::Error>> {
+    for format in ebook_formats(metadata) {
+        let Some(path) = mirror_path(mirror_dir, &format.url) else {
+            continue;
+        };
+        if !path.is_file() {
+            continue;
+        }
+        let chapters = if format.has_media_type("application/epub+zip") {
+            read_epub(&path)?
+        } else {
+            read_html(&path)?
+        };
+        return Ok(Some(chapters).filter(|chapters| !chapters.is_empty()));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    const CHAPTER_ONE: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Pride and Prejudice</title><style>p { margin: 0 }</style></head>
+<body>
+<section class="pg-boilerplate pgheader" id="pg-header">
+  <p>The Project Gutenberg eBook of Pride and Prejudice</p>
+</section>
+<h2>Contents</h2>
+<p><a href="#ch1">Chapter I.</a></p>
+<h2 id="ch1">Chapter I.</h2>
+<h3>In Which a Truth Is Acknowledged</h3>
+<p>It is a truth universally acknowledged, that a single man in
+possession of a good fortune, must be in want of a wife.</p>
+<p>&ldquo;My dear Mr.&nbsp;Bennet,&rdquo; said his lady<br/>to him one day&hellip;</p>
+</body></html>"##;
+
+    const CHAPTER_ONE_CONTINUED: &str = r#"<html><body>
+<p>Mr. Bennet replied that he had not &#8212; &amp; did not care.</p>
+<h2>Chapter II.</h2>
+<p>Mr. Bennet was among the earliest of those who waited on Mr. Bingley.</p>
+<section id="pg-footer"><p>End of the Project Gutenberg eBook</p></section>
+</body></html>"#;
+
+    #[test]
+    fn test_html_to_chapters() {
+        let chapters = html_to_chapters(CHAPTER_ONE);
+        assert_eq!(chapters.len(), 1);
+        assert_eq!(
+            chapters[0].title.as_deref(),
+            Some("Chapter I.: In Which a Truth Is Acknowledged")
+        );
+        assert_eq!(
+            chapters[0].text,
+            "It is a truth universally acknowledged, that a single man in possession of a \
+             good fortune, must be in want of a wife.\n\n\
+             “My dear Mr. Bennet,” said his lady to him one day…"
+        );
+    }
+
+    #[test]
+    fn test_html_license_markers() {
+        let html = "<pre>Produced by volunteers\n\n*** START OF THE PROJECT GUTENBERG EBOOK EMMA ***</pre>\
+            <p>Emma Woodhouse, handsome, clever, and rich.</p>\
+            <pre>*** END OF THE PROJECT GUTENBERG EBOOK EMMA ***</pre><p>License text</p>";
+        let chapters = html_to_chapters(html);
+        assert_eq!(
+            chapters,
+            vec![Chapter {
+                title: None,
+                text: "Emma Woodhouse, handsome, clever, and rich.".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("a &amp; b &#x2014; &#233;"), "a & b — é");
+        assert_eq!(decode_entities("AT&T &unknown; &"), "AT&T &unknown; &");
+        assert_eq!(decode_entities("hy&shy;phen"), "hyphen");
+    }
+
+    #[test]
+    fn test_resolve_href() {
+        assert_eq!(
+            resolve_href("OEBPS/content.opf", "text/ch%201.xhtml#start"),
+            "OEBPS/text/ch 1.xhtml"
+        );
+        assert_eq!(resolve_href("content.opf", "ch1.xhtml"), "ch1.xhtml");
+        assert_eq!(
+            resolve_href("OEBPS/pkg/content.opf", "../ch1.xhtml"),
+            "OEBPS/ch1.xhtml"
+        );
+    }
+
+    #[test]
+    fn test_read_epub_in_spine_order() {
+        let container = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+        // manifest order differs from the spine, and the cover is not linear
+        let package = r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <manifest>
+    <item id="part2" href="text/part2.xhtml" media-type="application/xhtml+xml"/>
+    <item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>
+    <item id="part1" href="text/part1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="css" href="style.css" media-type="text/css"/>
+  </manifest>
+  <spine>
+    <itemref idref="cover" linear="no"/>
+    <itemref idref="part1"/>
+    <itemref idref="part2"/>
+  </spine>
+</package>"#;
+
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in [
+            ("META-INF/container.xml", container),
+            ("OEBPS/content.opf", package),
+            (
+                "OEBPS/cover.xhtml",
+                "<html><body><p>Cover</p></body></html>",
+            ),
+            ("OEBPS/text/part1.xhtml", CHAPTER_ONE),
+            ("OEBPS/text/part2.xhtml", CHAPTER_ONE_CONTINUED),
+        ] {
+            writer
+                .start_file(name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+
+        let chapters = read_epub_archive(Cursor::new(archive.into_inner())).unwrap();
+        let titles: Vec<Option<&str>> = chapters
+            .iter()
+            .map(|chapter| chapter.title.as_deref())
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                Some("Chapter I.: In Which a Truth Is Acknowledged"),
+                Some("Chapter II.")
+            ]
+        );
+        // the untitled start of part2 continues chapter I
+        assert!(
+            chapters[0]
+                .text
+                .ends_with("Mr. Bennet replied that he had not — & did not care.")
+        );
+        assert!(!chapters_text(&chapters).contains("Project Gutenberg"));
+        assert!(chapters_text(&chapters).starts_with("Chapter I.: In Which"));
+    }
+}
@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use sqlx::postgres::{PgPool, PgRow};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::schema::{Schema, TableName};
+
+/// The model of the vectors that predate the registry. Registering it adopts the
+/// unsuffixed tables from migrations 0002 and 0008 instead of creating new ones.
+pub const DEFAULT_MODEL: &str = "qwen3";
+
+/// What a vector table embeds. Each model has at most one table per kind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorKind {
+    Summary,
+    /// Full-text passages, many rows per book
+    Chunk,
+}
+
+impl VectorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VectorKind::Summary => "summary",
+            VectorKind::Chunk => "chunk",
+        }
+    }
+}
+
+impl fmt::Display for VectorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for VectorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(VectorKind::Summary),
+            "chunk" => Ok(VectorKind::Chunk),
+            _ => Err(format!("unknown vector kind {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingModel {
+    pub id: i32,
+    /// Also used in table names, so lowercase letters, digits and underscores only
+    pub name: String,
+    pub dimensions: i32,
+    pub model_path: String,
+    pub tokenizer_path: String,
+}
+
+impl EmbeddingModel {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        Ok(EmbeddingModel {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            dimensions: row.try_get("dimensions")?,
+            model_path: row.try_get("model_path")?,
+            tokenizer_path: row.try_get("tokenizer_path")?,
+        })
+    }
+}
+
+fn vector_table_name(model_name: &str, kind: VectorKind) -> String {
+    format!("book_{}_vectors_{}", kind, model_name)
+}
+
+/// The table a newly registered model gets for `kind`, see `DEFAULT_MODEL`
+fn new_vector_table_name(schema: &Schema, model_name: &str, kind: VectorKind) -> String {
+    match kind {
+        _ if model_name != DEFAULT_MODEL => vector_table_name(model_name, kind),
+        VectorKind::Summary => schema.vectors.name().to_string(),
+        VectorKind::Chunk => schema.chunks.name().to_string(),
+    }
+}
+
+fn create_vector_table_sql(
+    schema: &Schema,
+    table: &TableName,
+    kind: VectorKind,
+    dimensions: i32,
+) -> String {
+    match kind {
+        VectorKind::Summary => format!(
+            "
+            CREATE TABLE IF NOT EXISTS {} (
+                id bigint PRIMARY KEY,
+                embedding vector({}),
+                content_hash TEXT,
+                model_fingerprint TEXT,
+                updated_at timestamptz NOT NULL DEFAULT now()
+            )
+            ",
+            table, dimensions
+        ),
+        // same shape as book_chunks from migration 0008
+        VectorKind::Chunk => format!(
+            "
+            CREATE TABLE IF NOT EXISTS {} (
+                book_id bigint NOT NULL REFERENCES {} (id) ON DELETE CASCADE,
+                chunk_index INT NOT NULL,
+                start_offset INT NOT NULL,
+                end_offset INT NOT NULL,
+                text TEXT NOT NULL,
+                embedding vector({}),
+                source_hash TEXT,
+                model_fingerprint TEXT,
+                updated_at timestamptz NOT NULL DEFAULT now(),
+                PRIMARY KEY (book_id, chunk_index)
+            )
+            ",
+            table, schema.metadata, dimensions
+        ),
+    }
+}
+
+/// Adds a model to the registry, or updates its file paths if it is already registered,
+/// and creates its summary vector table. The dimension of a registered model cannot change,
+/// register it under a new name instead.
+pub async fn register_model(
+    pool: &PgPool,
+    schema: &Schema,
+    name: &str,
+    dimensions: i32,
+    model_path: &str,
+    tokenizer_path: &str,
+) -> Result<EmbeddingModel, Box<dyn std::error::Error>> {
+    // fail before inserting anything if the name cannot be used in a table name,
+    // or if it would adopt a table of another dimension
+    TableName::new(&vector_table_name(name, VectorKind::Summary))?;
+    let table = schema.sibling(&new_vector_table_name(schema, name, VectorKind::Summary))?;
+    check_existing_dimensions(pool, &table, dimensions).await?;
+
+    let insert_string = format!(
+        "
+        INSERT INTO {} (name, dimensions, model_path, tokenizer_path)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (name) DO UPDATE SET
+            model_path = excluded.model_path,
+            tokenizer_path = excluded.tokenizer_path
+        RETURNING id, name, dimensions, model_path, tokenizer_path
+        ",
+        schema.models
+    );
+    let row = sqlx::query(insert_string.as_str())
+        .bind(name)
+        .bind(dimensions)
+        .bind(model_path)
+        .bind(tokenizer_path)
+        .fetch_one(pool)
+        .await?;
+    let model = EmbeddingModel::from_row(&row)?;
+    if model.dimensions != dimensions {
+        return Err(format!(
+            "model {} is registered with {} dimensions, not {}",
+            name, model.dimensions, dimensions
+        )
+        .into());
+    }
+
+    ensure_vector_table(pool, schema, &model, VectorKind::Summary).await?;
+    Ok(model)
+}
+
+/// Creates the model's table for `kind` unless it already has one
+pub async fn ensure_vector_table(
+    pool: &PgPool,
+    schema: &Schema,
+    model: &EmbeddingModel,
+    kind: VectorKind,
+) -> Result<TableName, Box<dyn std::error::Error>> {
+    if let Some(table) = find_vector_table(pool, schema, model, kind).await? {
+        return Ok(table);
+    }
+
+    let table_name = new_vector_table_name(schema, &model.name, kind);
+    let table = schema.sibling(&table_name)?;
+    check_existing_dimensions(pool, &table, model.dimensions).await?;
+    let mut transaction = pool.begin().await?;
+    sqlx::query(create_vector_table_sql(schema, &table, kind, model.dimensions).as_str())
+        .execute(&mut *transaction)
+        .await?;
+    let insert_string = format!(
+        "INSERT INTO {} (model_id, kind, table_name) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        schema.model_tables
+    );
+    sqlx::query(insert_string.as_str())
+        .bind(model.id)
+        .bind(kind.as_str())
+        .bind(&table_name)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    println!("Created {} for model {}", table, model.name);
+    Ok(table)
+}
+
+/// Refuses to adopt an existing table whose embedding column has another dimension,
+/// CREATE TABLE IF NOT EXISTS would otherwise keep it silently
+async fn check_existing_dimensions(
+    pool: &PgPool,
+    table: &TableName,
+    dimensions: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // atttypmod of a vector(n) column is n
+    let row = sqlx::query(
+        "SELECT atttypmod FROM pg_attribute WHERE attrelid = to_regclass($1) AND attname = 'embedding'",
+    )
+    .bind(table.to_string())
+    .fetch_optional(pool)
+    .await?;
+    if let Some(row) = row {
+        let existing: i32 = row.try_get("atttypmod")?;
+        if existing != dimensions {
+            return Err(format!(
+                "{} already exists with vector({}), not vector({})",
+                table, existing, dimensions
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+async fn find_vector_table(
+    pool: &PgPool,
+    schema: &Schema,
+    model: &EmbeddingModel,
+    kind: VectorKind,
+) -> Result<Option<TableName>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT table_name FROM {} WHERE model_id = $1 AND kind = $2",
+        schema.model_tables
+    );
+    let row = sqlx::query(query_string.as_str())
+        .bind(model.id)
+        .bind(kind.as_str())
+        .fetch_optional(pool)
+        .await?;
+    match row {
+        Some(row) => Ok(Some(schema.sibling(row.try_get("table_name")?)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn load_model(
+    pool: &PgPool,
+    schema: &Schema,
+    name: &str,
+) -> Result<EmbeddingModel, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT id, name, dimensions, model_path, tokenizer_path FROM {} WHERE name = $1",
+        schema.models
+    );
+    let row = sqlx::query(query_string.as_str())
+        .bind(name)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| {
+            format!(
+                "no embedding model named {:?} is registered, run `{}` first",
+                name,
+                register_command(name)
+            )
+        })?;
+    Ok(EmbeddingModel::from_row(&row)?)
+}
+
+/// The `models register` command line for a model that is missing from the registry
+fn register_command(name: &str) -> String {
+    // the vector(1024) tables of migrations 0002 and 0008
+    let dimensions = if name == DEFAULT_MODEL { "1024" } else { "<n>" };
+    format!(
+        "book-recommender models register {} --dimensions {} --model-path <model.onnx> --tokenizer-path <tokenizer.json>",
+        name, dimensions
+    )
+}
+
+pub async fn list_models(
+    pool: &PgPool,
+    schema: &Schema,
+) -> Result<Vec<EmbeddingModel>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT id, name, dimensions, model_path, tokenizer_path FROM {} ORDER BY name",
+        schema.models
+    );
+    let rows = sqlx::query(query_string.as_str()).fetch_all(pool).await?;
+    let mut models = Vec::with_capacity(rows.len());
+    for row in rows {
+        models.push(EmbeddingModel::from_row(&row)?);
+    }
+    Ok(models)
+}
+
+/// The schema to pass to indexing and search functions to use `model_name`'s vectors.
+/// The chunk table is created on first use with `ensure_vector_table`.
+pub async fn schema_for_model(
+    pool: &PgPool,
+    schema: &Schema,
+    model_name: &str,
+) -> Result<(Schema, EmbeddingModel), Box<dyn std::error::Error>> {
+    let model = load_model(pool, schema, model_name).await?;
+    let vectors = find_vector_table(pool, schema, &model, VectorKind::Summary)
+        .await?
+        .ok_or_else(|| format!("model {} has no summary vector table", model.name))?;
+    let chunks = match find_vector_table(pool, schema, &model, VectorKind::Chunk).await? {
+        Some(table) => table,
+        None => schema.sibling(&new_vector_table_name(
+            schema,
+            &model.name,
+            VectorKind::Chunk,
+        ))?,
+    };
+    Ok((schema.with_vectors(vectors, chunks), model))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_table_per_model() {
+        let name = vector_table_name("minilm_l6", VectorKind::Summary);
+        assert_eq!(name, "book_summary_vectors_minilm_l6");
+
+        let schema = Schema::in_namespace("test_run").unwrap();
+        let table = schema.sibling(&name).unwrap();
+        let sql = create_vector_table_sql(&schema, &table, VectorKind::Summary, 384);
+        assert!(sql.contains("\"test_run\".\"book_summary_vectors_minilm_l6\""));
+        assert!(sql.contains("embedding vector(384)"));
+
+        let name = vector_table_name("minilm_l6", VectorKind::Chunk);
+        assert_eq!(name, "book_chunk_vectors_minilm_l6");
+        let table = schema.sibling(&name).unwrap();
+        let sql = create_vector_table_sql(&schema, &table, VectorKind::Chunk, 384);
+        assert!(sql.contains("REFERENCES \"test_run\".\"book_metadata\" (id)"));
+
+        assert!(TableName::new(&vector_table_name("MiniLM", VectorKind::Summary)).is_err());
+
+        // the default model adopts the tables from before the registry
+        assert_eq!(
+            new_vector_table_name(&schema, DEFAULT_MODEL, VectorKind::Summary),
+            "book_summary_vectors"
+        );
+        assert_eq!(
+            new_vector_table_name(&schema, DEFAULT_MODEL, VectorKind::Chunk),
+            "book_chunks"
+        );
+        assert_eq!(
+            new_vector_table_name(&schema, "minilm_l6", VectorKind::Chunk),
+            "book_chunk_vectors_minilm_l6"
+        );
+        assert!(register_command(DEFAULT_MODEL).contains("register qwen3 --dimensions 1024 "));
+    }
+}
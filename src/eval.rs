@@ -0,0 +1,713 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::batch::{BatchQuery, run_queries};
+use crate::config::EmbeddingConfig;
+use crate::embedding_models::EmbeddingModel;
+use crate::schema::Schema;
+use crate::search::{SearchFilters, SearchRequest, similar_to};
+
+// Judgments are JSONL, one query per line with either the text of a search or the seed
+// book of a "more like this" query, and the books a good answer contains:
+//
+//   {"id": "q1", "text": "whaling voyage", "relevant": [{"id": 2701, "grade": 3}, {"id": 15}]}
+//   {"id": "q2", "seed": 1342, "relevant": [{"id": 161, "grade": 2}, {"id": 158, "grade": 2}]}
+//
+// Grades are graded relevance for nDCG, 1 when left out. Every other metric counts any
+// grade above 0 as relevant. `filters` takes the fields of `SearchFilters`.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelevantBook {
+    pub id: i64,
+    #[serde(default = "default_grade")]
+    pub grade: u32,
+}
+
+fn default_grade() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Judgment {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(default, skip_serializing)]
+    pub filters: SearchFilters,
+    pub relevant: Vec<RelevantBook>,
+}
+
+impl Judgment {
+    fn describe(&self) -> String {
+        match (&self.text, self.seed) {
+            (Some(text), _) => text.clone(),
+            (None, Some(seed)) => format!("similar to {}", seed),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+fn check_judgments(judgments: &[Judgment]) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    for judgment in judgments {
+        if !ids.insert(judgment.id.as_str()) {
+            return Err(format!("query id {} appears more than once", judgment.id));
+        }
+        match (&judgment.text, judgment.seed) {
+            (Some(text), None) if !text.trim().is_empty() => {}
+            (None, Some(_)) => {}
+            _ => {
+                return Err(format!(
+                    "query {} needs either a non-empty text or a seed",
+                    judgment.id
+                ));
+            }
+        }
+        if !judgment.relevant.iter().any(|book| book.grade > 0) {
+            return Err(format!("query {} has no relevant books", judgment.id));
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_judgments(text: &str) -> Result<Vec<Judgment>, String> {
+    let judgments = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", index + 1, e))
+        })
+        .collect::<Result<Vec<Judgment>, _>>()?;
+    check_judgments(&judgments)?;
+    Ok(judgments)
+}
+
+/// The judgments in the file and the SHA-256 of its content, see `EvalReport::judgments_sha256`
+pub fn read_judgments(path: &Path) -> Result<(Vec<Judgment>, String), Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read judgments {}: {}", path.display(), e))?;
+    let judgments = parse_judgments(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok((judgments, judgments_sha256(&text)))
+}
+
+fn judgments_sha256(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Every metric is cut off at the report's k and lies in [0, 1], higher is better
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    pub recall: f64,
+    pub precision: f64,
+    /// 1 / rank of the first relevant book, the mean over queries is MRR
+    pub reciprocal_rank: f64,
+    pub ndcg: f64,
+    /// The mean over queries is MAP
+    pub average_precision: f64,
+}
+
+impl Metrics {
+    /// Display names for reports, with the aggregate names used for means
+    fn named(&self) -> [(&'static str, f64); 5] {
+        [
+            ("recall@k", self.recall),
+            ("precision@k", self.precision),
+            ("MRR", self.reciprocal_rank),
+            ("nDCG@k", self.ndcg),
+            ("MAP", self.average_precision),
+        ]
+    }
+
+    pub fn mean(metrics: &[Metrics]) -> Metrics {
+        if metrics.is_empty() {
+            return Metrics::default();
+        }
+        let n = metrics.len() as f64;
+        let sum = |field: fn(&Metrics) -> f64| metrics.iter().map(field).sum::<f64>() / n;
+        Metrics {
+            recall: sum(|m| m.recall),
+            precision: sum(|m| m.precision),
+            reciprocal_rank: sum(|m| m.reciprocal_rank),
+            ndcg: sum(|m| m.ndcg),
+            average_precision: sum(|m| m.average_precision),
+        }
+    }
+}
+
+// Exponential gain, so a grade 3 book matters much more than two grade 1 books
+fn gain(grade: u32) -> f64 {
+    2f64.powi(grade as i32) - 1.0
+}
+
+fn discount(index: usize) -> f64 {
+    1.0 / (index as f64 + 2.0).log2()
+}
+
+/// Scores the first `k` of `retrieved` (book ids, best first) against the judged books
+pub fn evaluate(retrieved: &[i64], relevant: &[RelevantBook], k: usize) -> Metrics {
+    let grades: HashMap<i64, u32> = relevant
+        .iter()
+        .filter(|book| book.grade > 0)
+        .map(|book| (book.id, book.grade))
+        .collect();
+    if grades.is_empty() || k == 0 {
+        return Metrics::default();
+    }
+    let top = &retrieved[..retrieved.len().min(k)];
+
+    let mut found = 0;
+    let mut precision_sum = 0.0;
+    let mut dcg = 0.0;
+    let mut first_hit = None;
+    for (index, id) in top.iter().enumerate() {
+        if let Some(&grade) = grades.get(id) {
+            found += 1;
+            precision_sum += found as f64 / (index + 1) as f64;
+            dcg += gain(grade) * discount(index);
+            first_hit.get_or_insert(index);
+        }
+    }
+    let mut ideal: Vec<u32> = grades.values().copied().collect();
+    ideal.sort_unstable_by(|a, b| b.cmp(a));
+    let ideal_dcg: f64 = ideal
+        .iter()
+        .take(k)
+        .enumerate()
+        .map(|(index, &grade)| gain(grade) * discount(index))
+        .sum();
+
+    Metrics {
+        recall: found as f64 / grades.len() as f64,
+        precision: found as f64 / k as f64,
+        reciprocal_rank: first_hit.map_or(0.0, |index| 1.0 / (index + 1) as f64),
+        ndcg: dcg / ideal_dcg,
+        average_precision: precision_sum / grades.len() as f64,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryEvaluation {
+    pub query_id: String,
+    pub query: String,
+    /// Book ids in rank order
+    pub retrieved: Vec<i64>,
+    /// The relevant books among `retrieved`
+    pub hits: Vec<i64>,
+    pub metrics: Metrics,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvalReport {
+    /// Names the run in comparisons, e.g. the model or index settings being tried
+    pub label: String,
+    pub model: String,
+    pub k: usize,
+    /// Path of the judgments file as given on the command line
+    pub judgments: String,
+    /// Identifies the judgments whatever their path, runs are only compared when it matches.
+    /// Empty in reports written before it was recorded.
+    #[serde(default)]
+    pub judgments_sha256: String,
+    pub mean: Metrics,
+    pub queries: Vec<QueryEvaluation>,
+}
+
+impl EvalReport {
+    pub fn new(
+        label: &str,
+        model: &str,
+        k: usize,
+        judgments_path: &Path,
+        judgments_sha256: &str,
+        judgments: &[Judgment],
+        retrieved: Vec<Vec<i64>>,
+    ) -> Self {
+        let queries: Vec<QueryEvaluation> = judgments
+            .iter()
+            .zip(retrieved)
+            .map(|(judgment, retrieved)| {
+                let relevant: HashSet<i64> = judgment
+                    .relevant
+                    .iter()
+                    .filter(|book| book.grade > 0)
+                    .map(|book| book.id)
+                    .collect();
+                QueryEvaluation {
+                    query_id: judgment.id.clone(),
+                    query: judgment.describe(),
+                    hits: retrieved
+                        .iter()
+                        .take(k)
+                        .filter(|id| relevant.contains(id))
+                        .copied()
+                        .collect(),
+                    metrics: evaluate(&retrieved, &judgment.relevant, k),
+                    retrieved,
+                }
+            })
+            .collect();
+        let metrics: Vec<Metrics> = queries.iter().map(|query| query.metrics).collect();
+        EvalReport {
+            label: label.to_string(),
+            model: model.to_string(),
+            k,
+            judgments: judgments_path.display().to_string(),
+            judgments_sha256: judgments_sha256.to_string(),
+            mean: Metrics::mean(&metrics),
+            queries,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read report {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&text)
+            .map_err(|e| format!("{} is not an evaluation report: {}", path.display(), e))?)
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "# {}\n", self.label).unwrap();
+        writeln!(
+            out,
+            "Model {}, k = {}, {} queries from {}\n",
+            self.model,
+            self.k,
+            self.queries.len(),
+            self.judgments
+        )
+        .unwrap();
+        writeln!(out, "| metric | value |\n|---|---:|").unwrap();
+        for (name, value) in self.mean.named() {
+            writeln!(out, "| {} | {:.4} |", name, value).unwrap();
+        }
+        writeln!(
+            out,
+            "\n| query | recall | precision | RR | nDCG | AP |\n|---|---:|---:|---:|---:|---:|"
+        )
+        .unwrap();
+        for query in &self.queries {
+            let m = &query.metrics;
+            writeln!(
+                out,
+                "| {} | {:.3} | {:.3} | {:.3} | {:.3} | {:.3} |",
+                query.query_id,
+                m.recall,
+                m.precision,
+                m.reciprocal_rank,
+                m.ndcg,
+                m.average_precision
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryDiff {
+    pub query_id: String,
+    pub query: String,
+    pub baseline: Metrics,
+    pub candidate: Metrics,
+    /// Relevant books only the candidate retrieved
+    pub gained: Vec<i64>,
+    /// Relevant books only the baseline retrieved
+    pub lost: Vec<i64>,
+}
+
+impl QueryDiff {
+    fn ndcg_delta(&self) -> f64 {
+        self.candidate.ndcg - self.baseline.ndcg
+    }
+}
+
+/// Two runs over the same judgments side by side
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub baseline: String,
+    pub candidate: String,
+    pub k: usize,
+    pub baseline_mean: Metrics,
+    pub candidate_mean: Metrics,
+    /// Queries whose metrics changed, the biggest nDCG losses first
+    pub changed: Vec<QueryDiff>,
+    pub unchanged: usize,
+    /// Query ids in only one of the runs, left out of everything above
+    pub unmatched: Vec<String>,
+}
+
+impl Comparison {
+    pub fn new(baseline: &EvalReport, candidate: &EvalReport) -> Result<Self, String> {
+        if baseline.k != candidate.k {
+            return Err(format!(
+                "runs used different k ({} and {}), metrics are not comparable",
+                baseline.k, candidate.k
+            ));
+        }
+        // reports from before the hash was recorded can only be matched on the path
+        let same_judgments =
+            if baseline.judgments_sha256.is_empty() || candidate.judgments_sha256.is_empty() {
+                baseline.judgments == candidate.judgments
+            } else {
+                baseline.judgments_sha256 == candidate.judgments_sha256
+            };
+        if !same_judgments {
+            return Err(format!(
+                "runs used different judgments ({} and {} differ), metrics are not comparable",
+                baseline.judgments, candidate.judgments
+            ));
+        }
+        let candidates: HashMap<&str, &QueryEvaluation> = candidate
+            .queries
+            .iter()
+            .map(|query| (query.query_id.as_str(), query))
+            .collect();
+        let baseline_ids: HashSet<&str> = baseline
+            .queries
+            .iter()
+            .map(|query| query.query_id.as_str())
+            .collect();
+
+        let mut pairs = Vec::new();
+        let mut unmatched = Vec::new();
+        for before in &baseline.queries {
+            match candidates.get(before.query_id.as_str()) {
+                Some(after) => pairs.push((before, *after)),
+                None => unmatched.push(before.query_id.clone()),
+            }
+        }
+        unmatched.extend(
+            candidate
+                .queries
+                .iter()
+                .filter(|query| !baseline_ids.contains(query.query_id.as_str()))
+                .map(|query| query.query_id.clone()),
+        );
+
+        let mut changed: Vec<QueryDiff> = pairs
+            .iter()
+            .filter(|(before, after)| before.metrics != after.metrics)
+            .map(|(before, after)| QueryDiff {
+                query_id: before.query_id.clone(),
+                query: before.query.clone(),
+                baseline: before.metrics,
+                candidate: after.metrics,
+                gained: after
+                    .hits
+                    .iter()
+                    .filter(|id| !before.hits.contains(id))
+                    .copied()
+                    .collect(),
+                lost: before
+                    .hits
+                    .iter()
+                    .filter(|id| !after.hits.contains(id))
+                    .copied()
+                    .collect(),
+            })
+            .collect();
+        changed.sort_by(|a, b| a.ndcg_delta().total_cmp(&b.ndcg_delta()));
+
+        let matched = |report: &EvalReport| {
+            let metrics: Vec<Metrics> = report
+                .queries
+                .iter()
+                .filter(|query| !unmatched.contains(&query.query_id))
+                .map(|query| query.metrics)
+                .collect();
+            Metrics::mean(&metrics)
+        };
+        Ok(Comparison {
+            baseline: baseline.label.clone(),
+            candidate: candidate.label.clone(),
+            k: baseline.k,
+            baseline_mean: matched(baseline),
+            candidate_mean: matched(candidate),
+            unchanged: pairs.len() - changed.len(),
+            changed,
+            unmatched,
+        })
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "# {} vs {}\n", self.baseline, self.candidate).unwrap();
+        writeln!(
+            out,
+            "k = {}, {} queries changed, {} unchanged\n",
+            self.k,
+            self.changed.len(),
+            self.unchanged
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "| metric | {} | {} | delta |\n|---|---:|---:|---:|",
+            self.baseline, self.candidate
+        )
+        .unwrap();
+        for ((name, before), (_, after)) in self
+            .baseline_mean
+            .named()
+            .into_iter()
+            .zip(self.candidate_mean.named())
+        {
+            writeln!(
+                out,
+                "| {} | {:.4} | {:.4} | {:+.4} |",
+                name,
+                before,
+                after,
+                after - before
+            )
+            .unwrap();
+        }
+
+        if !self.changed.is_empty() {
+            writeln!(
+                out,
+                "\n| query | nDCG | delta | recall | delta | gained | lost |\n\
+                 |---|---:|---:|---:|---:|---|---|"
+            )
+            .unwrap();
+            let ids = |ids: &[i64]| {
+                ids.iter()
+                    .map(i64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            for diff in &self.changed {
+                writeln!(
+                    out,
+                    "| {} | {:.3} | {:+.3} | {:.3} | {:+.3} | {} | {} |",
+                    diff.query_id,
+                    diff.candidate.ndcg,
+                    diff.ndcg_delta(),
+                    diff.candidate.recall,
+                    diff.candidate.recall - diff.baseline.recall,
+                    ids(&diff.gained),
+                    ids(&diff.lost)
+                )
+                .unwrap();
+            }
+        }
+        if !self.unmatched.is_empty() {
+            writeln!(
+                out,
+                "\nOnly in one run, not compared: {}",
+                self.unmatched.join(", ")
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Markdown,
+}
+
+pub fn render<T: Serialize>(
+    value: &T,
+    markdown: impl Fn(&T) -> String,
+    format: ReportFormat,
+) -> Result<String, serde_json::Error> {
+    match format {
+        ReportFormat::Json => serde_json::to_string_pretty(value),
+        ReportFormat::Markdown => Ok(markdown(value)),
+    }
+}
+
+/// Retrieves the top `request.k` books for every judged query, text queries through the
+/// batch search path and seed queries through `similar_to`
+pub async fn run_evaluation(
+    pool: &PgPool,
+    schema: &Schema,
+    model: &EmbeddingModel,
+    judgments: &[Judgment],
+    request: &SearchRequest,
+    embedding: &EmbeddingConfig,
+) -> Result<Vec<Vec<i64>>, Box<dyn std::error::Error>> {
+    let text_queries: Vec<BatchQuery> = judgments
+        .iter()
+        .filter_map(|judgment| {
+            Some(BatchQuery {
+                id: judgment.id.clone(),
+                text: judgment.text.clone()?,
+                k: None,
+                filters: judgment.filters.clone(),
+            })
+        })
+        .collect();
+    let mut text_results: HashMap<String, Vec<i64>> = HashMap::new();
+    if !text_queries.is_empty() {
+        for results in run_queries(pool, schema, model, &text_queries, request, embedding).await? {
+            if let Some(error) = results.error {
+                return Err(format!("query {} failed: {}", results.query_id, error).into());
+            }
+            let ids = results.results.iter().map(|result| result.id).collect();
+            text_results.insert(results.query_id, ids);
+        }
+    }
+
+    let mut retrieved = Vec::with_capacity(judgments.len());
+    for judgment in judgments {
+        let ids = match judgment.seed {
+            Some(seed) if judgment.text.is_none() => {
+                let request = SearchRequest {
+                    filters: judgment.filters.clone(),
+                    ..request.clone()
+                };
+                similar_to(pool, schema, seed, &request)
+                    .await
+                    .map_err(|e| format!("query {} failed: {}", judgment.id, e))?
+                    .iter()
+                    .map(|result| result.id)
+                    .collect()
+            }
+            _ => text_results.remove(&judgment.id).unwrap_or_default(),
+        };
+        retrieved.push(ids);
+    }
+    Ok(retrieved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn books(ids: &[(i64, u32)]) -> Vec<RelevantBook> {
+        ids.iter()
+            .map(|&(id, grade)| RelevantBook { id, grade })
+            .collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // relevant books 1 (grade 3) and 2 (grade 1), retrieved at ranks 2 and 4
+        let relevant = books(&[(1, 3), (2, 1), (9, 0)]);
+        let metrics = evaluate(&[5, 1, 9, 2, 7], &relevant, 4);
+        assert_close(metrics.recall, 1.0);
+        assert_close(metrics.precision, 0.5);
+        assert_close(metrics.reciprocal_rank, 0.5);
+        assert_close(metrics.average_precision, (1.0 / 2.0 + 2.0 / 4.0) / 2.0);
+        let dcg = 7.0 / 3f64.log2() + 1.0 / 5f64.log2();
+        let ideal = 7.0 + 1.0 / 3f64.log2();
+        assert_close(metrics.ndcg, dcg / ideal);
+
+        // the cut-off drops book 2
+        let metrics = evaluate(&[5, 1, 9, 2, 7], &relevant, 2);
+        assert_close(metrics.recall, 0.5);
+        assert_close(metrics.precision, 0.5);
+
+        let perfect = evaluate(&[1, 2], &relevant, 2);
+        assert_close(perfect.ndcg, 1.0);
+        assert_close(perfect.average_precision, 1.0);
+        assert_eq!(evaluate(&[5, 6], &relevant, 2), Metrics::default());
+    }
+
+    #[test]
+    fn test_parse_judgments() {
+        let judgments = parse_judgments(
+            r#"{"id": "q1", "text": "whaling voyage", "relevant": [{"id": 2701, "grade": 3}, {"id": 15}]}
+{"id": "q2", "seed": 1342, "relevant": [{"id": 161}], "filters": {"languages": ["en"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(judgments[0].relevant[1].grade, 1);
+        assert_eq!(judgments[1].seed, Some(1342));
+        assert_eq!(judgments[1].describe(), "similar to 1342");
+
+        assert!(parse_judgments(r#"{"id": "q1", "relevant": [{"id": 1}]}"#).is_err());
+        assert!(
+            parse_judgments(r#"{"id": "q1", "text": "x", "seed": 1, "relevant": [{"id": 1}]}"#)
+                .is_err()
+        );
+        assert!(
+            parse_judgments(r#"{"id": "q1", "text": "x", "relevant": [{"id": 1, "grade": 0}]}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_compare_reports() {
+        let judgments = parse_judgments(
+            r#"{"id": "q1", "text": "whales", "relevant": [{"id": 1}, {"id": 2}]}
+{"id": "q2", "text": "ghosts", "relevant": [{"id": 3}]}
+{"id": "q3", "text": "pirates", "relevant": [{"id": 4}]}"#,
+        )
+        .unwrap();
+        let path = Path::new("judgments.jsonl");
+        let baseline = EvalReport::new(
+            "minilm",
+            "minilm",
+            2,
+            path,
+            "abc",
+            &judgments,
+            vec![vec![1, 8], vec![3, 9], vec![4, 7]],
+        );
+        let candidate = EvalReport::new(
+            "qwen3",
+            "qwen3",
+            2,
+            Path::new("copy/judgments.jsonl"),
+            "abc",
+            &judgments[..2],
+            vec![vec![2, 1], vec![9, 3]],
+        );
+        assert_close(baseline.mean.recall, (0.5 + 1.0 + 1.0) / 3.0);
+
+        let comparison = Comparison::new(&baseline, &candidate).unwrap();
+        // the same path with other content
+        let other = EvalReport {
+            judgments: baseline.judgments.clone(),
+            judgments_sha256: "def".to_string(),
+            ..candidate.clone()
+        };
+        assert!(Comparison::new(&baseline, &other).is_err());
+        assert_eq!(comparison.unmatched, vec!["q3"]);
+        assert_eq!(comparison.unchanged, 0);
+        // q2 got worse, so it comes first
+        assert_eq!(comparison.changed[0].query_id, "q2");
+        assert_eq!(comparison.changed[1].gained, vec![2]);
+        assert!(comparison.changed[1].lost.is_empty());
+        assert_close(comparison.baseline_mean.recall, 0.75);
+        assert_close(comparison.candidate_mean.recall, 1.0);
+
+        let markdown = comparison.to_markdown();
+        assert!(markdown.contains("| recall@k | 0.7500 | 1.0000 | +0.2500 |"));
+        assert!(markdown.contains("Only in one run, not compared: q3"));
+        assert!(baseline.to_markdown().contains("| q1 | 0.500 |"));
+
+        let json = serde_json::to_string(&baseline).unwrap();
+        let reloaded: EvalReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(reloaded.queries[0].hits, vec![1]);
+
+        let mut other_k = candidate.clone();
+        other_k.k = 5;
+        assert!(Comparison::new(&baseline, &other_k).is_err());
+    }
+}
@@ -0,0 +1,387 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::book_metadata::{BookFormat, BookMetadata};
+
+// Full texts come from a local mirror laid out like the RDF catalog, one directory per
+// book: <mirror>/1342/pg1342.txt next to <mirror>/1342/pg1342.rdf. The RDF lists each
+// rendition's URL, which is mapped onto that layout instead of downloading anything.
+
+/// Local file for a Gutenberg download URL, if the URL has a known layout.
+/// Handles /cache/epub/<id>/<file>, /files/<id>/<file> and the /ebooks/<id>.<format>
+/// redirect URLs, which point at the cache/epub files.
+pub fn mirror_path(mirror_dir: &Path, url: &str) -> Option<PathBuf> {
+    let path = url
+        .split_once("gutenberg.org/")
+        .map(|(_, path)| path)
+        .unwrap_or(url);
+    let parts: Vec<&str> = path.split('/').collect();
+
+    match parts.as_slice() {
+        ["cache", "epub", id, file] | ["files", id, file] => {
+            id.parse::<u32>().ok()?;
+            if file.is_empty() || file.starts_with('.') {
+                return None;
+            }
+            Some(mirror_dir.join(id).join(file))
+        }
+        ["ebooks", file] => {
+            let (id, format) = file.split_once('.')?;
+            id.parse::<u32>().ok()?;
+            let file_name = match format {
+                "txt.utf-8" => format!("pg{}.txt", id),
+                "html.images" => format!("pg{}-images.html", id),
+                "epub.images" => format!("pg{}-images.epub", id),
+                "epub3.images" => format!("pg{}-images-3.epub", id),
+                "epub.noimages" => format!("pg{}.epub", id),
+                _ => return None,
+            };
+            Some(mirror_dir.join(id).join(file_name))
+        }
+        _ => None,
+    }
+}
+
+/// Plain-text renditions of a book, UTF-8 first, skipping zipped copies
+pub fn plain_text_formats(metadata: &BookMetadata) -> Vec<&BookFormat> {
+    let mut formats: Vec<&BookFormat> = metadata
+        .formats
+        .iter()
+        .filter(|format| {
+            format.has_media_type("text/plain") && !format.has_media_type("application/zip")
+        })
+        .collect();
+    formats.sort_by_key(|format| !format.has_media_type("text/plain; charset=utf-8"));
+    formats
+}
+
+// Bytes 0x80-0x9F in Windows-1252, the rest of the upper half matches Latin-1
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// Decodes UTF-8 (dropping a byte order mark), falling back to Windows-1252, which also
+/// covers the Latin-1 and ASCII files in older parts of the collection.
+pub fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.trim_start_matches('\u{feff}').to_string(),
+        Err(_) => bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x80..=0x9f => WINDOWS_1252[(byte - 0x80) as usize],
+                _ => byte as char,
+            })
+            .collect(),
+    }
+}
+
+pub fn is_start_marker(line: &str) -> bool {
+    let upper = line.to_uppercase();
+    (upper.starts_with("*** START OF") || upper.starts_with("***START OF"))
+        && upper.contains("PROJECT GUTENBERG")
+        // pre-2000s files end their license header with this line instead
+        || upper.starts_with("*END*THE SMALL PRINT")
+}
+
+pub fn is_end_marker(line: &str) -> bool {
+    let upper = line.to_uppercase();
+    (upper.starts_with("*** END OF") || upper.starts_with("***END OF"))
+        && upper.contains("PROJECT GUTENBERG")
+        || upper.starts_with("END OF THE PROJECT GUTENBERG EBOOK")
+        || upper.starts_with("END OF PROJECT GUTENBERG")
+}
+
+/// The book itself, between the Gutenberg header and the license footer.
+/// Files without markers are returned whole.
+fn strip_boilerplate(text: &str) -> &str {
+    let mut start = 0;
+    let mut end = text.len();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if start == 0 && is_start_marker(trimmed) {
+            start = offset + line.len();
+        } else if is_end_marker(trimmed) {
+            end = offset;
+            break;
+        }
+        offset += line.len();
+    }
+    &text[start.min(end)..end]
+}
+
+fn is_credit(paragraph: &str) -> bool {
+    let lower = paragraph.to_lowercase();
+    lower.starts_with("produced by")
+        || lower.starts_with("e-text prepared by")
+        || lower.starts_with("this ebook was produced by")
+        || lower.contains("online distributed proofreading team")
+}
+
+fn is_transcriber_note(paragraph: &str) -> bool {
+    let lower = paragraph
+        .trim_start_matches(['[', '*', ' '])
+        .to_lowercase()
+        .replace('’', "'");
+    lower.starts_with("transcriber's note") || lower.starts_with("transcribers note")
+}
+
+pub fn is_contents_heading(paragraph: &str) -> bool {
+    matches!(
+        paragraph
+            .trim()
+            .trim_end_matches('.')
+            .to_lowercase()
+            .as_str(),
+        "contents" | "table of contents"
+    )
+}
+
+// Gutenberg wraps prose at ~70 columns, so a paragraph with a few long lines is body text.
+// Contents entries are short, even when they end in a page number.
+fn looks_like_prose(paragraph: &str) -> bool {
+    let lines: Vec<&str> = paragraph.lines().collect();
+    let long_lines = lines.iter().filter(|line| line.trim().len() > 55).count();
+    long_lines >= 2 || lines.iter().any(|line| line.trim().len() > 90)
+}
+
+// Neither a bracketed note nor a table of contents runs longer than this, past it the
+// end was missed and skipping on would drop the book itself
+const MAX_NOTE_PARAGRAPHS: usize = 10;
+const MAX_CONTENTS_PARAGRAPHS: usize = 100;
+
+/// Paragraphs after the opening one that belong to a bracketed transcriber's note,
+/// 0 if the closing bracket is not found
+fn note_length(rest: &[&str]) -> usize {
+    rest.iter()
+        .take(MAX_NOTE_PARAGRAPHS)
+        .position(|paragraph| paragraph.trim_end_matches(['.', ' ']).ends_with(']'))
+        .map_or(0, |position| position + 1)
+}
+
+/// Paragraphs after a contents heading that list the contents, 0 if their end is not found
+fn contents_length(rest: &[&str]) -> usize {
+    let first_entry = rest
+        .first()
+        .and_then(|entry| entry.lines().next())
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    // the contents end where the first listed chapter starts, e.g. "CHAPTER I."
+    // for an entry "CHAPTER I.  The Beginning  1" or "THE RAVEN" for "The Raven",
+    // or at the first prose
+    for (skipped, paragraph) in rest.iter().take(MAX_CONTENTS_PARAGRAPHS).enumerate() {
+        if looks_like_prose(paragraph) {
+            return skipped;
+        }
+        let heading = paragraph.lines().next().unwrap_or("").trim().to_lowercase();
+        if skipped > 0 && !heading.is_empty() && first_entry.starts_with(&heading) {
+            return skipped;
+        }
+    }
+    0
+}
+
+/// Drops credits, transcriber's notes and tables of contents from the book's paragraphs.
+fn strip_front_matter(paragraphs: Vec<&str>) -> Vec<&str> {
+    let mut kept = Vec::with_capacity(paragraphs.len());
+    let mut i = 0;
+    while i < paragraphs.len() {
+        let paragraph = paragraphs[i];
+        if is_credit(paragraph) {
+            i += 1;
+        } else if is_transcriber_note(paragraph) {
+            // bracketed notes can span several paragraphs
+            let closed = paragraph.trim_end_matches(['.', ' ']).ends_with(']');
+            if paragraph.trim_start().starts_with('[') && !closed {
+                i += note_length(&paragraphs[i + 1..]);
+            }
+            i += 1;
+        } else if is_contents_heading(paragraph) {
+            i += 1 + contents_length(&paragraphs[i + 1..]);
+        } else {
+            kept.push(paragraph);
+            i += 1;
+        }
+    }
+    kept
+}
+
+/// Joins hard-wrapped lines and collapses runs of whitespace within a paragraph
+pub fn normalize_paragraph(paragraph: &str) -> String {
+    paragraph
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Turns a raw Gutenberg plain-text file into the text worth embedding
+pub fn clean_gutenberg_text(raw: &str) -> String {
+    let text = raw.replace("\r\n", "\n").replace('\r', "\n");
+    let body = strip_boilerplate(&text);
+    let paragraphs: Vec<&str> = body
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect();
+
+    strip_front_matter(paragraphs)
+        .into_iter()
+        .map(normalize_paragraph)
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+/// Reads and cleans the first plain-text rendition of the book found in the mirror.
+/// Returns None if the RDF lists no plain text or none of it is mirrored.
+pub fn load_plain_text(
+    mirror_dir: &Path,
+    metadata: &BookMetadata,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    for format in plain_text_formats(metadata) {
+        let Some(path) = mirror_path(mirror_dir, &format.url) else {
+            continue;
+        };
+        if path.is_file() {
+            let bytes = fs::read(&path)?;
+            return Ok(Some(clean_gutenberg_text(&decode_text(&bytes))));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirror_path() {
+        let mirror = Path::new("data/cache/epub");
+        assert_eq!(
+            mirror_path(mirror, "https://www.gutenberg.org/ebooks/1342.txt.utf-8"),
+            Some(PathBuf::from("data/cache/epub/1342/pg1342.txt"))
+        );
+        assert_eq!(
+            mirror_path(
+                mirror,
+                "https://www.gutenberg.org/cache/epub/1342/pg1342-images.html"
+            ),
+            Some(PathBuf::from("data/cache/epub/1342/pg1342-images.html"))
+        );
+        assert_eq!(
+            mirror_path(mirror, "https://www.gutenberg.org/files/1342/1342-0.txt"),
+            Some(PathBuf::from("data/cache/epub/1342/1342-0.txt"))
+        );
+        assert_eq!(
+            mirror_path(mirror, "https://www.gutenberg.org/ebooks/1342.kf8.images"),
+            None
+        );
+        assert_eq!(
+            mirror_path(mirror, "https://www.gutenberg.org/files/../etc/passwd"),
+            None
+        );
+        assert_eq!(
+            mirror_path(mirror, "https://www.gutenberg.org/files/1342/.."),
+            None
+        );
+    }
+
+    #[test]
+    fn test_plain_text_formats_prefer_utf8() {
+        let format = |url: &str, media_types: &[&str]| BookFormat {
+            url: url.to_string(),
+            media_types: media_types.iter().map(|m| m.to_string()).collect(),
+        };
+        let metadata = BookMetadata {
+            formats: vec![
+                format("a.txt", &["text/plain; charset=us-ascii"]),
+                format(
+                    "a.zip",
+                    &["text/plain; charset=us-ascii", "application/zip"],
+                ),
+                format("a.html", &["text/html"]),
+                format("a-0.txt", &["text/plain; charset=utf-8"]),
+            ],
+            ..Default::default()
+        };
+        let urls: Vec<&str> = plain_text_formats(&metadata)
+            .iter()
+            .map(|format| format.url.as_str())
+            .collect();
+        assert_eq!(urls, vec!["a-0.txt", "a.txt"]);
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text("\u{feff}Café".as_bytes()), "Café");
+        assert_eq!(
+            decode_text(&[b'C', b'a', b'f', 0xe9, 0x20, 0x93, b'x', 0x94]),
+            "Café “x”"
+        );
+    }
+
+    #[test]
+    fn test_clean_gutenberg_text() {
+        let raw = "The Project Gutenberg eBook of Example\r\n\r\n\
+            This ebook is for the use of anyone anywhere.\r\n\r\n\
+            *** START OF THE PROJECT GUTENBERG EBOOK EXAMPLE ***\r\n\r\n\
+            Produced by Jane Doe and the Online Distributed\r\nProofreading Team\r\n\r\n\
+            [Transcriber's Note: Obvious typos\r\n\r\nhave been corrected.]\r\n\r\n\
+            CONTENTS\r\n\r\n\
+            CHAPTER I.    The Beginning      1\r\nCHAPTER II.   The End           9\r\n\r\n\
+            CHAPTER I.\r\n\r\n\
+            It was a dark and stormy night; the rain fell in torrents, except at\r\n\
+            occasional intervals,   when it was checked by a violent gust of wind\r\n\
+            which swept up the streets.\r\n\r\n\
+            *** END OF THE PROJECT GUTENBERG EBOOK EXAMPLE ***\r\n\r\n\
+            Section 1. General Terms of Use\r\n";
+
+        let cleaned = clean_gutenberg_text(raw);
+        assert_eq!(
+            cleaned,
+            "CHAPTER I.\n\nIt was a dark and stormy night; the rain fell in torrents, except at \
+             occasional intervals, when it was checked by a violent gust of wind which swept up \
+             the streets."
+        );
+    }
+
+    #[test]
+    fn test_strip_front_matter_is_bounded() {
+        // a poetry contents with short lines and headings in another case than the entries
+        let poems = "CONTENTS\n\n\
+            The Raven\nAnnabel Lee\n\n\
+            THE RAVEN\n\n\
+            Once upon a midnight dreary, while I pondered, weak and weary,\n\
+            Over many a quaint and curious volume of forgotten lore—\n\n\
+            ANNABEL LEE\n\n\
+            It was many and many a year ago,\nIn a kingdom by the sea,";
+        let cleaned = clean_gutenberg_text(poems);
+        assert!(cleaned.starts_with("THE RAVEN\n\nOnce upon a midnight dreary"));
+        assert!(cleaned.ends_with("In a kingdom by the sea,"));
+
+        // with no heading matching an entry, only the contents heading goes
+        let untitled = "Contents\n\nI\nII\n\nThe sea is calm tonight.\n\n\
+                        The tide is full, the moon lies fair";
+        assert_eq!(
+            clean_gutenberg_text(untitled),
+            "I II\n\nThe sea is calm tonight.\n\nThe tide is full, the moon lies fair"
+        );
+
+        // a note closed by "]." instead of "]" keeps the book after it
+        let noted = "[Transcriber's Note:\n\nSpelling is as in the original].\n\n\
+                     It is a truth universally acknowledged.";
+        assert_eq!(
+            clean_gutenberg_text(noted),
+            "It is a truth universally acknowledged."
+        );
+    }
+
+    #[test]
+    fn test_clean_gutenberg_text_without_markers() {
+        assert_eq!(
+            clean_gutenberg_text("Just a\nshort   text.\n\n\nSecond paragraph."),
+            "Just a short text.\n\nSecond paragraph."
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use sqlx::postgres::{PgPool, PgRow};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::embedding_models::DEFAULT_MODEL;
+use crate::schema::Schema;
+
+// An embedding run is recorded as a job so it can be picked up again after a crash.
+// Books are embedded in id order and progress is checkpointed after every batch, once the
+// batch's vectors are committed, so resuming only has to look at ids past the checkpoint.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    /// Stopped with Ctrl-C, resumable like a failed job
+    Interrupted,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Interrupted => "interrupted",
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "running" => Ok(JobStatus::Running),
+            "completed" => Ok(JobStatus::Completed),
+            "failed" => Ok(JobStatus::Failed),
+            "interrupted" => Ok(JobStatus::Interrupted),
+            _ => Err(format!("unknown job status {:?}", s)),
+        }
+    }
+}
+
+/// Everything needed to re-run a job with the same settings, stored as jsonb
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobParameters {
+    /// Name in the embedding model registry, selects the vector table
+    #[serde(default = "default_model_name")]
+    pub model_name: String,
+    pub model_path: String,
+    pub tokenizer_path: String,
+    pub batch_size: usize,
+    /// Embedding threads, each with its own model session
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// ONNX Runtime threads per worker session
+    #[serde(default = "default_intra_threads")]
+    pub intra_threads: usize,
+}
+
+fn default_model_name() -> String {
+    DEFAULT_MODEL.to_string()
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+// jobs created before the setting existed ran with 4
+fn default_intra_threads() -> usize {
+    4
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexingJob {
+    pub id: i64,
+    pub model: String,
+    pub model_fingerprint: String,
+    pub parameters: JobParameters,
+    pub status: JobStatus,
+    /// Every book up to this id has a vector or a recorded failure
+    pub last_processed_id: Option<i64>,
+    pub succeeded: i64,
+    pub failed: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobFailure {
+    pub book_id: i64,
+    pub error: String,
+}
+
+fn job_from_row(row: &PgRow) -> Result<IndexingJob, Box<dyn std::error::Error>> {
+    let parameters: serde_json::Value = row.try_get("parameters")?;
+    let status: String = row.try_get("status")?;
+    Ok(IndexingJob {
+        id: row.try_get("id")?,
+        model: row.try_get("model")?,
+        model_fingerprint: row.try_get("model_fingerprint")?,
+        parameters: serde_json::from_value(parameters)?,
+        status: status.parse()?,
+        last_processed_id: row.try_get("last_processed_id")?,
+        succeeded: row.try_get("succeeded")?,
+        failed: row.try_get("failed")?,
+    })
+}
+
+const JOB_COLUMNS: &str =
+    "id, model, model_fingerprint, parameters, status, last_processed_id, succeeded, failed";
+
+pub async fn create_job(
+    pool: &PgPool,
+    schema: &Schema,
+    parameters: &JobParameters,
+    model_fingerprint: &str,
+) -> Result<IndexingJob, Box<dyn std::error::Error>> {
+    let insert_string = format!(
+        "INSERT INTO {} (model, model_fingerprint, parameters) VALUES ($1, $2, $3) RETURNING {}",
+        schema.jobs, JOB_COLUMNS
+    );
+    let row = sqlx::query(insert_string.as_str())
+        .bind(&parameters.model_name)
+        .bind(model_fingerprint)
+        .bind(serde_json::to_value(parameters)?)
+        .fetch_one(pool)
+        .await?;
+    let job = job_from_row(&row)?;
+    println!("Started indexing job {}", job.id);
+    Ok(job)
+}
+
+pub async fn load_job(
+    pool: &PgPool,
+    schema: &Schema,
+    job_id: i64,
+) -> Result<IndexingJob, Box<dyn std::error::Error>> {
+    let query_string = format!("SELECT {} FROM {} WHERE id = $1", JOB_COLUMNS, schema.jobs);
+    let row = sqlx::query(query_string.as_str())
+        .bind(job_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| format!("no indexing job with id {}", job_id))?;
+    job_from_row(&row)
+}
+
+/// Most recent jobs first
+pub async fn list_jobs(
+    pool: &PgPool,
+    schema: &Schema,
+    limit: i64,
+) -> Result<Vec<IndexingJob>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT {} FROM {} ORDER BY id DESC LIMIT $1",
+        JOB_COLUMNS, schema.jobs
+    );
+    let rows = sqlx::query(query_string.as_str())
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    rows.iter().map(job_from_row).collect()
+}
+
+/// Records a processed batch in one transaction: failures are stored (replacing an older
+/// error for the same book), books that now succeeded are cleared from the failure list,
+/// and the counters and checkpoint are updated. Pass `last_processed_id = None` when
+/// retrying failures so the checkpoint does not move.
+pub async fn record_batch(
+    pool: &PgPool,
+    schema: &Schema,
+    job_id: i64,
+    last_processed_id: Option<i64>,
+    succeeded_ids: &[i64],
+    failures: &[JobFailure],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let failure_ids: Vec<i64> = failures.iter().map(|failure| failure.book_id).collect();
+    let errors: Vec<&str> = failures
+        .iter()
+        .map(|failure| failure.error.as_str())
+        .collect();
+
+    let mut transaction = pool.begin().await?;
+    let insert_string = format!(
+        "
+        INSERT INTO {} (job_id, book_id, error)
+        SELECT $1, * FROM UNNEST($2::bigint[], $3::text[])
+        ON CONFLICT (job_id, book_id) DO UPDATE SET error = excluded.error, failed_at = now()
+        ",
+        schema.job_failures
+    );
+    sqlx::query(insert_string.as_str())
+        .bind(job_id)
+        .bind(&failure_ids)
+        .bind(&errors)
+        .execute(&mut *transaction)
+        .await?;
+
+    let delete_string = format!(
+        "DELETE FROM {} WHERE job_id = $1 AND book_id = ANY($2)",
+        schema.job_failures
+    );
+    sqlx::query(delete_string.as_str())
+        .bind(job_id)
+        .bind(succeeded_ids)
+        .execute(&mut *transaction)
+        .await?;
+
+    let update_string = format!(
+        "
+        UPDATE {} SET
+            last_processed_id = coalesce($2, last_processed_id),
+            succeeded = succeeded + $3,
+            failed = (SELECT count(*) FROM {} WHERE job_id = $1),
+            updated_at = now()
+        WHERE id = $1
+        ",
+        schema.jobs, schema.job_failures
+    );
+    sqlx::query(update_string.as_str())
+        .bind(job_id)
+        .bind(last_processed_id)
+        .bind(succeeded_ids.len() as i64)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Also used to flip a failed or interrupted job back to running before resuming it
+pub async fn set_job_status(
+    pool: &PgPool,
+    schema: &Schema,
+    job_id: i64,
+    status: JobStatus,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let update_string = format!(
+        "
+        UPDATE {} SET status = $2, updated_at = now(),
+        finished_at = CASE WHEN $2 = 'running' THEN NULL ELSE now() END
+        WHERE id = $1
+        ",
+        schema.jobs
+    );
+    sqlx::query(update_string.as_str())
+        .bind(job_id)
+        .bind(status.as_str())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn job_failures(
+    pool: &PgPool,
+    schema: &Schema,
+    job_id: i64,
+) -> Result<Vec<JobFailure>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT book_id, error FROM {} WHERE job_id = $1 ORDER BY book_id",
+        schema.job_failures
+    );
+    let rows = sqlx::query(query_string.as_str())
+        .bind(job_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut failures = Vec::with_capacity(rows.len());
+    for row in rows {
+        failures.push(JobFailure {
+            book_id: row.try_get("book_id")?,
+            error: row.try_get("error")?,
+        });
+    }
+    Ok(failures)
+}
+
+pub fn print_job(job: &IndexingJob) {
+    println!(
+        "Job {} [{}] model {} — {} succeeded, {} failed, checkpoint {}",
+        job.id,
+        job.status,
+        job.model,
+        job.succeeded,
+        job.failed,
+        job.last_processed_id
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| "none".to_string())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_status_round_trip() {
+        for status in [
+            JobStatus::Running,
+            JobStatus::Completed,
+            JobStatus::Failed,
+            JobStatus::Interrupted,
+        ] {
+            assert_eq!(status.as_str().parse::<JobStatus>().unwrap(), status);
+        }
+        assert!("paused".parse::<JobStatus>().is_err());
+    }
+
+    #[test]
+    fn test_job_parameters_defaults() {
+        let parameters: JobParameters = serde_json::from_str(
+            r#"{"model_path": "model.onnx", "tokenizer_path": "tokenizer.json", "batch_size": 100}"#,
+        )
+        .unwrap();
+        assert_eq!(parameters.workers, 1);
+        assert_eq!(parameters.model_name, DEFAULT_MODEL);
+        assert_eq!(parameters.batch_size, 100);
+    }
+}
@@ -1,33 +1,39 @@
+mod ann_benchmark;
+mod batch;
 mod book_db_handler;
 mod book_metadata;
+mod bulk;
+mod chunks;
+mod cli;
+mod config;
+mod diversify;
+mod ebook_text;
+mod embedding_models;
+mod eval;
+mod gutenberg_text;
+mod jobs;
+mod migrations;
 mod models;
-
-use dotenv::dotenv;
-use std::env;
+mod output;
+mod pipeline;
+mod recommend;
+mod repl;
+mod schema;
+mod search;
+mod server;
+mod synthetic_judgments;
+mod vector_index;
+mod vector_store;
+
+use clap::Parser;
 
 use sqlx::postgres::PgPoolOptions;
 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> ort::Result<(), Box<dyn std::error::Error>> {
-    // let model_path = "/home/sand/coding/qwen3-test/model.onnx";
-    // let tokenizer_path = "/home/sand/coding/qwen3-test/tokenizer.json";
-    // models::get_model_info(model_path)?;
-
-    // let inputs = vec![
-    //     "Mr. and Mrs. Bennet live with their five daughters. Jane, the eldest daughter, falls in love with Charles Bingley, a rich bachelor who moves into a house nearby with his two sisters and friend, Fitzwilliam Darcy. Darcy is attracted to the second daughter, Elizabeth, but she finds him arrogant and self-centered. When Darcy proposes to Elizabeth, she refuses. But perhaps there is more to Darcy than meets the eye.",
-    //     "A woman and a man fall in love. The man's friend pursues the woman's younger sister, who does not like him at first.",
-    //     "A woman meets a man who she does not like at first, even though he likes her.",
-    //     "A dummy piece of text",
-    //     " Beautiful, clever, rich—and single—Emma Woodhouse is perfectly content with her life and sees no need for either love or marriage. Nothing, however, delights her more than interfering in the romantic lives of others. But when she ignores the warnings of her good friend Mr. Knightley and attempts to arrange a suitable match for her protegee Harriet Smith, her carefully laid plans soon unravel and have consequences that she never expected.",
-    //     "A man is exiled from his home, only to come back years later to take revenge.",
-    // ];
-    // models::query_model(model_path, tokenizer_path, inputs)?;
-    // rayon::ThreadPoolBuilder::new()
-    //     .num_threads(4)
-    //     .build_global()
-    //     .unwrap();
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = cli::Cli::parse();
 
     tracing_subscriber::registry()
         .with(
@@ -38,14 +44,20 @@ async fn main() -> ort::Result<(), Box<dyn std::error::Error>> {
         .init();
 
     dotenv::dotenv().ok();
-    let DATABASE_URL = env::var("DATABASE_URL")?;
+    let config = cli.resolve_config()?;
+    if cli.runs_in_memory(&config) {
+        return cli::run_in_memory(cli, &config).await;
+    }
+    // e.g. "postgres://postgres:@localhost/book_recommender"
+    let database_url = config
+        .database
+        .url
+        .as_deref()
+        .ok_or("no database url, set DATABASE_URL")?;
     let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&DATABASE_URL)
+        .max_connections(config.database.max_connections)
+        .connect(database_url)
         .await?;
-    // "postgres://postgres:@localhost/book_recommender")
 
-    // book_db_handler::set_up_metadata_table(&pool, "book_metadata").await?;
-    book_db_handler::set_up_vector_table(&pool, "book_summary_vectors").await?;
-    Ok(())
+    cli::run(cli, &config, &pool).await
 }
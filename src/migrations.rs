@@ -0,0 +1,112 @@
+use sqlx::Row;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+
+use crate::schema::quoted_identifier;
+
+// Embedded at compile time from ./migrations, add new files as NNNN_description.sql.
+// Never edit a migration that has been applied somewhere, sqlx checksums them.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub description: String,
+    pub installed_on: String,
+    pub success: bool,
+    pub execution_time_ms: i64,
+}
+
+/// Applies pending migrations. Already applied versions are skipped,
+/// so this is safe to call every time the program starts.
+pub async fn run_migrations(pool: &PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    MIGRATOR.run(pool).await?;
+    Ok(())
+}
+
+/// Applies the migrations inside a separate Postgres schema, e.g. for `Schema::in_namespace`.
+/// The namespace gets its own `_sqlx_migrations` history.
+pub async fn run_migrations_in_namespace(
+    pool: &PgPool,
+    namespace: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let create_string = format!(
+        "CREATE SCHEMA IF NOT EXISTS {}",
+        quoted_identifier(namespace)?
+    );
+    sqlx::query(create_string.as_str()).execute(pool).await?;
+
+    // public stays on the path so the vector type from the extension resolves
+    let options = pool
+        .connect_options()
+        .as_ref()
+        .clone()
+        .options([("search_path", format!("{},public", namespace))]);
+    let namespaced_pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    MIGRATOR.run(&namespaced_pool).await?;
+    namespaced_pool.close().await;
+    Ok(())
+}
+
+pub async fn applied_migrations(
+    pool: &PgPool,
+) -> Result<Vec<AppliedMigration>, Box<dyn std::error::Error>> {
+    let rows = sqlx::query(
+        "
+        SELECT version, description, installed_on::text AS installed_on, success,
+        execution_time / 1000000 AS execution_time_ms
+        FROM _sqlx_migrations ORDER BY version
+        ",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut applied = Vec::new();
+    for row in rows {
+        applied.push(AppliedMigration {
+            version: row.try_get("version")?,
+            description: row.try_get("description")?,
+            installed_on: row.try_get("installed_on")?,
+            success: row.try_get("success")?,
+            execution_time_ms: row.try_get("execution_time_ms")?,
+        });
+    }
+    Ok(applied)
+}
+
+/// Prints every known migration and whether it has been applied to this database.
+pub async fn print_migration_status(pool: &PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let applied = applied_migrations(pool).await?;
+    for migration in MIGRATOR.iter() {
+        match applied.iter().find(|a| a.version == migration.version) {
+            Some(a) => println!(
+                "{:04} {:<24} applied {} ({} ms){}",
+                a.version,
+                a.description,
+                a.installed_on,
+                a.execution_time_ms,
+                if a.success { "" } else { " FAILED" }
+            ),
+            None => println!(
+                "{:04} {:<24} pending",
+                migration.version, migration.description
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        let versions: Vec<i64> = MIGRATOR.iter().map(|m| m.version).collect();
+        assert!(!versions.is_empty());
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
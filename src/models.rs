@@ -1,12 +1,12 @@
-use ndarray::{ArrayBase, Axis, Dim, Ix2, ViewRepr};
+use ndarray::{Axis, Ix2};
 use ort::{
     Error,
-    session::{self, Session, builder::GraphOptimizationLevel},
+    session::{Session, builder::GraphOptimizationLevel},
     value::TensorRef,
 };
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::io::Read;
 use tokenizers::Tokenizer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 pub fn get_model_info(model_path: &str) -> ort::Result<()> {
     let session = Session::builder()?.commit_from_file(model_path)?;
@@ -133,30 +133,59 @@ fn prepare_tokenized_inputs(
 //     Ok(embeddings)
 // }
 
-pub fn ready_model(model_path: &str) -> Result<Session, Box<dyn std::error::Error>> {
-    // Initialize tracing to receive debug messages from `ort`
-    
+/// ONNX Runtime threads per session, `configured` or the cores split between `sessions`
+/// sessions running at once, so that several sessions do not oversubscribe the CPU
+pub fn intra_threads(configured: Option<usize>, sessions: usize) -> usize {
+    configured.unwrap_or_else(|| {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        (cores / sessions.max(1)).max(1)
+    })
+}
 
+pub fn ready_model(
+    model_path: &str,
+    intra_threads: usize,
+) -> Result<Session, Box<dyn std::error::Error>> {
     let session = Session::builder()?
         .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_intra_threads(4)?
+        .with_intra_threads(intra_threads)?
         .commit_from_file(model_path)?;
 
     Ok(session)
 }
 
-pub fn ready_tokenizer(tokenizer_path: &str) -> Tokenizer {
-    Tokenizer::from_file(tokenizer_path).unwrap()
+pub fn ready_tokenizer(tokenizer_path: &str) -> Result<Tokenizer, Box<dyn std::error::Error>> {
+    Tokenizer::from_file(tokenizer_path)
+        .map_err(|e| format!("could not load tokenizer {}: {}", tokenizer_path, e).into())
+}
+
+/// SHA-256 of the model file, stored next to each vector so a model swap
+/// marks every existing embedding as stale.
+pub fn model_fingerprint(model_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = std::fs::File::open(model_path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("sha256:{:x}", hasher.finalize()))
 }
 
+/// Each input text with its pooled embedding, in input order
+pub type TextEmbeddings = Vec<(String, Vec<f32>)>;
+
 pub fn query_model(
     session: &mut Session,
     tokenizer: &Tokenizer,
     inputs: Vec<&str>,
-) -> ort::Result<Vec<(String, Vec<f32>)>, Box<dyn std::error::Error>> {
+) -> Result<TextEmbeddings, Box<dyn std::error::Error>> {
     // Load the tokenizer and encode the text.
 
-    let (ids, mask, padded_token_length) = prepare_tokenized_inputs(&tokenizer, &inputs)?;
+    let (ids, mask, padded_token_length) = prepare_tokenized_inputs(tokenizer, &inputs)?;
     // println!(
     //     "Tokenized inputs: {:?}",
     //     (ids.clone(), mask.clone(), padded_token_length)
@@ -205,6 +234,19 @@ pub fn query_model(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_model_fingerprint() {
+        let path = std::env::temp_dir().join("book_recommender_fingerprint_test.onnx");
+        std::fs::write(&path, b"abc").unwrap();
+        let fingerprint = model_fingerprint(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            fingerprint,
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
     #[test]
     fn test_prepare_tokenized_inputs() {
         // Load tokenizer from file
@@ -244,7 +286,7 @@ mod tests {
                 );
 
                 // Verify that padding was applied (should have 0s for padding tokens)
-                let has_padding = ids.iter().any(|&id| id == 0) || mask.iter().any(|&m| m == 0);
+                let has_padding = ids.contains(&0) || mask.contains(&0);
                 println!(
                     "Test passed: max_length={}, has_padding={}",
                     max_length, has_padding
@@ -284,8 +326,8 @@ mod tests {
             "A man is exiled from his home, only to come back years later to take revenge.",
         ];
 
-        let mut session = ready_model(model_path).unwrap();
-        let tokenizer = ready_tokenizer(tokenizer_path);
+        let mut session = ready_model(model_path, intra_threads(None, 1)).unwrap();
+        let tokenizer = ready_tokenizer(tokenizer_path).unwrap();
 
         // Call the function - it should not panic and should return Ok
         let result = query_model(&mut session, &tokenizer, inputs.clone()).unwrap();
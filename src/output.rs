@@ -0,0 +1,391 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::book_db_handler::CatalogStats;
+use crate::chunks::ChunkSearchResult;
+use crate::search::SearchResult;
+
+// Field names in json, jsonl, csv and markdown output are part of the interface, scripts
+// and notebooks select columns by them. Add fields rather than renaming or removing them.
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns with long text truncated, for reading in a terminal
+    Table,
+    /// One JSON array
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// RFC 4180, with a header row
+    Csv,
+    /// A GitHub flavored markdown table
+    Markdown,
+}
+
+pub struct Column {
+    pub name: &'static str,
+    /// Longer values are truncated in table output only
+    pub max_width: usize,
+    /// Right aligned in table output
+    pub numeric: bool,
+}
+
+pub const fn column(name: &'static str, max_width: usize, numeric: bool) -> Column {
+    Column {
+        name,
+        max_width,
+        numeric,
+    }
+}
+
+/// A record that can be written in every `OutputFormat`. `cells` are in `COLUMNS` order
+/// and the column names match the serialized field names.
+pub trait OutputRow: Serialize {
+    const COLUMNS: &'static [Column];
+
+    fn cells(&self) -> Vec<String>;
+}
+
+/// One ranked book from search, similar or recommend
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResultRow {
+    /// 1 for the best match
+    pub rank: usize,
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    /// Cosine similarity, 1 - distance. Higher is better
+    pub score: f64,
+    pub summary: Option<String>,
+}
+
+impl ResultRow {
+    pub fn from_results(results: &[SearchResult]) -> Vec<ResultRow> {
+        results
+            .iter()
+            .enumerate()
+            .map(|(index, result)| ResultRow::new(index + 1, result))
+            .collect()
+    }
+
+    fn new(rank: usize, result: &SearchResult) -> Self {
+        ResultRow {
+            rank,
+            id: result.id,
+            title: result.title.clone(),
+            author: result.author.clone(),
+            score: 1.0 - result.distance,
+            summary: result.summary.clone(),
+        }
+    }
+}
+
+impl OutputRow for ResultRow {
+    const COLUMNS: &'static [Column] = &[
+        column("rank", 4, true),
+        column("id", 6, true),
+        column("title", 50, false),
+        column("author", 30, false),
+        column("score", 6, true),
+        column("summary", 60, false),
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.rank.to_string(),
+            self.id.to_string(),
+            self.title.clone(),
+            self.author.clone(),
+            format!("{:.4}", self.score),
+            self.summary.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+/// A `ResultRow` for a passage search, with the passage that matched best
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PassageRow {
+    pub rank: usize,
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    /// 1 - the aggregated passage distance
+    pub score: f64,
+    pub summary: Option<String>,
+    pub passage: String,
+    /// Character offset of `passage` in the book's text
+    pub passage_offset: i32,
+    pub matched_passages: usize,
+}
+
+impl PassageRow {
+    pub fn from_results(results: &[ChunkSearchResult]) -> Vec<PassageRow> {
+        results
+            .iter()
+            .enumerate()
+            .map(|(index, result)| {
+                let row = ResultRow::new(index + 1, &result.book);
+                PassageRow {
+                    rank: row.rank,
+                    id: row.id,
+                    title: row.title,
+                    author: row.author,
+                    score: row.score,
+                    summary: row.summary,
+                    passage: result.snippet.clone(),
+                    passage_offset: result.start_offset,
+                    matched_passages: result.matched_chunks,
+                }
+            })
+            .collect()
+    }
+}
+
+impl OutputRow for PassageRow {
+    const COLUMNS: &'static [Column] = &[
+        column("rank", 4, true),
+        column("id", 6, true),
+        column("title", 40, false),
+        column("author", 25, false),
+        column("score", 6, true),
+        column("summary", 40, false),
+        column("passage", 80, false),
+        column("passage_offset", 8, true),
+        column("matched_passages", 4, true),
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.rank.to_string(),
+            self.id.to_string(),
+            self.title.clone(),
+            self.author.clone(),
+            format!("{:.4}", self.score),
+            self.summary.clone().unwrap_or_default(),
+            self.passage.clone(),
+            self.passage_offset.to_string(),
+            self.matched_passages.to_string(),
+        ]
+    }
+}
+
+impl OutputRow for CatalogStats {
+    const COLUMNS: &'static [Column] = &[
+        column("books", 10, true),
+        column("deleted_books", 10, true),
+        column("books_with_summary", 10, true),
+        column("embedded_books", 10, true),
+        column("passages", 10, true),
+        column("books_with_passages", 10, true),
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        [
+            self.books,
+            self.deleted_books,
+            self.books_with_summary,
+            self.embedded_books,
+            self.passages,
+            self.books_with_passages,
+        ]
+        .iter()
+        .map(i64::to_string)
+        .collect()
+    }
+}
+
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+// Summaries and passages keep the line breaks of the source text
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn markdown_cell(value: &str) -> String {
+    single_line(value).replace('|', "\\|")
+}
+
+fn write_table<R: OutputRow>(out: &mut impl Write, rows: &[R]) -> io::Result<()> {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            row.cells()
+                .iter()
+                .zip(R::COLUMNS)
+                .map(|(cell, column)| truncate(&single_line(cell), column.max_width))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = R::COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(index, column)| {
+            cells
+                .iter()
+                .map(|row| row[index].chars().count())
+                .chain([column.name.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let line = |values: Vec<&str>| {
+        let padded: Vec<String> = values
+            .iter()
+            .zip(R::COLUMNS)
+            .zip(&widths)
+            .map(|((value, column), &width)| {
+                if column.numeric {
+                    format!("{:>width$}", value)
+                } else {
+                    format!("{:<width$}", value)
+                }
+            })
+            .collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    writeln!(out, "{}", line(R::COLUMNS.iter().map(|c| c.name).collect()))?;
+    for row in &cells {
+        writeln!(out, "{}", line(row.iter().map(String::as_str).collect()))?;
+    }
+    Ok(())
+}
+
+pub fn write_rows<R: OutputRow>(
+    out: &mut impl Write,
+    rows: &[R],
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Table => write_table(out, rows)?,
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(rows)?)?,
+        OutputFormat::Jsonl => {
+            for row in rows {
+                writeln!(out, "{}", serde_json::to_string(row)?)?;
+            }
+        }
+        OutputFormat::Csv => {
+            let header: Vec<&str> = R::COLUMNS.iter().map(|column| column.name).collect();
+            write!(out, "{}\r\n", header.join(","))?;
+            for row in rows {
+                let fields: Vec<String> = row.cells().iter().map(|cell| csv_field(cell)).collect();
+                write!(out, "{}\r\n", fields.join(","))?;
+            }
+        }
+        OutputFormat::Markdown => {
+            let header: Vec<&str> = R::COLUMNS.iter().map(|column| column.name).collect();
+            writeln!(out, "| {} |", header.join(" | "))?;
+            let rule: Vec<&str> = R::COLUMNS
+                .iter()
+                .map(|column| if column.numeric { "---:" } else { "---" })
+                .collect();
+            writeln!(out, "|{}|", rule.join("|"))?;
+            for row in rows {
+                let cells: Vec<String> =
+                    row.cells().iter().map(|cell| markdown_cell(cell)).collect();
+                writeln!(out, "| {} |", cells.join(" | "))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_rows<R: OutputRow>(
+    rows: &[R],
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_rows(&mut io::stdout().lock(), rows, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_rows() -> Vec<ResultRow> {
+        ResultRow::from_results(&[
+            SearchResult {
+                id: 1342,
+                title: "Pride and Prejudice".to_string(),
+                author: "Austen, Jane".to_string(),
+                birthyear: Some(1775),
+                deathyear: Some(1817),
+                summary: Some("Elizabeth Bennet meets \"Mr. Darcy\",\nwho is proud.".to_string()),
+                distance: 0.25,
+            },
+            SearchResult {
+                id: 84,
+                title: "Frankenstein | The Modern Prometheus".to_string(),
+                author: "Shelley, Mary Wollstonecraft".to_string(),
+                birthyear: Some(1797),
+                deathyear: Some(1851),
+                summary: None,
+                distance: 0.5,
+            },
+        ])
+    }
+
+    fn render(format: OutputFormat) -> String {
+        let mut out = Vec::new();
+        write_rows(&mut out, &sample_rows(), format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_machine_readable_formats() {
+        let json: serde_json::Value = serde_json::from_str(&render(OutputFormat::Json)).unwrap();
+        assert_eq!(json[0]["rank"], 1);
+        assert_eq!(json[0]["id"], 1342);
+        assert_eq!(json[0]["score"], 0.75);
+        assert_eq!(json[1]["summary"], serde_json::Value::Null);
+
+        let jsonl = render(OutputFormat::Jsonl);
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let second: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["title"], "Frankenstein | The Modern Prometheus");
+
+        let csv = render(OutputFormat::Csv);
+        let mut records = csv.split("\r\n");
+        assert_eq!(records.next(), Some("rank,id,title,author,score,summary"));
+        assert_eq!(
+            records.next(),
+            Some(
+                "1,1342,Pride and Prejudice,\"Austen, Jane\",0.7500,\
+                 \"Elizabeth Bennet meets \"\"Mr. Darcy\"\",\nwho is proud.\""
+            )
+        );
+    }
+
+    #[test]
+    fn test_human_readable_formats() {
+        let markdown = render(OutputFormat::Markdown);
+        let lines: Vec<&str> = markdown.lines().collect();
+        assert_eq!(lines[0], "| rank | id | title | author | score | summary |");
+        assert_eq!(lines[1], "|---:|---:|---|---|---:|---|");
+        assert!(lines[2].ends_with("| Elizabeth Bennet meets \"Mr. Darcy\", who is proud. |"));
+        assert!(lines[3].contains("Frankenstein \\| The Modern Prometheus"));
+
+        let table = render(OutputFormat::Table);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("rank    id  title"));
+        assert!(lines[1].starts_with("   1  1342  Pride and Prejudice"));
+        assert_eq!(truncate("Frankenstein", 6), "Frank…");
+    }
+}
@@ -0,0 +1,375 @@
+use ort::session::Session;
+use pgvector::Vector;
+use sqlx::postgres::PgPool;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc;
+
+use crate::book_db_handler::{PendingBook, books_by_id, pending_embeddings};
+use crate::bulk::VectorWriter;
+use crate::embedding_models::{EmbeddingModel, schema_for_model};
+use crate::jobs::{self, IndexingJob, JobFailure, JobStatus};
+use crate::models::{query_model, ready_model, ready_tokenizer};
+use crate::schema::Schema;
+
+// Three stages connected by bounded channels, so each one waits for the next instead of
+// buffering the whole catalog:
+//
+//   source (async, pages pending books out of Postgres)
+//     -> embedding workers (OS threads, one ONNX session each)
+//     -> writer (async, batched upserts + job checkpoint)
+//
+// On Ctrl-C the source stops, workers finish the book they are on, and the writer drains
+// and checkpoints what was embedded. The job can then be resumed.
+
+/// Which books a job run should embed
+pub enum BookSource {
+    /// Every pending book, in id order, after the given checkpoint
+    Pending { after_id: Option<i64> },
+    /// Exactly these books, e.g. a job's failures. Does not move the checkpoint.
+    Ids(Vec<i64>),
+}
+
+struct EmbeddedBook {
+    /// Position in the order the source sent books, used for checkpointing
+    seq: u64,
+    book_id: i64,
+    content_hash: String,
+    outcome: Result<Vector, String>,
+}
+
+/// Workers finish out of order, so the checkpoint may only move past a book once every
+/// book the source sent before it has been written.
+#[derive(Debug, Default)]
+struct Watermark {
+    next_seq: u64,
+    done: BTreeMap<u64, i64>,
+    checkpoint: Option<i64>,
+}
+
+impl Watermark {
+    fn complete(&mut self, seq: u64, book_id: i64) {
+        self.done.insert(seq, book_id);
+        while let Some(book_id) = self.done.remove(&self.next_seq) {
+            self.checkpoint = Some(book_id);
+            self.next_seq += 1;
+        }
+    }
+}
+
+// Writes are flushed when a batch is full or this long after its first book arrived
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+// Books fetched from Postgres per source query
+const PAGE_SIZE: i64 = 1000;
+
+fn embed_book(
+    session: &mut Session,
+    tokenizer: &Tokenizer,
+    book: &PendingBook,
+    dimensions: usize,
+) -> Result<Vector, String> {
+    let summary = vec![book.summary.as_str()];
+    let embedding = query_model(session, tokenizer, summary)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .next()
+        .map(|(_, vec)| vec)
+        .ok_or_else(|| "model returned no embedding".to_string())?;
+    // checked here so one bad vector fails its book rather than the whole batch insert
+    if embedding.len() != dimensions {
+        return Err(format!(
+            "model returned {} dimensions, the vector table expects {}",
+            embedding.len(),
+            dimensions
+        ));
+    }
+    Ok(Vector::from(embedding))
+}
+
+async fn produce(
+    pool: &PgPool,
+    schema: &Schema,
+    model_fingerprint: &str,
+    source: BookSource,
+    books: mpsc::Sender<(u64, PendingBook)>,
+    cancel: &AtomicBool,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut seq = 0;
+    match source {
+        BookSource::Pending { mut after_id } => loop {
+            let page =
+                pending_embeddings(pool, schema, model_fingerprint, after_id, Some(PAGE_SIZE))
+                    .await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after_id = Some(last.id);
+            for book in page {
+                if cancel.load(Ordering::Relaxed) || books.send((seq, book)).await.is_err() {
+                    return Ok(seq);
+                }
+                seq += 1;
+            }
+        },
+        BookSource::Ids(ids) => {
+            for book in books_by_id(pool, schema, &ids).await? {
+                if cancel.load(Ordering::Relaxed) || books.send((seq, book)).await.is_err() {
+                    return Ok(seq);
+                }
+                seq += 1;
+            }
+        }
+    }
+    Ok(seq)
+}
+
+fn embed_worker(
+    model_path: &str,
+    intra_threads: usize,
+    dimensions: usize,
+    tokenizer: &Tokenizer,
+    books: &Mutex<mpsc::Receiver<(u64, PendingBook)>>,
+    results: mpsc::Sender<EmbeddedBook>,
+    cancel: &AtomicBool,
+) -> Result<(), String> {
+    let mut session = ready_model(model_path, intra_threads).map_err(|e| e.to_string())?;
+    while !cancel.load(Ordering::Relaxed) {
+        // the lock is only held while waiting for the next book, not during inference
+        let next = books.lock().unwrap().blocking_recv();
+        let Some((seq, book)) = next else {
+            break;
+        };
+        let embedded = EmbeddedBook {
+            seq,
+            book_id: book.id,
+            outcome: embed_book(&mut session, tokenizer, &book, dimensions),
+            content_hash: book.content_hash,
+        };
+        if results.blocking_send(embedded).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn write_results(
+    pool: &PgPool,
+    schema: &Schema,
+    job: &IndexingJob,
+    mut results: mpsc::Receiver<EmbeddedBook>,
+    advance_checkpoint: bool,
+) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+    let batch_size = job.parameters.batch_size.max(1);
+    let mut writer = VectorWriter::new(pool, schema, batch_size, &job.model_fingerprint);
+    let mut watermark = Watermark::default();
+    let (mut succeeded, mut failed) = (0, 0);
+
+    while let Some(first) = results.recv().await {
+        let deadline = tokio::time::Instant::now() + FLUSH_INTERVAL;
+        let mut batch = vec![first];
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, results.recv()).await {
+                Ok(Some(embedded)) => batch.push(embedded),
+                _ => break,
+            }
+        }
+
+        let mut succeeded_ids = Vec::new();
+        let mut failures = Vec::new();
+        let mut completed = Vec::with_capacity(batch.len());
+        for embedded in batch {
+            completed.push((embedded.seq, embedded.book_id));
+            match embedded.outcome {
+                Ok(embedding) => {
+                    writer
+                        .push(embedded.book_id, embedding, embedded.content_hash)
+                        .await?;
+                    succeeded_ids.push(embedded.book_id);
+                }
+                Err(error) => failures.push(JobFailure {
+                    book_id: embedded.book_id,
+                    error,
+                }),
+            }
+        }
+        // vectors must be committed before the checkpoint moves past them
+        writer.flush().await?;
+        for (seq, book_id) in completed {
+            watermark.complete(seq, book_id);
+        }
+
+        let last_processed_id = watermark.checkpoint.filter(|_| advance_checkpoint);
+        jobs::record_batch(
+            pool,
+            schema,
+            job.id,
+            last_processed_id,
+            &succeeded_ids,
+            &failures,
+        )
+        .await?;
+        succeeded += succeeded_ids.len() as u64;
+        failed += failures.len() as u64;
+    }
+
+    writer.finish().await?;
+    Ok((succeeded, failed))
+}
+
+async fn run_stages(
+    pool: &PgPool,
+    schema: &Schema,
+    model: &EmbeddingModel,
+    job: &IndexingJob,
+    source: BookSource,
+    cancel: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let parameters = &job.parameters;
+    let workers = parameters.workers.max(1);
+    let advance_checkpoint = matches!(source, BookSource::Pending { .. });
+    // enough queued work to keep every worker busy while the source waits on Postgres
+    let (book_sender, book_receiver) = mpsc::channel(parameters.batch_size.max(1) * 2);
+    let (result_sender, result_receiver) = mpsc::channel(parameters.batch_size.max(1) * 2);
+
+    let tokenizer = Arc::new(ready_tokenizer(&parameters.tokenizer_path)?);
+    let book_receiver = Arc::new(Mutex::new(book_receiver));
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let model_path = parameters.model_path.clone();
+            let intra_threads = parameters.intra_threads.max(1);
+            let dimensions = model.dimensions as usize;
+            let tokenizer = Arc::clone(&tokenizer);
+            let book_receiver = Arc::clone(&book_receiver);
+            let result_sender = result_sender.clone();
+            let cancel = Arc::clone(&cancel);
+            thread::spawn(move || {
+                embed_worker(
+                    &model_path,
+                    intra_threads,
+                    dimensions,
+                    &tokenizer,
+                    &book_receiver,
+                    result_sender,
+                    &cancel,
+                )
+            })
+        })
+        .collect();
+    // the writer stops once every worker has dropped its sender
+    drop(result_sender);
+    drop(book_receiver);
+
+    let start = Instant::now();
+    let source_future = produce(
+        pool,
+        schema,
+        &job.model_fingerprint,
+        source,
+        book_sender,
+        &cancel,
+    );
+    let writer_future = write_results(pool, schema, job, result_receiver, advance_checkpoint);
+    let stages = async { tokio::join!(source_future, writer_future) };
+    tokio::pin!(stages);
+
+    let (sent, written) = tokio::select! {
+        outcome = &mut stages => outcome,
+        _ = tokio::signal::ctrl_c() => {
+            println!("Interrupted, finishing in-flight books before stopping");
+            cancel.store(true, Ordering::Relaxed);
+            stages.await
+        }
+    };
+
+    let mut worker_errors = Vec::new();
+    for handle in handles {
+        match handle.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => worker_errors.push(e),
+            Err(_) => worker_errors.push("embedding worker panicked".to_string()),
+        }
+    }
+
+    let sent = sent?;
+    let (succeeded, failed) = written?;
+    println!(
+        "Embedded {} of {} books ({} failed) with {} workers in {:.1}s",
+        succeeded,
+        sent,
+        failed,
+        workers,
+        start.elapsed().as_secs_f64()
+    );
+    if let Some(error) = worker_errors.first() {
+        return Err(format!(
+            "{} of {} workers failed: {}",
+            worker_errors.len(),
+            workers,
+            error
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Runs a job through the pipeline and records how it ended: completed, failed if a stage
+/// errored, or interrupted on Ctrl-C. Books whose embedding failed do not fail the job,
+/// they are listed for a retry.
+pub async fn run_indexing_job(
+    pool: &PgPool,
+    schema: &Schema,
+    job: &IndexingJob,
+    source: BookSource,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let outcome = match schema_for_model(pool, schema, &job.parameters.model_name).await {
+        Ok((model_schema, model)) => {
+            run_stages(
+                pool,
+                &model_schema,
+                &model,
+                job,
+                source,
+                Arc::clone(&cancel),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    let status = match (&outcome, cancel.load(Ordering::Relaxed)) {
+        (Err(_), _) => JobStatus::Failed,
+        (Ok(_), true) => JobStatus::Interrupted,
+        (Ok(_), false) => JobStatus::Completed,
+    };
+    jobs::set_job_status(pool, schema, job.id, status).await?;
+    jobs::print_job(&jobs::load_job(pool, schema, job.id).await?);
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watermark_waits_for_gaps() {
+        let mut watermark = Watermark::default();
+        assert_eq!(watermark.checkpoint, None);
+
+        // books 10, 11, 12 were sent in that order, 11 and 12 finish first
+        watermark.complete(1, 11);
+        watermark.complete(2, 12);
+        assert_eq!(watermark.checkpoint, None);
+
+        watermark.complete(0, 10);
+        assert_eq!(watermark.checkpoint, Some(12));
+
+        watermark.complete(4, 14);
+        assert_eq!(watermark.checkpoint, Some(12));
+        watermark.complete(3, 13);
+        assert_eq!(watermark.checkpoint, Some(14));
+    }
+}
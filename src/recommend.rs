@@ -0,0 +1,248 @@
+use serde::Deserialize;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+
+use crate::schema::Schema;
+use crate::search::{SearchRequest, SearchResult, report_missing_embeddings};
+use crate::vector_store::{PgVectorStore, VectorStore};
+
+/// A book from a reader's history. Weights scale how much the book pulls the profile,
+/// e.g. a star rating or a recency decay. Defaults to 1.0.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RatedBook {
+    pub id: i64,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+impl RatedBook {
+    pub fn new(id: i64) -> Self {
+        RatedBook { id, weight: 1.0 }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ReadingHistory {
+    pub liked: Vec<RatedBook>,
+    pub disliked: Vec<RatedBook>,
+}
+
+impl ReadingHistory {
+    fn read_ids(&self) -> Vec<i64> {
+        self.liked
+            .iter()
+            .chain(self.disliked.iter())
+            .map(|book| book.id)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ProfileStrategy {
+    /// One search with alpha * mean(liked) - beta * mean(disliked)
+    Rocchio { alpha: f32, beta: f32 },
+    /// One search per liked book, keeping each candidate's best weighted similarity.
+    /// Better for readers with several unrelated tastes, disliked books are only excluded.
+    MaxSimilarity,
+}
+
+impl Default for ProfileStrategy {
+    fn default() -> Self {
+        ProfileStrategy::Rocchio {
+            alpha: 1.0,
+            beta: 0.25,
+        }
+    }
+}
+
+fn weighted_centroid(vectors: &[(&[f32], f32)]) -> Option<Vec<f32>> {
+    let dimensions = vectors.first()?.0.len();
+    let total_weight: f32 = vectors.iter().map(|(_, weight)| weight).sum();
+    if total_weight == 0.0 {
+        return None;
+    }
+
+    let mut centroid = vec![0.0; dimensions];
+    for (vector, weight) in vectors {
+        for (c, v) in centroid.iter_mut().zip(vector.iter()) {
+            *c += v * weight / total_weight;
+        }
+    }
+    Some(centroid)
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+fn rocchio_profile(
+    liked: &[(&[f32], f32)],
+    disliked: &[(&[f32], f32)],
+    alpha: f32,
+    beta: f32,
+) -> Option<Vec<f32>> {
+    let mut profile: Vec<f32> = weighted_centroid(liked)?
+        .iter()
+        .map(|v| v * alpha)
+        .collect();
+    if let Some(negative) = weighted_centroid(disliked) {
+        for (p, n) in profile.iter_mut().zip(negative.iter()) {
+            *p -= beta * n;
+        }
+    }
+    // cosine distance ignores magnitude, but normalized profiles keep distances comparable
+    normalize(&mut profile);
+    Some(profile)
+}
+
+fn weighted_vectors<'a>(
+    books: &[RatedBook],
+    embeddings: &'a HashMap<i64, Vec<f32>>,
+) -> Vec<(&'a [f32], f32)> {
+    books
+        .iter()
+        .filter_map(|book| {
+            embeddings
+                .get(&book.id)
+                .map(|embedding| (embedding.as_slice(), book.weight))
+        })
+        .collect()
+}
+
+fn merge_max_similarity(result_sets: Vec<(Vec<SearchResult>, f32)>, k: usize) -> Vec<SearchResult> {
+    let mut best: HashMap<i64, (SearchResult, f64)> = HashMap::new();
+    for (results, weight) in result_sets {
+        for result in results {
+            let similarity = (1.0 - result.distance) * weight as f64;
+            match best.get(&result.id) {
+                Some((_, existing)) if *existing >= similarity => (),
+                _ => {
+                    best.insert(result.id, (result, similarity));
+                }
+            }
+        }
+    }
+
+    let mut merged: Vec<SearchResult> = best
+        .into_values()
+        .map(|(mut result, similarity)| {
+            result.distance = 1.0 - similarity;
+            result
+        })
+        .collect();
+    merged.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    merged.truncate(k);
+    merged
+}
+
+/// Recommends unread books from a reading history using the stored summary embeddings.
+/// Every book in the history is excluded from the results.
+pub async fn recommend_from_history(
+    pool: &PgPool,
+    schema: &Schema,
+    history: &ReadingHistory,
+    request: &SearchRequest,
+    strategy: ProfileStrategy,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let store = PgVectorStore::new(pool, schema);
+    recommend_in_store(&store, history, request, strategy).await
+}
+
+/// `recommend_from_history` against any `VectorStore`
+pub async fn recommend_in_store(
+    store: &impl VectorStore,
+    history: &ReadingHistory,
+    request: &SearchRequest,
+    strategy: ProfileStrategy,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let read_ids = history.read_ids();
+    let embeddings = store.embeddings(&read_ids).await?;
+    report_missing_embeddings(&read_ids, &embeddings);
+    let liked = weighted_vectors(&history.liked, &embeddings);
+    if liked.is_empty() {
+        return Err("none of the liked books have stored embeddings".into());
+    }
+
+    let mut request = request.clone();
+    request.filters.exclude_ids.extend(read_ids);
+
+    match strategy {
+        ProfileStrategy::Rocchio { alpha, beta } => {
+            let disliked = weighted_vectors(&history.disliked, &embeddings);
+            let profile = rocchio_profile(&liked, &disliked, alpha, beta)
+                .ok_or("liked book weights sum to zero")?;
+            store.search(&profile, &request).await
+        }
+        ProfileStrategy::MaxSimilarity => {
+            let mut result_sets = Vec::new();
+            for (embedding, weight) in liked {
+                let results = store.search(embedding, &request).await?;
+                result_sets.push((results, weight));
+            }
+            Ok(merge_max_similarity(result_sets, request.k as usize))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(id: i64, distance: f64) -> SearchResult {
+        SearchResult {
+            id,
+            title: format!("Book {}", id),
+            author: "Author".to_string(),
+            birthyear: None,
+            deathyear: None,
+            summary: None,
+            distance,
+        }
+    }
+
+    #[test]
+    fn test_weighted_centroid() {
+        let a = [1.0, 0.0];
+        let b = [0.0, 1.0];
+        let centroid = weighted_centroid(&[(&a, 3.0), (&b, 1.0)]).unwrap();
+        assert_eq!(centroid, vec![0.75, 0.25]);
+
+        assert!(weighted_centroid(&[]).is_none());
+        assert!(weighted_centroid(&[(&a, 0.0)]).is_none());
+    }
+
+    #[test]
+    fn test_rocchio_profile_moves_away_from_disliked() {
+        let liked = [1.0, 1.0];
+        let disliked = [0.0, 1.0];
+        let profile = rocchio_profile(&[(&liked, 1.0)], &[(&disliked, 1.0)], 1.0, 0.5).unwrap();
+
+        let norm: f32 = profile.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-6);
+        assert!(profile[0] > profile[1]);
+    }
+
+    #[test]
+    fn test_merge_max_similarity_keeps_best_score() {
+        let merged = merge_max_similarity(
+            vec![
+                (vec![result(1, 0.2), result(2, 0.5)], 1.0),
+                (vec![result(2, 0.1), result(3, 0.4)], 0.5),
+            ],
+            2,
+        );
+
+        let ids: Vec<i64> = merged.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        // book 2: max(1.0 * 0.5, 0.5 * 0.9) = 0.5
+        assert!((merged[1].distance - 0.5).abs() < 1e-9);
+    }
+}
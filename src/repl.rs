@@ -0,0 +1,382 @@
+use ort::session::Session;
+use pgvector::Vector;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use sqlx::postgres::PgPool;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+use tokenizers::Tokenizer;
+
+use crate::config::Config;
+use crate::embedding_models::schema_for_model;
+use crate::models::{intra_threads, query_model, ready_model, ready_tokenizer};
+use crate::output::{OutputFormat, ResultRow, write_rows};
+use crate::schema::Schema;
+use crate::search::{
+    SearchFilters, SearchRequest, SearchResult, explain_search, search_by_vector, similar_to,
+    year_range,
+};
+
+const HELP: &str = "\
+<text>             search summaries for a description
+:similar <id>      books similar to an embedded book
+:k <n>             number of results
+:filter            show the filters
+:filter key=value  author, born-after, born-before, died-after, died-before, lang, subject,
+                   bookshelf or exclude (comma separated ids). Years are inclusive.
+                   lang, subject and bookshelf add to the list, an empty value clears the key
+:filter clear      remove every filter
+:explain           query plan of the last text search
+:help              this text
+:quit              exit, as does ctrl-d";
+
+#[derive(Debug, PartialEq)]
+enum ReplCommand {
+    Search(String),
+    Similar(i64),
+    SetK(i64),
+    ShowFilters,
+    ClearFilters,
+    SetFilter(String, String),
+    Explain,
+    Help,
+    Quit,
+}
+
+/// None for a blank line
+fn parse_line(line: &str) -> Result<Option<ReplCommand>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let Some(command) = line.strip_prefix(':') else {
+        return Ok(Some(ReplCommand::Search(line.to_string())));
+    };
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (command, ""),
+    };
+    let command = match (name, argument) {
+        ("similar", id) => ReplCommand::Similar(
+            id.parse()
+                .map_err(|_| format!("expected a book id, got {:?}", id))?,
+        ),
+        ("k", k) => match k.parse() {
+            Ok(k) if k >= 1 => ReplCommand::SetK(k),
+            _ => return Err(format!("expected a positive number, got {:?}", k)),
+        },
+        ("filter", "") => ReplCommand::ShowFilters,
+        ("filter", "clear") => ReplCommand::ClearFilters,
+        ("filter", filter) => {
+            let (key, value) = filter
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {:?}", filter))?;
+            ReplCommand::SetFilter(key.trim().to_string(), value.trim().to_string())
+        }
+        ("explain", "") => ReplCommand::Explain,
+        ("help", "") => ReplCommand::Help,
+        ("quit" | "q" | "exit", "") => ReplCommand::Quit,
+        _ => return Err(format!("unknown command {:?}, see :help", line)),
+    };
+    Ok(Some(command))
+}
+
+/// The "in or after" and "in or before" bounds of a year range built by `year_range`
+fn year_bounds(range: Option<(i32, i32)>) -> (Option<i32>, Option<i32>) {
+    match range {
+        Some((start, end)) => (
+            (start != i32::MIN).then_some(start),
+            (end != i32::MAX).then_some(end),
+        ),
+        None => (None, None),
+    }
+}
+
+fn set_filter(filters: &mut SearchFilters, key: &str, value: &str) -> Result<(), String> {
+    let list = |list: &mut Vec<String>| {
+        if value.is_empty() {
+            list.clear();
+        } else {
+            list.push(value.to_string());
+        }
+    };
+    let year = || -> Result<Option<i32>, String> {
+        if value.is_empty() {
+            return Ok(None);
+        }
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("expected a year, got {:?}", value))
+    };
+    let (born_after, born_before) = year_bounds(filters.birthyear_range);
+    let (died_after, died_before) = year_bounds(filters.deathyear_range);
+    match key {
+        "author" => filters.author = (!value.is_empty()).then(|| value.to_string()),
+        "born-after" => filters.birthyear_range = year_range(year()?, born_before),
+        "born-before" => filters.birthyear_range = year_range(born_after, year()?),
+        "died-after" => filters.deathyear_range = year_range(year()?, died_before),
+        "died-before" => filters.deathyear_range = year_range(died_after, year()?),
+        "lang" => list(&mut filters.languages),
+        "subject" => list(&mut filters.subjects),
+        "bookshelf" => list(&mut filters.bookshelves),
+        "exclude" => {
+            filters.exclude_ids = value
+                .split(',')
+                .filter(|id| !id.trim().is_empty())
+                .map(|id| {
+                    id.trim()
+                        .parse()
+                        .map_err(|_| format!("expected book ids, got {:?}", id))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        _ => return Err(format!("unknown filter {:?}, see :help", key)),
+    }
+    Ok(())
+}
+
+fn describe_filters(filters: &SearchFilters) -> String {
+    let mut parts = Vec::new();
+    if let Some(author) = &filters.author {
+        parts.push(format!("author={}", author));
+    }
+    let (born_after, born_before) = year_bounds(filters.birthyear_range);
+    let (died_after, died_before) = year_bounds(filters.deathyear_range);
+    for (key, year) in [
+        ("born-after", born_after),
+        ("born-before", born_before),
+        ("died-after", died_after),
+        ("died-before", died_before),
+    ] {
+        parts.extend(year.map(|year| format!("{}={}", key, year)));
+    }
+    for (key, values) in [
+        ("lang", &filters.languages),
+        ("subject", &filters.subjects),
+        ("bookshelf", &filters.bookshelves),
+    ] {
+        parts.extend(values.iter().map(|value| format!("{}={}", key, value)));
+    }
+    if !filters.exclude_ids.is_empty() {
+        let ids: Vec<String> = filters.exclude_ids.iter().map(i64::to_string).collect();
+        parts.push(format!("exclude={}", ids.join(",")));
+    }
+    if parts.is_empty() {
+        "no filters".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+fn print_results(results: &[SearchResult]) -> Result<(), Box<dyn std::error::Error>> {
+    let rows = ResultRow::from_results(results);
+    write_rows(&mut io::stdout().lock(), &rows, OutputFormat::Table)
+}
+
+struct Repl<'a> {
+    pool: &'a PgPool,
+    schema: Schema,
+    session: Session,
+    tokenizer: Tokenizer,
+    request: SearchRequest,
+    /// Embedding and request of the last text search, for :explain
+    last_search: Option<(Vector, SearchRequest)>,
+}
+
+impl Repl<'_> {
+    /// Returns false once the user quits
+    async fn execute(&mut self, command: ReplCommand) -> Result<bool, Box<dyn std::error::Error>> {
+        match command {
+            ReplCommand::Search(text) => {
+                let started = Instant::now();
+                let embedding = Vector::from(
+                    query_model(&mut self.session, &self.tokenizer, vec![&text])?
+                        .into_iter()
+                        .next()
+                        .ok_or("model returned no embedding")?
+                        .1,
+                );
+                let embedded = started.elapsed();
+                let results =
+                    search_by_vector(self.pool, &self.schema, &embedding, &self.request).await?;
+                print_results(&results)?;
+                println!(
+                    "{} results, embedding {:.0?}, search {:.0?}",
+                    results.len(),
+                    embedded,
+                    started.elapsed() - embedded
+                );
+                self.last_search = Some((embedding, self.request.clone()));
+            }
+            ReplCommand::Similar(id) => {
+                let started = Instant::now();
+                let results = similar_to(self.pool, &self.schema, id, &self.request).await?;
+                print_results(&results)?;
+                println!("{} results in {:.0?}", results.len(), started.elapsed());
+            }
+            ReplCommand::SetK(k) => {
+                self.request.k = k;
+                println!("k = {}", k);
+            }
+            ReplCommand::ShowFilters => println!("{}", describe_filters(&self.request.filters)),
+            ReplCommand::ClearFilters => {
+                self.request.filters = SearchFilters::default();
+                println!("no filters");
+            }
+            ReplCommand::SetFilter(key, value) => {
+                set_filter(&mut self.request.filters, &key, &value)?;
+                println!("{}", describe_filters(&self.request.filters));
+            }
+            // explains the search as it ran, not with the k and filters set since
+            ReplCommand::Explain => match &self.last_search {
+                Some((embedding, request)) => {
+                    for line in explain_search(self.pool, &self.schema, embedding, request).await? {
+                        println!("{}", line);
+                    }
+                }
+                None => println!("Nothing to explain yet, search for something first"),
+            },
+            ReplCommand::Help => println!("{}", HELP),
+            ReplCommand::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Loads the model once and reads queries until :quit or ctrl-d. `filters` and
+/// `config.search` are the starting settings. Lines are kept in `history` across sessions.
+pub async fn run_repl(
+    pool: &PgPool,
+    schema: &Schema,
+    config: &Config,
+    filters: SearchFilters,
+    history: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (schema, model) = schema_for_model(pool, schema, &config.embedding.model).await?;
+    let mut repl = Repl {
+        pool,
+        schema,
+        session: ready_model(
+            &model.model_path,
+            intra_threads(config.embedding.intra_threads, 1),
+        )?,
+        tokenizer: ready_tokenizer(&model.tokenizer_path)?,
+        request: SearchRequest {
+            k: config.search.k,
+            filters,
+            ef_search: config.search.ef_search,
+            probes: config.search.probes,
+        },
+        last_search: None,
+    };
+
+    let mut editor = DefaultEditor::new()?;
+    if let Some(history) = history {
+        // there is no history file before the first session
+        editor.load_history(history).ok();
+    }
+    println!(
+        "{} loaded, k = {}, {}. :help lists the commands",
+        model.name,
+        repl.request.k,
+        describe_filters(&repl.request.filters)
+    );
+
+    loop {
+        let line = match editor.readline("books> ") {
+            Ok(line) => line,
+            // ctrl-c abandons the line being typed, ctrl-d quits
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        editor.add_history_entry(line.as_str())?;
+        let command = match parse_line(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        match repl.execute(command).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+
+    if let Some(history) = history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("   "), Ok(None));
+        assert_eq!(
+            parse_line(" whaling voyage "),
+            Ok(Some(ReplCommand::Search("whaling voyage".to_string())))
+        );
+        assert_eq!(
+            parse_line(":similar 1342"),
+            Ok(Some(ReplCommand::Similar(1342)))
+        );
+        assert_eq!(parse_line(":k 10"), Ok(Some(ReplCommand::SetK(10))));
+        assert!(parse_line(":k 0").is_err());
+        assert!(parse_line(":similar pride").is_err());
+        assert_eq!(
+            parse_line(":filter lang = en"),
+            Ok(Some(ReplCommand::SetFilter(
+                "lang".to_string(),
+                "en".to_string()
+            )))
+        );
+        assert_eq!(parse_line(":filter"), Ok(Some(ReplCommand::ShowFilters)));
+        assert_eq!(
+            parse_line(":filter clear"),
+            Ok(Some(ReplCommand::ClearFilters))
+        );
+        assert_eq!(parse_line(":explain"), Ok(Some(ReplCommand::Explain)));
+        assert_eq!(parse_line(":q"), Ok(Some(ReplCommand::Quit)));
+        assert!(parse_line(":explian").is_err());
+    }
+
+    #[test]
+    fn test_set_filter() {
+        let mut filters = SearchFilters::default();
+        set_filter(&mut filters, "lang", "en").unwrap();
+        set_filter(&mut filters, "lang", "fr").unwrap();
+        set_filter(&mut filters, "author", "austen").unwrap();
+        set_filter(&mut filters, "exclude", "1342, 158").unwrap();
+        set_filter(&mut filters, "born-after", "1750").unwrap();
+        set_filter(&mut filters, "born-before", "1800").unwrap();
+        assert_eq!(filters.languages, vec!["en", "fr"]);
+        assert_eq!(filters.exclude_ids, vec![1342, 158]);
+        assert_eq!(filters.birthyear_range, Some((1750, 1800)));
+        assert_eq!(
+            describe_filters(&filters),
+            "author=austen born-after=1750 born-before=1800 lang=en lang=fr exclude=1342,158"
+        );
+
+        set_filter(&mut filters, "born-after", "").unwrap();
+        assert_eq!(filters.birthyear_range, Some((i32::MIN, 1800)));
+        set_filter(&mut filters, "born-before", "").unwrap();
+        assert_eq!(filters.birthyear_range, None);
+        assert!(set_filter(&mut filters, "died-before", "victorian").is_err());
+
+        set_filter(&mut filters, "lang", "").unwrap();
+        set_filter(&mut filters, "author", "").unwrap();
+        assert!(filters.languages.is_empty());
+        assert_eq!(filters.author, None);
+        assert!(set_filter(&mut filters, "exclude", "pride").is_err());
+        assert!(set_filter(&mut filters, "year", "1813").is_err());
+    }
+}
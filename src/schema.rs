@@ -0,0 +1,219 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A validated, optionally schema-qualified Postgres table name.
+/// `Display` renders it quoted, so it can be interpolated into SQL with `format!`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TableName {
+    namespace: Option<String>,
+    name: String,
+}
+
+// Stricter than Postgres requires, but every table we create fits and it rules out
+// anything that could change the meaning of the surrounding statement.
+fn validate_identifier(identifier: &str) -> Result<(), String> {
+    let mut chars = identifier.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_');
+    let valid_rest = chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    // NAMEDATALEN - 1, longer identifiers are silently truncated by Postgres
+    if !valid_start || !valid_rest || identifier.len() > 63 {
+        return Err(format!(
+            "invalid identifier {:?}, expected 1-63 lowercase letters, digits or underscores",
+            identifier
+        ));
+    }
+    Ok(())
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Validates and quotes a standalone identifier, e.g. a schema name for CREATE SCHEMA
+pub fn quoted_identifier(identifier: &str) -> Result<String, String> {
+    validate_identifier(identifier)?;
+    Ok(quote_identifier(identifier))
+}
+
+impl TableName {
+    /// Accepts "table" or "namespace.table"
+    pub fn new(name: &str) -> Result<Self, String> {
+        match name.split_once('.') {
+            Some((namespace, table)) => Self::qualified(namespace, table),
+            None => {
+                validate_identifier(name)?;
+                Ok(TableName {
+                    namespace: None,
+                    name: name.to_string(),
+                })
+            }
+        }
+    }
+
+    pub fn qualified(namespace: &str, name: &str) -> Result<Self, String> {
+        validate_identifier(namespace)?;
+        validate_identifier(name)?;
+        Ok(TableName {
+            namespace: Some(namespace.to_string()),
+            name: name.to_string(),
+        })
+    }
+
+    /// Unquoted table name without the namespace, e.g. for pg_indexes.tablename
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Name for an object that lives next to this table, e.g. an index.
+    /// Postgres creates indexes in the table's namespace, so this is not qualified.
+    pub fn derived(&self, suffix: &str) -> String {
+        quote_identifier(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// Qualified name for an object that lives next to this table, for DROP and regclass lookups
+    pub fn derived_qualified(&self, suffix: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}.{}", quote_identifier(namespace), self.derived(suffix)),
+            None => self.derived(suffix),
+        }
+    }
+}
+
+impl fmt::Display for TableName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(namespace) = &self.namespace {
+            write!(f, "{}.", quote_identifier(namespace))?;
+        }
+        write!(f, "{}", quote_identifier(&self.name))
+    }
+}
+
+impl FromStr for TableName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TableName::new(s)
+    }
+}
+
+/// Every table the recommender reads or writes. Pass this to the db functions instead of
+/// hardcoding names, so tests and side-by-side experiments can use their own namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub metadata: TableName,
+    pub vectors: TableName,
+    pub contributors: TableName,
+    pub subjects: TableName,
+    /// Full-text passages and their embeddings, see `chunks`
+    pub chunks: TableName,
+    pub jobs: TableName,
+    pub job_failures: TableName,
+    pub models: TableName,
+    pub model_tables: TableName,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema {
+            metadata: TableName::new("book_metadata").unwrap(),
+            vectors: TableName::new("book_summary_vectors").unwrap(),
+            contributors: TableName::new("book_contributors").unwrap(),
+            subjects: TableName::new("book_subjects").unwrap(),
+            chunks: TableName::new("book_chunks").unwrap(),
+            jobs: TableName::new("indexing_jobs").unwrap(),
+            job_failures: TableName::new("indexing_job_failures").unwrap(),
+            models: TableName::new("embedding_models").unwrap(),
+            model_tables: TableName::new("model_vector_tables").unwrap(),
+        }
+    }
+}
+
+impl Schema {
+    /// The default tables inside a Postgres schema, e.g. "test_run" for throwaway test data.
+    /// Create them with `migrations::run_migrations_in_namespace`.
+    pub fn in_namespace(namespace: &str) -> Result<Self, String> {
+        let default = Schema::default();
+        Ok(Schema {
+            metadata: TableName::qualified(namespace, default.metadata.name())?,
+            vectors: TableName::qualified(namespace, default.vectors.name())?,
+            contributors: TableName::qualified(namespace, default.contributors.name())?,
+            subjects: TableName::qualified(namespace, default.subjects.name())?,
+            chunks: TableName::qualified(namespace, default.chunks.name())?,
+            jobs: TableName::qualified(namespace, default.jobs.name())?,
+            job_failures: TableName::qualified(namespace, default.job_failures.name())?,
+            models: TableName::qualified(namespace, default.models.name())?,
+            model_tables: TableName::qualified(namespace, default.model_tables.name())?,
+        })
+    }
+
+    /// Same tables, but reading and writing vectors in `vectors` and `chunks`,
+    /// e.g. another model's tables
+    pub fn with_vectors(&self, vectors: TableName, chunks: TableName) -> Self {
+        Schema {
+            vectors,
+            chunks,
+            ..self.clone()
+        }
+    }
+
+    /// A table next to the metadata table, in the same namespace
+    pub fn sibling(&self, name: &str) -> Result<TableName, String> {
+        match self.metadata.namespace() {
+            Some(namespace) => TableName::qualified(namespace, name),
+            None => TableName::new(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_name_quoting() {
+        let table = TableName::new("book_metadata").unwrap();
+        assert_eq!(table.to_string(), "\"book_metadata\"");
+        assert_eq!(table.namespace(), None);
+
+        let table: TableName = "test_run.book_summary_vectors".parse().unwrap();
+        assert_eq!(table.to_string(), "\"test_run\".\"book_summary_vectors\"");
+        assert_eq!(table.name(), "book_summary_vectors");
+        assert_eq!(
+            table.derived("embedding_hnsw_idx"),
+            "\"book_summary_vectors_embedding_hnsw_idx\""
+        );
+        assert_eq!(
+            table.derived_qualified("embedding_hnsw_idx"),
+            "\"test_run\".\"book_summary_vectors_embedding_hnsw_idx\""
+        );
+    }
+
+    #[test]
+    fn test_table_name_rejects_unsafe_identifiers() {
+        assert!(TableName::new("").is_err());
+        assert!(TableName::new("1books").is_err());
+        assert!(TableName::new("Books").is_err());
+        assert!(TableName::new("books; DROP TABLE book_metadata").is_err());
+        assert!(TableName::new("books\"").is_err());
+        assert!(TableName::new("a.b.c").is_err());
+        assert!(TableName::new(&"a".repeat(64)).is_err());
+        assert!(quoted_identifier("test run").is_err());
+        assert_eq!(quoted_identifier("test_run").unwrap(), "\"test_run\"");
+    }
+
+    #[test]
+    fn test_schema_in_namespace() {
+        let schema = Schema::in_namespace("test_run").unwrap();
+        assert_eq!(
+            schema.vectors.to_string(),
+            "\"test_run\".\"book_summary_vectors\""
+        );
+        assert!(Schema::in_namespace("test-run").is_err());
+    }
+}
@@ -0,0 +1,480 @@
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgConnection, PgPool, PgRow};
+use sqlx::{Postgres, QueryBuilder, Row};
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+use crate::models::query_model;
+use crate::schema::Schema;
+use crate::vector_store::{PgVectorStore, VectorStore};
+
+/// Metadata constraints applied alongside the vector ordering.
+/// Empty fields are ignored, list fields match if any of their entries match.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchFilters {
+    /// Case-insensitive substring match on the author name, e.g. "austen"
+    pub author: Option<String>,
+    /// Inclusive range, books with an unknown birth year are excluded when set
+    pub birthyear_range: Option<(i32, i32)>,
+    /// Inclusive range, books with an unknown death year are excluded when set
+    pub deathyear_range: Option<(i32, i32)>,
+    /// RFC 4646 codes as they appear in the RDF, e.g. "en", "fr"
+    pub languages: Vec<String>,
+    /// Case-insensitive substring match against LCSH headings
+    pub subjects: Vec<String>,
+    /// Case-insensitive substring match against Gutenberg bookshelves
+    pub bookshelves: Vec<String>,
+    pub exclude_ids: Vec<i64>,
+    /// Drops every edition of the given book's work, matched on author and normalized title
+    pub exclude_work_of: Option<i64>,
+}
+
+/// Builds an inclusive year range from optional "in or after" and "in or before" bounds,
+/// None when neither is set
+pub fn year_range(after: Option<i32>, before: Option<i32>) -> Option<(i32, i32)> {
+    if after.is_none() && before.is_none() {
+        return None;
+    }
+    Some((after.unwrap_or(i32::MIN), before.unwrap_or(i32::MAX)))
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchRequest {
+    pub k: i64,
+    pub filters: SearchFilters,
+    /// hnsw.ef_search for this query, pgvector defaults to 40. Must be at least k
+    /// for an HNSW index to return k rows without iterative scans.
+    pub ef_search: Option<i32>,
+    /// ivfflat.probes for this query, pgvector defaults to 1
+    pub probes: Option<i32>,
+}
+
+impl Default for SearchRequest {
+    fn default() -> Self {
+        SearchRequest {
+            k: 10,
+            filters: SearchFilters::default(),
+            ef_search: None,
+            probes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    pub birthyear: Option<i32>,
+    pub deathyear: Option<i32>,
+    pub summary: Option<String>,
+    /// Cosine distance, 0 is identical
+    pub distance: f64,
+}
+
+impl SearchResult {
+    pub fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        Ok(SearchResult {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            author: row.try_get("author")?,
+            birthyear: row.try_get("birthyear")?,
+            deathyear: row.try_get("deathyear")?,
+            summary: row.try_get("summary")?,
+            distance: row.try_get("distance")?,
+        })
+    }
+}
+
+// Gutenberg editions of the same work differ in subtitles ("Pride and Prejudice\nIllustrated")
+// and case, so compare the title up to the first line break, colon or semicolon.
+fn work_title_sql(column: &str) -> String {
+    format!(
+        "lower(trim(regexp_replace({}, '[:;\\r\\n].*$', '')))",
+        column
+    )
+}
+
+/// `work_title_sql` for titles already in memory
+pub fn work_title(title: &str) -> String {
+    let end = title.find([':', ';', '\r', '\n']).unwrap_or(title.len());
+    title[..end].trim().to_lowercase()
+}
+
+/// Appends the WHERE clause for `filters`, the query must alias the metadata table as `m`.
+/// Author, subject and bookshelf filters are case-insensitive substring matches, with `%`
+/// and `_` taken literally as in `vector_store`.
+pub fn push_filters(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    schema: &Schema,
+    filters: &SearchFilters,
+) {
+    builder.push(" WHERE m.deleted_at IS NULL");
+
+    if let Some(author) = &filters.author {
+        builder.push(" AND strpos(lower(m.author), lower(");
+        builder.push_bind(author.clone());
+        builder.push(")) > 0");
+    }
+    if let Some((start, end)) = filters.birthyear_range {
+        builder.push(" AND m.birthyear BETWEEN ");
+        builder.push_bind(start);
+        builder.push(" AND ");
+        builder.push_bind(end);
+    }
+    if let Some((start, end)) = filters.deathyear_range {
+        builder.push(" AND m.deathyear BETWEEN ");
+        builder.push_bind(start);
+        builder.push(" AND ");
+        builder.push_bind(end);
+    }
+    if !filters.languages.is_empty() {
+        builder.push(" AND m.languages && ");
+        builder.push_bind(filters.languages.clone());
+    }
+    for (scheme, headings) in [
+        ("LCSH", &filters.subjects),
+        ("bookshelf", &filters.bookshelves),
+    ] {
+        if headings.is_empty() {
+            continue;
+        }
+        builder.push(format!(
+            " AND EXISTS (SELECT 1 FROM {} s, unnest(",
+            schema.subjects
+        ));
+        builder.push_bind(headings.clone());
+        builder.push(format!(
+            "::text[]) q WHERE s.book_id = m.id AND s.scheme = '{}' AND strpos(lower(s.heading), lower(q)) > 0)",
+            scheme
+        ));
+    }
+    if !filters.exclude_ids.is_empty() {
+        builder.push(" AND m.id <> ALL(");
+        builder.push_bind(filters.exclude_ids.clone());
+        builder.push(")");
+    }
+    if let Some(seed_id) = filters.exclude_work_of {
+        builder.push(format!(
+            " AND NOT EXISTS (SELECT 1 FROM {} seed WHERE seed.id = ",
+            schema.metadata
+        ));
+        builder.push_bind(seed_id);
+        builder.push(format!(
+            " AND seed.author = m.author AND {} = {})",
+            work_title_sql("seed.title"),
+            work_title_sql("m.title")
+        ));
+    }
+}
+
+fn build_search_query<'a>(
+    schema: &Schema,
+    embedding: &Vector,
+    request: &SearchRequest,
+) -> QueryBuilder<'a, Postgres> {
+    search_query("", schema, embedding, request)
+}
+
+// The inner query is materialized and re-sorted because relaxed_order iterative
+// scans can return rows slightly out of distance order.
+fn search_query<'a>(
+    prefix: &str,
+    schema: &Schema,
+    embedding: &Vector,
+    request: &SearchRequest,
+) -> QueryBuilder<'a, Postgres> {
+    let mut builder = QueryBuilder::new(format!(
+        "{}
+        WITH candidates AS MATERIALIZED (
+            SELECT m.id, m.title, m.author, m.birthyear, m.deathyear, m.summary,
+            v.embedding <=> ",
+        prefix
+    ));
+    builder.push_bind(embedding.clone());
+    builder.push(format!(
+        " AS distance
+            FROM {} v
+            JOIN {} m ON m.id = v.id",
+        schema.vectors, schema.metadata
+    ));
+    push_filters(&mut builder, schema, &request.filters);
+    builder.push(" ORDER BY v.embedding <=> $1 LIMIT ");
+    builder.push_bind(request.k);
+    builder.push(
+        "
+        )
+        SELECT * FROM candidates ORDER BY distance",
+    );
+    builder
+}
+
+/// pgvector's hnsw.ef_search default and upper bound
+const DEFAULT_EF_SEARCH: i32 = 40;
+const MAX_EF_SEARCH: i32 = 1000;
+
+/// hnsw.ef_search for a query returning `rows` rows. An HNSW scan returns at most
+/// ef_search rows, so it is raised to `rows` when the configured value, or pgvector's
+/// default when none is configured, is smaller. None keeps the default.
+pub fn scan_ef_search(request: &SearchRequest, rows: i64) -> Option<i32> {
+    let rows = rows.clamp(1, MAX_EF_SEARCH as i64) as i32;
+    match request.ef_search {
+        Some(ef_search) => Some(ef_search.max(rows)),
+        None if rows > DEFAULT_EF_SEARCH => Some(rows),
+        None => None,
+    }
+}
+
+/// The SET LOCAL statements of `configure_scan`, also used by the ANN benchmark
+pub fn scan_settings(request: &SearchRequest, rows: i64) -> Vec<String> {
+    // Without iterative scans an ANN index returns its ef_search/probes candidates
+    // and the WHERE clause is applied afterwards, so filters, including the
+    // deleted_at check every query has, would come back with fewer rows than asked
+    // for. Requires pgvector >= 0.8.
+    let mut settings = vec![
+        "SET LOCAL hnsw.iterative_scan = relaxed_order".to_string(),
+        "SET LOCAL ivfflat.iterative_scan = relaxed_order".to_string(),
+    ];
+    // SET does not take bind parameters, the values are integers so formatting is safe
+    if let Some(ef_search) = scan_ef_search(request, rows) {
+        settings.push(format!("SET LOCAL hnsw.ef_search = {}", ef_search));
+    }
+    if let Some(probes) = request.probes {
+        settings.push(format!("SET LOCAL ivfflat.probes = {}", probes));
+    }
+    settings
+}
+
+/// Per-transaction ANN settings for `request`, run before a search query returning `rows` rows
+pub async fn configure_scan(
+    connection: &mut PgConnection,
+    request: &SearchRequest,
+    rows: i64,
+) -> Result<(), sqlx::Error> {
+    for setting in scan_settings(request, rows) {
+        sqlx::query(setting.as_str())
+            .execute(&mut *connection)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn search_by_vector(
+    pool: &PgPool,
+    schema: &Schema,
+    embedding: &Vector,
+    request: &SearchRequest,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let mut transaction = pool.begin().await?;
+    configure_scan(&mut transaction, request, request.k).await?;
+
+    let rows = build_search_query(schema, embedding, request)
+        .build()
+        .fetch_all(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    let results = rows
+        .iter()
+        .map(SearchResult::from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(results)
+}
+
+/// The plan `search_by_vector` gets for `request`, executed with the same scan settings.
+/// Shows whether the ANN index is used and how many candidates the filters discard.
+pub async fn explain_search(
+    pool: &PgPool,
+    schema: &Schema,
+    embedding: &Vector,
+    request: &SearchRequest,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut transaction = pool.begin().await?;
+    configure_scan(&mut transaction, request, request.k).await?;
+
+    let rows = search_query("EXPLAIN (ANALYZE, BUFFERS)", schema, embedding, request)
+        .build()
+        .fetch_all(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    let plan = rows
+        .iter()
+        .map(|row| row.try_get(0))
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(plan)
+}
+
+pub async fn search_text(
+    pool: &PgPool,
+    schema: &Schema,
+    session: &mut ort::session::Session,
+    tokenizer: &Tokenizer,
+    text: &str,
+    request: &SearchRequest,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let text_embedding = Vector::from(
+        query_model(session, tokenizer, vec![text])?
+            .first()
+            .ok_or("model returned no embedding")?
+            .1
+            .clone(),
+    );
+    search_by_vector(pool, schema, &text_embedding, request).await
+}
+
+/// Stored summary embeddings for the given ids, ids without one are left out
+pub async fn load_embeddings(
+    pool: &PgPool,
+    schema: &Schema,
+    ids: &[i64],
+) -> Result<HashMap<i64, Vec<f32>>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "SELECT id, embedding FROM {} WHERE id = ANY($1)",
+        schema.vectors
+    );
+    let rows = sqlx::query(query_string.as_str())
+        .bind(ids)
+        .fetch_all(pool)
+        .await?;
+
+    let mut embeddings = HashMap::new();
+    for row in rows {
+        let embedding: Vector = row.try_get("embedding")?;
+        embeddings.insert(row.try_get("id")?, embedding.to_vec());
+    }
+    Ok(embeddings)
+}
+
+pub fn report_missing_embeddings(ids: &[i64], embeddings: &HashMap<i64, Vec<f32>>) {
+    for id in ids {
+        if !embeddings.contains_key(id) {
+            println!("No stored embedding for book {}, ignoring it", id);
+        }
+    }
+}
+
+/// Stored summary embeddings for the given ids, missing ids are reported and skipped.
+pub async fn fetch_embeddings(
+    pool: &PgPool,
+    schema: &Schema,
+    ids: &[i64],
+) -> Result<HashMap<i64, Vec<f32>>, Box<dyn std::error::Error>> {
+    let embeddings = load_embeddings(pool, schema, ids).await?;
+    report_missing_embeddings(ids, &embeddings);
+    Ok(embeddings)
+}
+
+/// "More like this" for a book that is already embedded, reusing its stored summary vector.
+/// The seed book and other editions of the same work are excluded from the results.
+pub async fn similar_to(
+    pool: &PgPool,
+    schema: &Schema,
+    book_id: i64,
+    request: &SearchRequest,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    similar_in_store(&PgVectorStore::new(pool, schema), book_id, request).await
+}
+
+/// `similar_to` against any `VectorStore`
+pub async fn similar_in_store(
+    store: &impl VectorStore,
+    book_id: i64,
+    request: &SearchRequest,
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let embedding = store
+        .embeddings(&[book_id])
+        .await?
+        .remove(&book_id)
+        .ok_or_else(|| format!("no stored embedding for book {}", book_id))?;
+
+    let mut request = request.clone();
+    request.filters.exclude_ids.push(book_id);
+    request.filters.exclude_work_of = Some(book_id);
+    store.search(&embedding, &request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_search_query_without_filters() {
+        let request = SearchRequest::default();
+        let builder = build_search_query(&Schema::default(), &Vector::from(vec![0.0; 4]), &request);
+        let sql = builder.sql();
+
+        assert!(sql.contains("WHERE m.deleted_at IS NULL ORDER BY v.embedding <=> $1 LIMIT $2"));
+        assert!(!sql.contains("strpos"));
+
+        let builder = search_query(
+            "EXPLAIN ANALYZE",
+            &Schema::default(),
+            &Vector::from(vec![0.0; 4]),
+            &request,
+        );
+        assert!(builder.sql().trim_start().starts_with("EXPLAIN ANALYZE\n"));
+    }
+
+    #[test]
+    fn test_build_search_query_with_filters() {
+        let request = SearchRequest {
+            k: 5,
+            filters: SearchFilters {
+                author: Some("austen".to_string()),
+                birthyear_range: Some((1700, 1850)),
+                languages: vec!["fr".to_string()],
+                subjects: vec!["Love stories".to_string()],
+                exclude_ids: vec![1342],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let builder = build_search_query(&Schema::default(), &Vector::from(vec![0.0; 4]), &request);
+        let sql = builder.sql();
+
+        assert!(sql.contains("strpos(lower(m.author), lower($2)) > 0"));
+        assert!(sql.contains("m.birthyear BETWEEN $3 AND $4"));
+        assert!(sql.contains("m.languages && $5"));
+        assert!(sql.contains("FROM \"book_subjects\" s, unnest($6::text[]) q WHERE s.book_id = m.id AND s.scheme = 'LCSH' AND strpos(lower(s.heading), lower(q)) > 0"));
+        assert!(sql.contains("m.id <> ALL($7)"));
+        assert!(sql.contains("LIMIT $8"));
+        assert!(!sql.contains("deathyear BETWEEN"));
+    }
+
+    #[test]
+    fn test_build_search_query_excludes_same_work() {
+        let request = SearchRequest {
+            k: 5,
+            filters: SearchFilters {
+                exclude_ids: vec![1342],
+                exclude_work_of: Some(1342),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let builder = build_search_query(&Schema::default(), &Vector::from(vec![0.0; 4]), &request);
+        let sql = builder.sql();
+
+        assert!(sql.contains("m.id <> ALL($2)"));
+        assert!(
+            sql.contains("NOT EXISTS (SELECT 1 FROM \"book_metadata\" seed WHERE seed.id = $3")
+        );
+        assert!(sql.contains("regexp_replace(seed.title, '[:;\\r\\n].*$', '')"));
+        assert!(sql.contains("LIMIT $4"));
+    }
+
+    #[test]
+    fn test_scan_ef_search() {
+        let mut request = SearchRequest::default();
+        assert_eq!(scan_ef_search(&request, 10), None);
+        assert_eq!(scan_ef_search(&request, 100), Some(100));
+        assert_eq!(scan_ef_search(&request, 5000), Some(1000));
+        request.ef_search = Some(200);
+        assert_eq!(scan_ef_search(&request, 10), Some(200));
+        request.ef_search = Some(20);
+        assert_eq!(scan_ef_search(&request, 50), Some(50));
+    }
+}
@@ -0,0 +1,485 @@
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use ort::session::Session;
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokenizers::Tokenizer;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::book_db_handler::{BookDetail, book_detail};
+use crate::config::Config;
+use crate::diversify::{MmrOptions, search_diverse};
+use crate::embedding_models::{EmbeddingModel, schema_for_model};
+use crate::models::{intra_threads, query_model, ready_model, ready_tokenizer};
+use crate::recommend::{ProfileStrategy, RatedBook, ReadingHistory, recommend_from_history};
+use crate::schema::Schema;
+use crate::search::{
+    SearchFilters, SearchRequest, SearchResult, fetch_embeddings, search_by_vector, similar_to,
+};
+
+// JSON API for the web front end:
+//
+//   GET  /health         database reachable, model loaded
+//   GET  /books/{id}     BookDetail
+//   POST /search         SearchBody -> ResultsResponse
+//   POST /similar        SimilarBody -> ResultsResponse
+//   POST /recommend      RecommendBody -> ResultsResponse
+//
+// Errors are {"error": "..."} with a 4xx or 5xx status.
+
+/// Upper bound on `k` for every endpoint
+pub const MAX_RESULTS: i64 = 100;
+
+/// Model sessions shared by request handlers. Inference runs on the blocking thread pool,
+/// one request per session, and further requests wait for a free session.
+pub struct SessionPool {
+    sessions: Mutex<Vec<Session>>,
+    permits: Arc<Semaphore>,
+    tokenizer: Tokenizer,
+}
+
+impl SessionPool {
+    pub fn new(
+        model: &EmbeddingModel,
+        size: usize,
+        intra_threads: usize,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+        let sessions = (0..size)
+            .map(|_| ready_model(&model.model_path, intra_threads))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Arc::new(SessionPool {
+            sessions: Mutex::new(sessions),
+            permits: Arc::new(Semaphore::new(size)),
+            tokenizer: ready_tokenizer(&model.tokenizer_path)?,
+        }))
+    }
+
+    pub async fn embed(self: &Arc<Self>, text: String) -> Result<Vector, String> {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .map_err(|e| e.to_string())?;
+        let pool = Arc::clone(self);
+        // The session goes back and the permit is released from the blocking task, so a
+        // request that times out mid-inference neither loses its session nor frees its
+        // slot before the inference is actually done.
+        tokio::task::spawn_blocking(move || {
+            let mut checked_out = CheckedOutSession::take(pool, permit);
+            let pool = &checked_out.pool;
+            let session = checked_out.session.as_mut().unwrap();
+            query_model(session, &pool.tokenizer, vec![&text])
+                .map_err(|e| e.to_string())
+                .and_then(|embeddings| {
+                    embeddings
+                        .into_iter()
+                        .next()
+                        .map(|(_, embedding)| Vector::from(embedding))
+                        .ok_or_else(|| "model returned no embedding".to_string())
+                })
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
+
+/// A session taken from the pool with the permit that allowed it. Dropping it, also while
+/// unwinding from a panic in the model, puts the session back before releasing the permit,
+/// so there is never a free permit without a free session.
+struct CheckedOutSession {
+    pool: Arc<SessionPool>,
+    session: Option<Session>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl CheckedOutSession {
+    fn take(pool: Arc<SessionPool>, permit: OwnedSemaphorePermit) -> Self {
+        let session = pool
+            .sessions
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit is only available while a session is free");
+        CheckedOutSession {
+            pool,
+            session: Some(session),
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for CheckedOutSession {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.pool.sessions.lock().unwrap().push(session);
+        }
+        // _permit is dropped after this, once the session is back
+    }
+}
+
+pub struct AppState {
+    pub pool: PgPool,
+    /// Tables of the served model, see `schema_for_model`
+    pub schema: Schema,
+    pub model: EmbeddingModel,
+    pub sessions: Arc<SessionPool>,
+    pub default_k: i64,
+    pub ef_search: Option<i32>,
+    pub probes: Option<i32>,
+    pub request_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// The detail goes to the log only, database and model errors are not for clients
+    fn internal(error: impl std::fmt::Display) -> Self {
+        tracing::error!("request failed: {}", error);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct ErrorBody {
+            error: String,
+        }
+        (
+            self.status,
+            Json(ErrorBody {
+                error: self.message,
+            }),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchBody {
+    /// Description of the book the reader is looking for
+    pub text: String,
+    pub k: Option<i64>,
+    #[serde(default)]
+    pub filters: SearchFilters,
+    /// Re-rank a larger candidate set so results are not all by one author or series
+    #[serde(default)]
+    pub diverse: bool,
+    /// With diverse, 1.0 ranks purely by similarity to the text, 0.0 purely by novelty
+    pub lambda: Option<f64>,
+    /// With diverse, at most this many books per author
+    pub max_per_author: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimilarBody {
+    pub id: i64,
+    pub k: Option<i64>,
+    #[serde(default)]
+    pub filters: SearchFilters,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecommendBody {
+    pub liked: Vec<RatedBook>,
+    #[serde(default)]
+    pub disliked: Vec<RatedBook>,
+    pub k: Option<i64>,
+    #[serde(default)]
+    pub filters: SearchFilters,
+    /// Max similarity to each liked book instead of one averaged profile
+    #[serde(default)]
+    pub per_book: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResultsResponse {
+    pub model: String,
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    pub model: String,
+    pub database: bool,
+}
+
+fn result_count(k: Option<i64>, default: i64) -> Result<i64, ApiError> {
+    let k = k.unwrap_or(default);
+    if (1..=MAX_RESULTS).contains(&k) {
+        Ok(k)
+    } else {
+        Err(ApiError::bad_request(format!(
+            "k must be between 1 and {}",
+            MAX_RESULTS
+        )))
+    }
+}
+
+impl AppState {
+    /// The request for `k` results with the configured index tuning
+    fn request(&self, k: i64, filters: SearchFilters) -> SearchRequest {
+        SearchRequest {
+            k,
+            filters,
+            ef_search: self.ef_search,
+            probes: self.probes,
+        }
+    }
+
+    fn results(&self, results: Vec<SearchResult>) -> Json<ResultsResponse> {
+        Json(ResultsResponse {
+            model: self.model.name.clone(),
+            results,
+        })
+    }
+}
+
+async fn health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthResponse>) {
+    let database = sqlx::query("SELECT 1").execute(&state.pool).await.is_ok();
+    let (status, code) = if database {
+        ("ok", StatusCode::OK)
+    } else {
+        ("unavailable", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    let body = HealthResponse {
+        status,
+        model: state.model.name.clone(),
+        database,
+    };
+    (code, Json(body))
+}
+
+async fn book(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<Json<BookDetail>, ApiError> {
+    book_detail(&state.pool, &state.schema, id)
+        .await
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("no book {}", id)))
+}
+
+async fn search(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<SearchBody>,
+) -> Result<Json<ResultsResponse>, ApiError> {
+    let k = result_count(body.k, state.default_k)?;
+    if body.text.trim().is_empty() {
+        return Err(ApiError::bad_request("text is empty"));
+    }
+    let diverse = match body.diverse {
+        true => Some(
+            MmrOptions::with_overrides(body.lambda, body.max_per_author)
+                .map_err(ApiError::bad_request)?,
+        ),
+        false if body.lambda.is_some() || body.max_per_author.is_some() => {
+            return Err(ApiError::bad_request(
+                "lambda and max_per_author need diverse",
+            ));
+        }
+        false => None,
+    };
+    let embedding = state
+        .sessions
+        .embed(body.text)
+        .await
+        .map_err(ApiError::internal)?;
+    let request = state.request(k, body.filters);
+    let results = if let Some(options) = diverse {
+        search_diverse(&state.pool, &state.schema, &embedding, &request, &options).await
+    } else {
+        search_by_vector(&state.pool, &state.schema, &embedding, &request).await
+    }
+    .map_err(ApiError::internal)?;
+    Ok(state.results(results))
+}
+
+async fn similar(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<SimilarBody>,
+) -> Result<Json<ResultsResponse>, ApiError> {
+    let k = result_count(body.k, state.default_k)?;
+    let embedded = fetch_embeddings(&state.pool, &state.schema, &[body.id])
+        .await
+        .map_err(ApiError::internal)?;
+    if embedded.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("book {} has no stored embedding", body.id),
+        ));
+    }
+    let request = state.request(k, body.filters);
+    let results = similar_to(&state.pool, &state.schema, body.id, &request)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(state.results(results))
+}
+
+async fn recommend(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<RecommendBody>,
+) -> Result<Json<ResultsResponse>, ApiError> {
+    let k = result_count(body.k, state.default_k)?;
+    let liked_ids: Vec<i64> = body.liked.iter().map(|book| book.id).collect();
+    let embedded = fetch_embeddings(&state.pool, &state.schema, &liked_ids)
+        .await
+        .map_err(ApiError::internal)?;
+    if embedded.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "none of the liked books have stored embeddings",
+        ));
+    }
+    let history = ReadingHistory {
+        liked: body.liked,
+        disliked: body.disliked,
+    };
+    let strategy = if body.per_book {
+        ProfileStrategy::MaxSimilarity
+    } else {
+        ProfileStrategy::default()
+    };
+    let request = state.request(k, body.filters);
+    let results = recommend_from_history(&state.pool, &state.schema, &history, &request, strategy)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(state.results(results))
+}
+
+async fn limit_time(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    match tokio::time::timeout(state.request_timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => ApiError::new(StatusCode::GATEWAY_TIMEOUT, "request timed out").into_response(),
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/books/{id}", get(book))
+        .route("/search", post(search))
+        .route("/similar", post(similar))
+        .route("/recommend", post(recommend))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            limit_time,
+        ))
+        .with_state(state)
+}
+
+/// Serves the JSON API for `config.embedding.model` until ctrl-c
+pub async fn serve(
+    pool: &PgPool,
+    schema: &Schema,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (schema, model) = schema_for_model(pool, schema, &config.embedding.model).await?;
+    let intra_threads = intra_threads(config.embedding.intra_threads, config.server.sessions);
+    let sessions = SessionPool::new(&model, config.server.sessions, intra_threads)?;
+    let state = Arc::new(AppState {
+        pool: pool.clone(),
+        schema,
+        model,
+        sessions,
+        default_k: config.search.k.min(MAX_RESULTS),
+        ef_search: config.search.ef_search,
+        probes: config.search.probes,
+        request_timeout: Duration::from_secs(config.server.request_timeout_secs),
+    });
+
+    let listener = tokio::net::TcpListener::bind(&config.server.address).await?;
+    println!(
+        "Serving {} on http://{} with {} model sessions",
+        state.model.name,
+        listener.local_addr()?,
+        config.server.sessions
+    );
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_bodies() {
+        let body: SearchBody = serde_json::from_str(
+            r#"{"text": "sailors and pirates", "filters": {"languages": ["en"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(body.k, None);
+        assert!(!body.diverse);
+        assert_eq!(body.filters.languages, vec!["en"]);
+
+        let body: SearchBody = serde_json::from_str(
+            r#"{"text": "sailors", "diverse": true, "lambda": 0.5, "max_per_author": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(body.lambda, Some(0.5));
+        assert_eq!(body.max_per_author, Some(1));
+
+        let body: RecommendBody =
+            serde_json::from_str(r#"{"liked": [{"id": 1342}, {"id": 158, "weight": 0.5}]}"#)
+                .unwrap();
+        assert_eq!(body.liked[0].weight, 1.0);
+        assert_eq!(body.liked[1].weight, 0.5);
+        assert!(body.disliked.is_empty());
+
+        // typos are rejected instead of silently searching without the filter
+        assert!(serde_json::from_str::<SearchBody>(r#"{"text": "x", "filter": {}}"#).is_err());
+        assert!(
+            serde_json::from_str::<SimilarBody>(r#"{"id": 1, "filters": {"langs": ["en"]}}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_result_count() {
+        assert_eq!(result_count(None, 10).unwrap(), 10);
+        assert_eq!(result_count(Some(25), 10).unwrap(), 25);
+        assert_eq!(
+            result_count(Some(0), 10).unwrap_err().status,
+            StatusCode::BAD_REQUEST
+        );
+        assert!(result_count(Some(MAX_RESULTS + 1), 10).is_err());
+    }
+
+    #[test]
+    fn test_internal_error_hides_detail() {
+        let error = ApiError::internal("relation \"books\" does not exist");
+        assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.message, "internal error");
+    }
+}
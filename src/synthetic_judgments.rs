@@ -0,0 +1,481 @@
+use sqlx::Row;
+use sqlx::postgres::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::eval::{Judgment, RelevantBook};
+use crate::schema::Schema;
+use crate::search::{SearchFilters, work_title};
+
+// Relevance judgments derived from the catalog instead of labeled by hand. For a seed book,
+// other books are relevant with a grade from the strongest link between them:
+//
+//   3  another volume of the same series (same author, same title apart from "Volume 2")
+//   2  the same author, or at least two shared LCSH subjects
+//   1  one shared LCSH subject, or a shared Gutenberg bookshelf
+//
+// Subjects and bookshelves with more than `max_group_size` books ("Fiction") say little
+// about similarity and are ignored. Other editions of the seed's own work are left out,
+// because `similar_to` never returns them.
+
+/// Authors that are placeholders rather than people
+const NON_AUTHORS: &[&str] = &["various", "anonymous", "unknown"];
+
+const VOLUME_MARKERS: &[&str] = &["volume", "vol", "v", "part", "book", "tome", "band"];
+
+const NUMBER_WORDS: &[&str] = &[
+    "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "first",
+    "second", "third", "fourth", "fifth", "sixth",
+];
+
+#[derive(Debug, Clone)]
+pub struct CatalogBook {
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    /// Full LCSH headings, e.g. "Whaling -- Fiction"
+    pub subjects: Vec<String>,
+    pub bookshelves: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntheticOptions {
+    /// Seed books to sample, fewer if the catalog runs out of seeds with enough relevant books
+    pub queries: usize,
+    /// Seeds with fewer relevant books are skipped
+    pub min_relevant: usize,
+    pub max_group_size: usize,
+    /// The same seed samples the same books from the same catalog
+    pub seed: u64,
+}
+
+impl Default for SyntheticOptions {
+    fn default() -> Self {
+        SyntheticOptions {
+            queries: 1000,
+            min_relevant: 3,
+            max_group_size: 500,
+            seed: 0,
+        }
+    }
+}
+
+fn is_volume_number(word: &str) -> bool {
+    let roman = !word.is_empty() && word.len() <= 6 && word.chars().all(|c| "ivxlc".contains(c));
+    word.chars().all(|c| c.is_ascii_digit()) && !word.is_empty()
+        || roman
+        || NUMBER_WORDS.contains(&word)
+}
+
+/// The title without its volume number, None if the title has none.
+/// "The History of the Decline and Fall of the Roman Empire — Volume 3" and
+/// "… Empire, Vol. II" both give "the history of the decline and fall of the roman empire".
+pub fn series_key(title: &str) -> Option<String> {
+    let lowered = title.to_lowercase();
+    let words: Vec<&str> = lowered
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let marker = words
+        .windows(2)
+        .position(|pair| VOLUME_MARKERS.contains(&pair[0]) && is_volume_number(pair[1]))?;
+    if marker == 0 {
+        return None;
+    }
+    Some(words[..marker].join(" "))
+}
+
+fn main_heading(subject: &str) -> &str {
+    subject.split(" -- ").next().unwrap_or(subject).trim()
+}
+
+// splitmix64, a stable shuffle that does not depend on a random number crate's version
+fn sample_order(seed: u64, id: i64) -> u64 {
+    let mut z = seed ^ (id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+struct CatalogIndex<'a> {
+    books: &'a [CatalogBook],
+    by_author: HashMap<&'a str, Vec<usize>>,
+    by_series: HashMap<(&'a str, String), Vec<usize>>,
+    by_subject: HashMap<&'a str, Vec<usize>>,
+    by_bookshelf: HashMap<&'a str, Vec<usize>>,
+}
+
+impl<'a> CatalogIndex<'a> {
+    fn new(books: &'a [CatalogBook]) -> Self {
+        let mut index = CatalogIndex {
+            books,
+            by_author: HashMap::new(),
+            by_series: HashMap::new(),
+            by_subject: HashMap::new(),
+            by_bookshelf: HashMap::new(),
+        };
+        for (position, book) in books.iter().enumerate() {
+            if !NON_AUTHORS.contains(&book.author.trim().to_lowercase().as_str()) {
+                index
+                    .by_author
+                    .entry(book.author.as_str())
+                    .or_default()
+                    .push(position);
+                if let Some(key) = series_key(&book.title) {
+                    index
+                        .by_series
+                        .entry((book.author.as_str(), key))
+                        .or_default()
+                        .push(position);
+                }
+            }
+            for subject in &book.subjects {
+                index
+                    .by_subject
+                    .entry(subject.as_str())
+                    .or_default()
+                    .push(position);
+            }
+            for bookshelf in &book.bookshelves {
+                index
+                    .by_bookshelf
+                    .entry(bookshelf.as_str())
+                    .or_default()
+                    .push(position);
+            }
+        }
+        index
+    }
+
+    fn relevant(&self, seed: &CatalogBook, max_group_size: usize) -> Vec<RelevantBook> {
+        let mut grades: HashMap<usize, u32> = HashMap::new();
+        let mut grade = |position: usize, grade: u32| {
+            let entry = grades.entry(position).or_default();
+            *entry = (*entry).max(grade);
+        };
+
+        if let Some(key) = series_key(&seed.title) {
+            for &position in self
+                .by_series
+                .get(&(seed.author.as_str(), key))
+                .into_iter()
+                .flatten()
+            {
+                grade(position, 3);
+            }
+        }
+        for &position in self
+            .by_author
+            .get(seed.author.as_str())
+            .into_iter()
+            .flatten()
+        {
+            grade(position, 2);
+        }
+        let mut shared_subjects: HashMap<usize, u32> = HashMap::new();
+        for subject in &seed.subjects {
+            let group = self
+                .by_subject
+                .get(subject.as_str())
+                .map_or(&[][..], Vec::as_slice);
+            if group.len() <= max_group_size {
+                for &position in group {
+                    *shared_subjects.entry(position).or_default() += 1;
+                }
+            }
+        }
+        for (position, shared) in shared_subjects {
+            grade(position, if shared >= 2 { 2 } else { 1 });
+        }
+        for bookshelf in &seed.bookshelves {
+            let group = self
+                .by_bookshelf
+                .get(bookshelf.as_str())
+                .map_or(&[][..], Vec::as_slice);
+            if group.len() <= max_group_size {
+                for &position in group {
+                    grade(position, 1);
+                }
+            }
+        }
+
+        let seed_work = work_title(&seed.title);
+        let mut relevant: Vec<RelevantBook> = grades
+            .into_iter()
+            .map(|(position, grade)| (&self.books[position], grade))
+            .filter(|(book, _)| {
+                book.id != seed.id
+                    && !(book.author == seed.author && work_title(&book.title) == seed_work)
+            })
+            .map(|(book, grade)| RelevantBook { id: book.id, grade })
+            .collect();
+        relevant.sort_by(|a, b| b.grade.cmp(&a.grade).then(a.id.cmp(&b.id)));
+        relevant
+    }
+}
+
+/// Samples seed books round-robin over their main LCSH heading, so small subjects are
+/// represented as well as large ones, and judges each seed from the catalog structure.
+/// Every book in `books` should be embedded, so that every relevant book can be retrieved.
+pub fn generate_judgments(books: &[CatalogBook], options: &SyntheticOptions) -> Vec<Judgment> {
+    let index = CatalogIndex::new(books);
+
+    // books without subjects form their own stratum
+    let mut strata: BTreeMap<&str, Vec<&CatalogBook>> = BTreeMap::new();
+    for book in books {
+        let stratum = book
+            .subjects
+            .first()
+            .map_or("", |subject| main_heading(subject));
+        strata.entry(stratum).or_default().push(book);
+    }
+    let mut strata: Vec<std::vec::IntoIter<&CatalogBook>> = strata
+        .into_values()
+        .map(|mut members| {
+            members.sort_by_key(|book| sample_order(options.seed, book.id));
+            members.into_iter()
+        })
+        .collect();
+
+    let mut judgments = Vec::new();
+    while judgments.len() < options.queries && !strata.is_empty() {
+        strata.retain(|stratum| stratum.len() > 0);
+        for stratum in strata.iter_mut() {
+            if judgments.len() == options.queries {
+                break;
+            }
+            // skip seeds whose relevant set is too small to measure anything
+            for seed in stratum.by_ref() {
+                let relevant = index.relevant(seed, options.max_group_size);
+                if relevant.len() >= options.min_relevant.max(1) {
+                    judgments.push(Judgment {
+                        id: format!("seed-{}", seed.id),
+                        text: None,
+                        seed: Some(seed.id),
+                        filters: SearchFilters::default(),
+                        relevant,
+                    });
+                    break;
+                }
+            }
+        }
+    }
+    judgments
+}
+
+/// One judgment per line, readable by `eval run`
+pub fn write_judgments(
+    path: &Path,
+    judgments: &[Judgment],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for judgment in judgments {
+        writeln!(out, "{}", serde_json::to_string(judgment)?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Every embedded book still in the catalog, with its subjects and bookshelves
+pub async fn load_catalog(
+    pool: &PgPool,
+    schema: &Schema,
+) -> Result<Vec<CatalogBook>, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "
+        SELECT m.id, m.title, m.author,
+        ARRAY(SELECT heading FROM {subjects} s WHERE s.book_id = m.id AND s.scheme = 'LCSH'
+            ORDER BY heading) AS subjects,
+        ARRAY(SELECT heading FROM {subjects} s WHERE s.book_id = m.id AND s.scheme = 'bookshelf'
+            ORDER BY heading) AS bookshelves
+        FROM {metadata} m
+        JOIN {vectors} v ON v.id = m.id
+        WHERE m.deleted_at IS NULL
+        ORDER BY m.id
+        ",
+        subjects = schema.subjects,
+        metadata = schema.metadata,
+        vectors = schema.vectors
+    );
+    let rows = sqlx::query(query_string.as_str()).fetch_all(pool).await?;
+    let books = rows
+        .iter()
+        .map(|row| {
+            Ok(CatalogBook {
+                id: row.try_get("id")?,
+                title: row.try_get("title")?,
+                author: row.try_get("author")?,
+                subjects: row.try_get("subjects")?,
+                bookshelves: row.try_get("bookshelves")?,
+            })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    Ok(books)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(
+        id: i64,
+        title: &str,
+        author: &str,
+        subjects: &[&str],
+        shelves: &[&str],
+    ) -> CatalogBook {
+        CatalogBook {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            subjects: subjects.iter().map(|s| s.to_string()).collect(),
+            bookshelves: shelves.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_series_key() {
+        let gibbon = "the history of the decline and fall of the roman empire";
+        assert_eq!(
+            series_key("The History of the Decline and Fall of the Roman Empire — Volume 3"),
+            Some(gibbon.to_string())
+        );
+        assert_eq!(
+            series_key("The History of the Decline and Fall of the Roman Empire, Vol. II"),
+            Some(gibbon.to_string())
+        );
+        assert_eq!(
+            series_key("Les Misérables, v. 1/5: Fantine"),
+            Some("les misérables".to_string())
+        );
+        assert_eq!(series_key("The Book of Tea"), None);
+        assert_eq!(series_key("Volume 1"), None);
+        assert_eq!(
+            work_title("Pride and Prejudice\nIllustrated"),
+            "pride and prejudice"
+        );
+    }
+
+    #[test]
+    fn test_relevant_books() {
+        let books = vec![
+            book(
+                1,
+                "Empire, Volume 1",
+                "Gibbon, Edward",
+                &["Rome -- History"],
+                &[],
+            ),
+            book(
+                2,
+                "Empire, Volume 2",
+                "Gibbon, Edward",
+                &["Rome -- History"],
+                &[],
+            ),
+            book(3, "Memoirs", "Gibbon, Edward", &[], &[]),
+            book(
+                4,
+                "Caesar",
+                "Froude, James",
+                &["Rome -- History", "Rome -- Biography"],
+                &[],
+            ),
+            book(
+                5,
+                "Gods",
+                "Hamilton, Edith",
+                &["Rome -- Biography"],
+                &["Classics"],
+            ),
+            book(6, "Fables", "Various", &[], &["Classics"]),
+            book(
+                7,
+                "Empire, Volume 1: Illustrated",
+                "Gibbon, Edward",
+                &[],
+                &[],
+            ),
+        ];
+        let index = CatalogIndex::new(&books);
+        let seed = book(
+            1,
+            "Empire, Volume 1",
+            "Gibbon, Edward",
+            &["Rome -- History", "Rome -- Biography"],
+            &["Classics"],
+        );
+        let relevant = index.relevant(&seed, 500);
+        let grades: Vec<(i64, u32)> = relevant.iter().map(|b| (b.id, b.grade)).collect();
+        // 7 is another edition of the seed's own work
+        assert_eq!(grades, vec![(2, 3), (3, 2), (4, 2), (5, 1), (6, 1)]);
+
+        // Rome -- History has three books, too many for a max group size of 2
+        let relevant = index.relevant(&seed, 2);
+        let grades: Vec<(i64, u32)> = relevant.iter().map(|b| (b.id, b.grade)).collect();
+        assert_eq!(grades, vec![(2, 3), (3, 2), (4, 1), (5, 1), (6, 1)]);
+
+        // placeholder authors do not relate books
+        let various = index.relevant(&books[5], 500);
+        assert_eq!(various.iter().map(|b| b.id).collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn test_stratified_sampling() {
+        let mut books = Vec::new();
+        // a large stratum of 20 sea stories and a small one of 3 ghost stories
+        for id in 0..20 {
+            books.push(book(
+                id,
+                "Sea",
+                &format!("Sailor {}", id % 4),
+                &["Sea stories"],
+                &[],
+            ));
+        }
+        for id in 20..23 {
+            books.push(book(
+                id,
+                &format!("Ghosts {}", id),
+                "Ghost, Writer",
+                &["Ghost stories"],
+                &[],
+            ));
+        }
+        let options = SyntheticOptions {
+            queries: 6,
+            min_relevant: 2,
+            ..Default::default()
+        };
+        let judgments = generate_judgments(&books, &options);
+        assert_eq!(judgments.len(), 6);
+        let ghosts = judgments
+            .iter()
+            .filter(|judgment| judgment.seed.unwrap() >= 20)
+            .count();
+        assert_eq!(ghosts, 3);
+
+        let again = generate_judgments(&books, &options);
+        let ids = |judgments: &[Judgment]| -> Vec<String> {
+            judgments
+                .iter()
+                .map(|judgment| judgment.id.clone())
+                .collect()
+        };
+        assert_eq!(ids(&judgments), ids(&again));
+        let reseeded = generate_judgments(&books, &SyntheticOptions { seed: 7, ..options });
+        assert_ne!(ids(&judgments), ids(&reseeded));
+
+        let everything = generate_judgments(
+            &books,
+            &SyntheticOptions {
+                queries: 100,
+                min_relevant: 2,
+                ..Default::default()
+            },
+        );
+        assert_eq!(everything.len(), 23);
+    }
+}
@@ -0,0 +1,230 @@
+use sqlx::Row;
+use sqlx::postgres::PgPool;
+use std::time::{Duration, Instant};
+
+use crate::schema::TableName;
+
+// pgvector docs: https://github.com/pgvector/pgvector#indexing
+// Builds are much faster when the graph fits in memory, e.g.
+// SET maintenance_work_mem = '2GB'; before creating an HNSW index.
+
+/// ANN index types supported by pgvector. Both use cosine distance to match the `<=>`
+/// operator used by every search query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexKind {
+    /// Graph index, better recall/latency tradeoff, slower to build.
+    /// pgvector defaults are m = 16, ef_construction = 64.
+    Hnsw { m: i32, ef_construction: i32 },
+    /// Clustered index, fast to build but should be created after the table is loaded.
+    /// A good starting point is rows / 1000 lists for up to 1M rows.
+    IvfFlat { lists: i32 },
+}
+
+impl IndexKind {
+    fn method(&self) -> &'static str {
+        match self {
+            IndexKind::Hnsw { .. } => "hnsw",
+            IndexKind::IvfFlat { .. } => "ivfflat",
+        }
+    }
+
+    fn parameters(&self) -> String {
+        match self {
+            IndexKind::Hnsw { m, ef_construction } => {
+                format!("m = {}, ef_construction = {}", m, ef_construction)
+            }
+            IndexKind::IvfFlat { lists } => format!("lists = {}", lists),
+        }
+    }
+
+    /// `parameters` as Postgres stores them in pg_class.reloptions
+    fn reloptions(&self) -> Vec<String> {
+        match self {
+            IndexKind::Hnsw { m, ef_construction } => {
+                vec![
+                    format!("m={}", m),
+                    format!("ef_construction={}", ef_construction),
+                ]
+            }
+            IndexKind::IvfFlat { lists } => vec![format!("lists={}", lists)],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexReport {
+    pub name: String,
+    pub definition: String,
+    pub size_bytes: i64,
+    /// Only known for indexes built by this process
+    pub build_time: Option<Duration>,
+}
+
+fn index_suffix(kind: &IndexKind) -> String {
+    format!("embedding_{}_idx", kind.method())
+}
+
+fn create_index_sql(table_name: &TableName, kind: &IndexKind) -> String {
+    format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} USING {} (embedding vector_cosine_ops) WITH ({})",
+        table_name.derived(&index_suffix(kind)),
+        table_name,
+        kind.method(),
+        kind.parameters()
+    )
+}
+
+/// Storage parameters of an existing index, None if there is no index by that name
+async fn existing_reloptions(
+    pool: &PgPool,
+    index_name: &str,
+) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    let row = sqlx::query(
+        "SELECT coalesce(reloptions, '{}') AS reloptions FROM pg_class WHERE oid = to_regclass($1)",
+    )
+    .bind(index_name)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| row.try_get("reloptions")).transpose()?)
+}
+
+/// Builds the index, or reports the existing one if it was built with the same parameters.
+/// An existing index with other parameters is an error, see `rebuild_index`.
+pub async fn create_index(
+    pool: &PgPool,
+    table_name: &TableName,
+    kind: &IndexKind,
+) -> Result<IndexReport, Box<dyn std::error::Error>> {
+    let index_name = table_name.derived_qualified(&index_suffix(kind));
+    if let Some(mut existing) = existing_reloptions(pool, &index_name).await? {
+        let mut wanted = kind.reloptions();
+        existing.sort();
+        wanted.sort();
+        if existing != wanted {
+            return Err(format!(
+                "{} already exists with ({}), use --rebuild to replace it with ({})",
+                index_name,
+                existing.join(", "),
+                kind.parameters()
+            )
+            .into());
+        }
+        println!("{} already exists with the same parameters", index_name);
+        return index_size(pool, &index_name).await;
+    }
+
+    let start = Instant::now();
+    sqlx::query(create_index_sql(table_name, kind).as_str())
+        .execute(pool)
+        .await?;
+    let build_time = start.elapsed();
+    println!("Built {} in {:.1}s", index_name, build_time.as_secs_f64());
+
+    let mut report = index_size(pool, &index_name).await?;
+    report.build_time = Some(build_time);
+    Ok(report)
+}
+
+/// Drops and recreates the index, e.g. after changing parameters or after a bulk load
+/// that made the IVFFlat centroids stale.
+pub async fn rebuild_index(
+    pool: &PgPool,
+    table_name: &TableName,
+    kind: &IndexKind,
+) -> Result<IndexReport, Box<dyn std::error::Error>> {
+    drop_index(pool, table_name, kind).await?;
+    create_index(pool, table_name, kind).await
+}
+
+pub async fn drop_index(
+    pool: &PgPool,
+    table_name: &TableName,
+    kind: &IndexKind,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let drop_string = format!(
+        "DROP INDEX IF EXISTS {}",
+        table_name.derived_qualified(&index_suffix(kind))
+    );
+    sqlx::query(drop_string.as_str()).execute(pool).await?;
+    Ok(())
+}
+
+async fn index_size(
+    pool: &PgPool,
+    index_name: &str,
+) -> Result<IndexReport, Box<dyn std::error::Error>> {
+    let row = sqlx::query(
+        "
+        SELECT relname::text AS indexname, pg_get_indexdef(oid) AS indexdef,
+        pg_relation_size(oid) AS size_bytes
+        FROM pg_class WHERE oid = $1::regclass
+        ",
+    )
+    .bind(index_name)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(IndexReport {
+        name: row.try_get("indexname")?,
+        definition: row.try_get("indexdef")?,
+        size_bytes: row.try_get("size_bytes")?,
+        build_time: None,
+    })
+}
+
+/// Every index on the table, including the primary key, with its on-disk size.
+pub async fn index_report(
+    pool: &PgPool,
+    table_name: &TableName,
+) -> Result<Vec<IndexReport>, Box<dyn std::error::Error>> {
+    let rows = sqlx::query(
+        "
+        SELECT indexname, indexdef,
+        pg_relation_size(format('%I.%I', schemaname, indexname)::regclass) AS size_bytes
+        FROM pg_indexes
+        WHERE tablename = $1 AND schemaname = coalesce($2, current_schema())
+        ORDER BY indexname
+        ",
+    )
+    .bind(table_name.name())
+    .bind(table_name.namespace())
+    .fetch_all(pool)
+    .await?;
+
+    let mut reports = Vec::new();
+    for row in rows {
+        reports.push(IndexReport {
+            name: row.try_get("indexname")?,
+            definition: row.try_get("indexdef")?,
+            size_bytes: row.try_get("size_bytes")?,
+            build_time: None,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_index_sql() {
+        let table_name = TableName::new("book_summary_vectors").unwrap();
+        let hnsw = IndexKind::Hnsw {
+            m: 16,
+            ef_construction: 64,
+        };
+        assert_eq!(
+            create_index_sql(&table_name, &hnsw),
+            "CREATE INDEX IF NOT EXISTS \"book_summary_vectors_embedding_hnsw_idx\" ON \"book_summary_vectors\" USING hnsw (embedding vector_cosine_ops) WITH (m = 16, ef_construction = 64)"
+        );
+
+        assert_eq!(hnsw.reloptions(), vec!["m=16", "ef_construction=64"]);
+
+        let ivfflat = IndexKind::IvfFlat { lists: 100 };
+        assert_eq!(
+            create_index_sql(&table_name, &ivfflat),
+            "CREATE INDEX IF NOT EXISTS \"book_summary_vectors_embedding_ivfflat_idx\" ON \"book_summary_vectors\" USING ivfflat (embedding vector_cosine_ops) WITH (lists = 100)"
+        );
+    }
+}
@@ -0,0 +1,683 @@
+use instant_distance::{Builder, HnswMap, Point, Search};
+use ndarray::{Array2, ArrayView1};
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::embedding_models::EmbeddingModel;
+use crate::schema::Schema;
+use crate::search::{
+    SearchFilters, SearchRequest, SearchResult, load_embeddings, search_by_vector, work_title,
+};
+
+// Summary vectors and the metadata their filters need, behind one interface so search,
+// similar and recommend run against Postgres or against a file loaded into memory.
+//
+// A store file is the magic bytes, the length of a JSON header as a little-endian u64, the
+// header with the model and one record per book, then each book's embedding as
+// little-endian f32s in header order. `store export` writes one from the database.
+
+const MAGIC: &[u8; 8] = b"BRSTORE1";
+
+/// Candidates an HNSW search returns before filtering. Queries that need more, because
+/// k is larger or the filters reject too many, fall back to a scan of every vector.
+const HNSW_EF_SEARCH: usize = 100;
+
+/// What the filters in `SearchFilters` look at, as stored in the metadata tables
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookRecord {
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    pub birthyear: Option<i32>,
+    pub deathyear: Option<i32>,
+    pub summary: Option<String>,
+    pub languages: Vec<String>,
+    /// LCSH headings
+    pub subjects: Vec<String>,
+    pub bookshelves: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredBook {
+    pub book: BookRecord,
+    pub embedding: Vec<f32>,
+}
+
+pub trait VectorStore {
+    /// The `request.k` nearest books to `embedding` by cosine distance that pass
+    /// `request.filters`, nearest first
+    async fn search(
+        &self,
+        embedding: &[f32],
+        request: &SearchRequest,
+    ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>>;
+
+    /// Stored embeddings for the given ids, ids without one are left out
+    async fn embeddings(
+        &self,
+        ids: &[i64],
+    ) -> Result<HashMap<i64, Vec<f32>>, Box<dyn std::error::Error>>;
+
+    /// Adds the books or replaces their embeddings, returns how many were written
+    async fn upsert(&mut self, books: &[StoredBook]) -> Result<usize, Box<dyn std::error::Error>>;
+
+    async fn len(&self) -> Result<usize, Box<dyn std::error::Error>>;
+}
+
+/// The model's vector table in Postgres, searched through its ANN index
+pub struct PgVectorStore<'a> {
+    pool: &'a PgPool,
+    schema: &'a Schema,
+}
+
+impl<'a> PgVectorStore<'a> {
+    pub fn new(pool: &'a PgPool, schema: &'a Schema) -> Self {
+        PgVectorStore { pool, schema }
+    }
+}
+
+impl VectorStore for PgVectorStore<'_> {
+    async fn search(
+        &self,
+        embedding: &[f32],
+        request: &SearchRequest,
+    ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+        let embedding = Vector::from(embedding.to_vec());
+        search_by_vector(self.pool, self.schema, &embedding, request).await
+    }
+
+    async fn embeddings(
+        &self,
+        ids: &[i64],
+    ) -> Result<HashMap<i64, Vec<f32>>, Box<dyn std::error::Error>> {
+        load_embeddings(self.pool, self.schema, ids).await
+    }
+
+    /// Only writes the vectors, the metadata tables are filled by `ingest-metadata`.
+    /// The content hash and fingerprint are cleared, so the next `embed` redoes these books.
+    async fn upsert(&mut self, books: &[StoredBook]) -> Result<usize, Box<dyn std::error::Error>> {
+        let query_string = format!(
+            "
+            INSERT INTO {} (id, embedding)
+            SELECT * FROM UNNEST($1::bigint[], $2::vector[])
+            ON CONFLICT (id) DO UPDATE SET
+                embedding = excluded.embedding,
+                content_hash = NULL,
+                model_fingerprint = NULL,
+                updated_at = now()
+            ",
+            self.schema.vectors
+        );
+        let ids: Vec<i64> = books.iter().map(|stored| stored.book.id).collect();
+        let embeddings: Vec<Vector> = books
+            .iter()
+            .map(|stored| Vector::from(stored.embedding.clone()))
+            .collect();
+        let result = sqlx::query(query_string.as_str())
+            .bind(&ids)
+            .bind(&embeddings)
+            .execute(self.pool)
+            .await?;
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn len(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let query_string = format!("SELECT count(*) FROM {}", self.schema.vectors);
+        let count: i64 = sqlx::query_scalar(query_string.as_str())
+            .fetch_one(self.pool)
+            .await?;
+        Ok(count as usize)
+    }
+}
+
+// A row of the store's matrix, or a query in a matrix of its own. The HNSW graph holds
+// these instead of copies of the vectors.
+#[derive(Clone)]
+struct UnitRow {
+    matrix: Arc<Array2<f32>>,
+    row: usize,
+}
+
+impl Point for UnitRow {
+    fn distance(&self, other: &Self) -> f32 {
+        1.0 - self.matrix.row(self.row).dot(&other.matrix.row(other.row))
+    }
+}
+
+fn unit_vector(vector: &[f32]) -> (Vec<f32>, f32) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return (vector.to_vec(), 0.0);
+    }
+    (vector.iter().map(|x| x / norm).collect(), norm)
+}
+
+fn contains_any(values: &[String], patterns: &[String]) -> bool {
+    values.iter().any(|value| {
+        let value = value.to_lowercase();
+        patterns
+            .iter()
+            .any(|pattern| value.contains(&pattern.to_lowercase()))
+    })
+}
+
+fn in_range(value: Option<i32>, range: Option<(i32, i32)>) -> bool {
+    match range {
+        Some((start, end)) => value.is_some_and(|value| (start..=end).contains(&value)),
+        None => true,
+    }
+}
+
+/// Every book and its vector in memory, searched by scanning a contiguous matrix of unit
+/// vectors, or through an HNSW graph when enabled. Filters behave like `push_filters`.
+#[derive(Default)]
+pub struct MemoryStore {
+    /// The model that made the vectors, needed to embed query text
+    model: Option<EmbeddingModel>,
+    dimensions: usize,
+    books: Vec<BookRecord>,
+    positions: HashMap<i64, usize>,
+    /// One row per book in `books` order, scaled to unit length
+    vectors: Arc<Array2<f32>>,
+    /// Lengths of the embeddings before scaling
+    norms: Vec<f32>,
+    hnsw: Option<HnswMap<UnitRow, usize>>,
+    use_hnsw: bool,
+}
+
+impl MemoryStore {
+    pub fn new(dimensions: usize, model: Option<EmbeddingModel>) -> Self {
+        MemoryStore {
+            model,
+            dimensions,
+            vectors: Arc::new(Array2::zeros((0, dimensions))),
+            ..Default::default()
+        }
+    }
+
+    /// Builds the HNSW graph now and again after every upsert
+    pub fn with_hnsw(mut self, use_hnsw: bool) -> Self {
+        self.use_hnsw = use_hnsw;
+        self.rebuild_hnsw();
+        self
+    }
+
+    pub fn model(&self) -> Option<&EmbeddingModel> {
+        self.model.as_ref()
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn rebuild_hnsw(&mut self) {
+        self.hnsw = None;
+        if !self.use_hnsw || self.books.is_empty() {
+            return;
+        }
+        let points = (0..self.books.len())
+            .map(|row| UnitRow {
+                matrix: Arc::clone(&self.vectors),
+                row,
+            })
+            .collect();
+        let rows = (0..self.books.len()).collect();
+        self.hnsw = Some(
+            Builder::default()
+                .ef_search(HNSW_EF_SEARCH)
+                .seed(0)
+                .build(points, rows),
+        );
+    }
+
+    fn embedding(&self, position: usize) -> Vec<f32> {
+        let norm = self.norms[position];
+        self.vectors
+            .row(position)
+            .iter()
+            .map(|x| x * norm)
+            .collect()
+    }
+
+    fn result(&self, position: usize, distance: f32) -> SearchResult {
+        let book = &self.books[position];
+        SearchResult {
+            id: book.id,
+            title: book.title.clone(),
+            author: book.author.clone(),
+            birthyear: book.birthyear,
+            deathyear: book.deathyear,
+            summary: book.summary.clone(),
+            distance: distance as f64,
+        }
+    }
+
+    /// Whether the book at `position` passes `filters`, see `push_filters`
+    fn passes(&self, position: usize, filters: &SearchFilters, work: Option<(&str, &str)>) -> bool {
+        let book = &self.books[position];
+        filters
+            .author
+            .as_ref()
+            .is_none_or(|author| book.author.to_lowercase().contains(&author.to_lowercase()))
+            && in_range(book.birthyear, filters.birthyear_range)
+            && in_range(book.deathyear, filters.deathyear_range)
+            && (filters.languages.is_empty()
+                || book
+                    .languages
+                    .iter()
+                    .any(|language| filters.languages.contains(language)))
+            && (filters.subjects.is_empty() || contains_any(&book.subjects, &filters.subjects))
+            && (filters.bookshelves.is_empty()
+                || contains_any(&book.bookshelves, &filters.bookshelves))
+            && !filters.exclude_ids.contains(&book.id)
+            && work.is_none_or(|(author, title)| {
+                book.author != author || work_title(&book.title) != title
+            })
+    }
+
+    fn search_rows(&self, query: &[f32], request: &SearchRequest) -> Vec<SearchResult> {
+        let k = request.k.max(0) as usize;
+        let (query, _) = unit_vector(query);
+        let filters = &request.filters;
+        let work = filters
+            .exclude_work_of
+            .and_then(|id| self.positions.get(&id))
+            .map(|&position| {
+                let seed = &self.books[position];
+                (seed.author.as_str(), work_title(&seed.title))
+            });
+        let work = work
+            .as_ref()
+            .map(|(author, title)| (*author, title.as_str()));
+
+        if let Some(hnsw) = &self.hnsw {
+            let point = UnitRow {
+                matrix: Arc::new(Array2::from_shape_vec((1, query.len()), query.clone()).unwrap()),
+                row: 0,
+            };
+            let mut search = Search::default();
+            let results: Vec<SearchResult> = hnsw
+                .search(&point, &mut search)
+                .filter(|item| self.passes(*item.value, filters, work))
+                .take(k)
+                .map(|item| self.result(*item.value, item.distance))
+                .collect();
+            if results.len() == k {
+                return results;
+            }
+        }
+
+        let similarities = self.vectors.dot(&ArrayView1::from(&query));
+        let mut scored: Vec<(f32, usize)> = similarities
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| self.passes(*position, filters, work))
+            .map(|(position, similarity)| (1.0 - similarity, position))
+            .collect();
+        let by_distance = |a: &(f32, usize), b: &(f32, usize)| {
+            a.0.total_cmp(&b.0)
+                .then(self.books[a.1].id.cmp(&self.books[b.1].id))
+        };
+        if scored.len() > k {
+            scored.select_nth_unstable_by(k, by_distance);
+            scored.truncate(k);
+        }
+        scored.sort_by(by_distance);
+        scored
+            .into_iter()
+            .map(|(distance, position)| self.result(position, distance))
+            .collect()
+    }
+
+    fn insert(&mut self, stored: &StoredBook) -> Result<(), String> {
+        if stored.embedding.len() != self.dimensions {
+            return Err(format!(
+                "book {} has {} dimensions, the store has {}",
+                stored.book.id,
+                stored.embedding.len(),
+                self.dimensions
+            ));
+        }
+        let (unit, norm) = unit_vector(&stored.embedding);
+        let vectors = Arc::make_mut(&mut self.vectors);
+        match self.positions.get(&stored.book.id) {
+            Some(&position) => {
+                vectors.row_mut(position).assign(&ArrayView1::from(&unit));
+                self.books[position] = stored.book.clone();
+                self.norms[position] = norm;
+            }
+            None => {
+                vectors
+                    .push_row(ArrayView1::from(&unit))
+                    .map_err(|e| e.to_string())?;
+                self.positions.insert(stored.book.id, self.books.len());
+                self.books.push(stored.book.clone());
+                self.norms.push(norm);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let header = StoreHeader {
+            model: self.model.clone(),
+            dimensions: self.dimensions,
+            books: self.books.clone(),
+        };
+        let header = serde_json::to_vec(&header)?;
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&(header.len() as u64).to_le_bytes())?;
+        out.write_all(&header)?;
+        for position in 0..self.books.len() {
+            for value in self.embedding(position) {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let context = |e: std::io::Error| format!("cannot read store {}: {}", path.display(), e);
+        let file = File::open(path).map_err(context)?;
+        let file_size = file.metadata().map_err(context)?.len();
+        let mut input = BufReader::new(file);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic).map_err(context)?;
+        if &magic != MAGIC {
+            return Err(format!("{} is not a vector store file", path.display()).into());
+        }
+        let mut length = [0; 8];
+        input.read_exact(&mut length).map_err(context)?;
+        // both sizes come from the file, check them before allocating
+        let truncated = || format!("{} is truncated or corrupt", path.display());
+        let header_length = u64::from_le_bytes(length);
+        let mut remaining = file_size - 16;
+        if header_length > remaining {
+            return Err(truncated().into());
+        }
+        remaining -= header_length;
+        let mut header = vec![0; header_length as usize];
+        input.read_exact(&mut header).map_err(context)?;
+        let header: StoreHeader = serde_json::from_slice(&header)?;
+        let vector_bytes = (header.dimensions as u64)
+            .checked_mul(4)
+            .and_then(|bytes| bytes.checked_mul(header.books.len() as u64));
+        if vector_bytes.is_none_or(|bytes| bytes > remaining) {
+            return Err(truncated().into());
+        }
+
+        let mut store = MemoryStore::new(header.dimensions, header.model);
+        let mut bytes = vec![0; header.dimensions * 4];
+        for book in header.books {
+            input.read_exact(&mut bytes).map_err(context)?;
+            let embedding = bytes
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect();
+            store.insert(&StoredBook { book, embedding })?;
+        }
+        Ok(store)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreHeader {
+    model: Option<EmbeddingModel>,
+    dimensions: usize,
+    books: Vec<BookRecord>,
+}
+
+impl VectorStore for MemoryStore {
+    async fn search(
+        &self,
+        embedding: &[f32],
+        request: &SearchRequest,
+    ) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+        if embedding.len() != self.dimensions {
+            return Err(format!(
+                "query has {} dimensions, the store has {}",
+                embedding.len(),
+                self.dimensions
+            )
+            .into());
+        }
+        Ok(self.search_rows(embedding, request))
+    }
+
+    async fn embeddings(
+        &self,
+        ids: &[i64],
+    ) -> Result<HashMap<i64, Vec<f32>>, Box<dyn std::error::Error>> {
+        Ok(ids
+            .iter()
+            .filter_map(|id| {
+                let position = *self.positions.get(id)?;
+                Some((*id, self.embedding(position)))
+            })
+            .collect())
+    }
+
+    async fn upsert(&mut self, books: &[StoredBook]) -> Result<usize, Box<dyn std::error::Error>> {
+        // the graph shares the matrix, drop it first so the matrix is not copied
+        self.hnsw = None;
+        for stored in books {
+            self.insert(stored)?;
+        }
+        self.rebuild_hnsw();
+        Ok(books.len())
+    }
+
+    async fn len(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        Ok(self.books.len())
+    }
+}
+
+/// Every embedded book still in the catalog with `model`'s vectors, for `MemoryStore::save`
+pub async fn export_store(
+    pool: &PgPool,
+    schema: &Schema,
+    model: EmbeddingModel,
+) -> Result<MemoryStore, Box<dyn std::error::Error>> {
+    let query_string = format!(
+        "
+        SELECT m.id, m.title, m.author, m.birthyear, m.deathyear, m.summary, m.languages,
+        ARRAY(SELECT heading FROM {subjects} s WHERE s.book_id = m.id AND s.scheme = 'LCSH'
+            ORDER BY heading) AS subjects,
+        ARRAY(SELECT heading FROM {subjects} s WHERE s.book_id = m.id AND s.scheme = 'bookshelf'
+            ORDER BY heading) AS bookshelves,
+        v.embedding
+        FROM {vectors} v
+        JOIN {metadata} m ON m.id = v.id
+        WHERE m.deleted_at IS NULL
+        ORDER BY m.id
+        ",
+        subjects = schema.subjects,
+        vectors = schema.vectors,
+        metadata = schema.metadata
+    );
+    let rows = sqlx::query(query_string.as_str()).fetch_all(pool).await?;
+    let mut books = Vec::with_capacity(rows.len());
+    for row in rows {
+        let embedding: Vector = row.try_get("embedding")?;
+        books.push(StoredBook {
+            book: BookRecord {
+                id: row.try_get("id")?,
+                title: row.try_get("title")?,
+                author: row.try_get("author")?,
+                birthyear: row.try_get("birthyear")?,
+                deathyear: row.try_get("deathyear")?,
+                summary: row.try_get("summary")?,
+                languages: row.try_get("languages")?,
+                subjects: row.try_get("subjects")?,
+                bookshelves: row.try_get("bookshelves")?,
+            },
+            embedding: embedding.to_vec(),
+        });
+    }
+    let mut store = MemoryStore::new(model.dimensions as usize, Some(model));
+    store.upsert(&books).await?;
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(id: i64, title: &str, author: &str, embedding: Vec<f32>) -> StoredBook {
+        StoredBook {
+            book: BookRecord {
+                id,
+                title: title.to_string(),
+                author: author.to_string(),
+                birthyear: Some(1800 + id as i32),
+                deathyear: None,
+                summary: None,
+                languages: vec![if id % 2 == 0 { "fr" } else { "en" }.to_string()],
+                subjects: vec![format!("Subject {} -- Fiction", id % 3)],
+                bookshelves: vec![],
+            },
+            embedding,
+        }
+    }
+
+    fn sample_books() -> Vec<StoredBook> {
+        vec![
+            stored(1, "Moby Dick", "Melville, Herman", vec![1.0, 0.0, 0.0]),
+            stored(
+                2,
+                "Moby Dick: Illustrated",
+                "Melville, Herman",
+                vec![2.0, 0.1, 0.0],
+            ),
+            stored(3, "Typee", "Melville, Herman", vec![0.8, 0.6, 0.0]),
+            stored(4, "Emma", "Austen, Jane", vec![0.0, 1.0, 0.0]),
+            stored(5, "Persuasion", "Austen, Jane", vec![0.0, 0.6, 0.8]),
+        ]
+    }
+
+    fn ids(results: &[SearchResult]) -> Vec<i64> {
+        results.iter().map(|result| result.id).collect()
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_search_and_filters() {
+        let mut store = MemoryStore::new(3, None);
+        assert_eq!(store.upsert(&sample_books()).await.unwrap(), 5);
+        let mut request = SearchRequest {
+            k: 3,
+            ..Default::default()
+        };
+        let results = store.search(&[1.0, 0.0, 0.0], &request).await.unwrap();
+        assert_eq!(ids(&results), vec![1, 2, 3]);
+        assert!(results[0].distance.abs() < 1e-6);
+
+        request.filters = SearchFilters {
+            author: Some("AUSTEN".to_string()),
+            ..Default::default()
+        };
+        let results = store.search(&[1.0, 0.0, 0.0], &request).await.unwrap();
+        assert_eq!(ids(&results), vec![4, 5]);
+
+        request.filters = SearchFilters {
+            languages: vec!["en".to_string()],
+            subjects: vec!["subject 0".to_string(), "subject 2".to_string()],
+            ..Default::default()
+        };
+        let results = store.search(&[1.0, 0.0, 0.0], &request).await.unwrap();
+        assert_eq!(ids(&results), vec![3, 5]);
+
+        request.filters = SearchFilters {
+            exclude_ids: vec![1],
+            exclude_work_of: Some(1),
+            birthyear_range: Some((1802, 1804)),
+            ..Default::default()
+        };
+        let results = store.search(&[1.0, 0.0, 0.0], &request).await.unwrap();
+        assert_eq!(ids(&results), vec![3, 4]);
+
+        // replacing a vector moves the book, the original scale is kept
+        store
+            .upsert(&[stored(4, "Emma", "Austen, Jane", vec![3.0, 0.0, 0.0])])
+            .await
+            .unwrap();
+        assert_eq!(store.len().await.unwrap(), 5);
+        let embeddings = store.embeddings(&[4, 99]).await.unwrap();
+        assert_eq!(embeddings.len(), 1);
+        assert_eq!(embeddings[&4], vec![3.0, 0.0, 0.0]);
+        request.filters = SearchFilters::default();
+        let results = store.search(&[1.0, 0.0, 0.0], &request).await.unwrap();
+        assert_eq!(ids(&results), vec![1, 4, 2]);
+        assert!(store.search(&[1.0, 0.0], &request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_hnsw_and_file() {
+        let books: Vec<StoredBook> = (0..200)
+            .map(|id| {
+                let angle = id as f32 * 0.05;
+                let embedding = vec![angle.cos(), angle.sin(), (id % 7) as f32 * 0.1];
+                stored(id, &format!("Book {}", id), "Author", embedding)
+            })
+            .collect();
+        let mut exact = MemoryStore::new(3, None);
+        exact.upsert(&books).await.unwrap();
+        let mut approximate = MemoryStore::new(3, None).with_hnsw(true);
+        approximate.upsert(&books).await.unwrap();
+        assert!(approximate.hnsw.is_some());
+
+        let request = SearchRequest {
+            k: 5,
+            ..Default::default()
+        };
+        let query = [0.3, 0.9, 0.2];
+        let expected = ids(&exact.search(&query, &request).await.unwrap());
+        assert_eq!(
+            ids(&approximate.search(&query, &request).await.unwrap()),
+            expected
+        );
+        // too selective for the graph's candidates, answered by the scan
+        let filtered = SearchRequest {
+            k: 5,
+            filters: SearchFilters {
+                birthyear_range: Some((1800, 1804)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&approximate.search(&query, &filtered).await.unwrap()).len(),
+            5
+        );
+
+        let path = std::env::temp_dir().join(format!("vector_store_{}.bin", std::process::id()));
+        exact.save(&path).unwrap();
+        let loaded = MemoryStore::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.books, exact.books);
+        let before = &exact.embeddings(&[17]).await.unwrap()[&17];
+        let after = &loaded.embeddings(&[17]).await.unwrap()[&17];
+        assert!(before.iter().zip(after).all(|(a, b)| (a - b).abs() < 1e-6));
+        assert_eq!(
+            ids(&loaded.search(&query, &request).await.unwrap()),
+            expected
+        );
+
+        // a header length past the end of the file is refused rather than allocated
+        let mut corrupt = MAGIC.to_vec();
+        corrupt.extend_from_slice(&u64::MAX.to_le_bytes());
+        corrupt.extend_from_slice(b"{}");
+        std::fs::write(&path, corrupt).unwrap();
+        let error = MemoryStore::load(&path).err().unwrap().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.ends_with("is truncated or corrupt"));
+    }
+}